@@ -0,0 +1,145 @@
+use crate::database_config::DatabaseConfig;
+use crate::error::Error;
+use crate::extract_from_image;
+use crate::extract_from_image::PointOfInterest;
+use crate::features::uuid_description_pair::UUIDDescriptionPair;
+use crate::frame_info::FrameInfo;
+
+use crate::features_database;
+use crate::insert_log;
+use crate::metadata_database;
+
+use crate::python_binary;
+
+pub fn add_image_to_database(file_path: &str, config: &DatabaseConfig) -> Result<(), Error> {
+	let frame = FrameInfo::new_from_static_image_path(file_path)?;
+	let image_features = extract_from_image::get_features_from_image_path(file_path)?;
+	return add_features_to_database(frame, image_features, config);
+}
+
+// For features that were extracted somewhere else. Only static images, with
+// a frame id of 0, are added.
+pub fn add_features_to_database(
+	frame: FrameInfo,
+	features: Vec<PointOfInterest>,
+	config: &DatabaseConfig,
+) -> Result<(), Error> {
+	return insert_metadata_and_description_to_database(
+		assign_uuids_to_list(vec![(frame, features)], config)?,
+		config,
+	);
+}
+
+pub fn add_python_binary_to_database(
+	file_path: &str,
+	config: &DatabaseConfig,
+) -> Result<(), Error> {
+	let files = python_binary::parse_python_binary(file_path)?;
+	let files = assign_uuids_to_list(files, config)?;
+	return insert_metadata_and_description_to_database(files, config);
+}
+
+pub fn bulk_add_python_binary_to_database(
+	file_path: &str,
+	config: &DatabaseConfig,
+) -> Result<(), Error> {
+	let files = python_binary::parse_python_binary(file_path)?;
+	let (metadata_list, description_pairs) = assign_uuids_to_list(files, config)?;
+	insert_log::write(&description_pairs, config)?;
+	return finish_or_undo(
+		metadata_database::insert_meta_data_pair_vec_to_database(metadata_list, config).and_then(
+			|_| {
+				insert_log::mark_tree_started(config)?;
+				features_database::bulk_insert_description_vec_into_database(
+					description_pairs,
+					config,
+				)
+				.map(|_size| ())
+			},
+		),
+		config,
+	);
+}
+
+fn insert_metadata_and_description_to_database(
+	list: FeaturesWithUUID,
+	config: &DatabaseConfig,
+) -> Result<(), Error> {
+	let (metadata_list, description_pairs) = list;
+
+	// Stays behind if the program stops part way, see insert_log::replay
+	insert_log::write(&description_pairs, config)?;
+	let result = if config.threaded_insert {
+		insert_log::mark_tree_started(config)?;
+		let sqlite_config = config.clone();
+		let sqlite_handle = std::thread::spawn(move || {
+			metadata_database::insert_meta_data_pair_vec_to_database(metadata_list, &sqlite_config)
+		});
+		let vp_tree_config = config.clone();
+		let vp_tree_handle = std::thread::spawn(move || {
+			features_database::insert_description_vec_into_database(
+				description_pairs,
+				&vp_tree_config,
+			)
+		});
+
+		let sqlite_result = sqlite_handle
+			.join()
+			.unwrap_or_else(|_| Err(Error::Panicked(String::from("The sqlite3 insert thread"))));
+		let vp_tree_result = vp_tree_handle
+			.join()
+			.map_err(|_| Error::Panicked(String::from("The VP tree insert thread")))
+			.and_then(|result| result.map_err(Error::from));
+		sqlite_result.and(vp_tree_result)
+	} else {
+		metadata_database::insert_meta_data_pair_vec_to_database(metadata_list, config).and_then(
+			|_| {
+				insert_log::mark_tree_started(config)?;
+				features_database::insert_description_vec_into_database(description_pairs, config)
+					.map_err(Error::from)
+			},
+		)
+	};
+	return finish_or_undo(result, config);
+}
+
+// An insert that failed is replayed right away rather than the next time the
+// program starts, as a server or a program using the library may keep
+// running and write a new insert log over this one
+fn finish_or_undo(result: Result<(), Error>, config: &DatabaseConfig) -> Result<(), Error> {
+	return match result {
+		Ok(()) => insert_log::clear(config),
+		Err(error) => {
+			let _replayed = insert_log::replay(config)?;
+			Err(error)
+		}
+	};
+}
+
+type FrameMetaDataPair = (FrameInfo, Vec<(u64, opencv::core::KeyPoint)>);
+type FeaturesWithUUID = (Vec<FrameMetaDataPair>, Vec<UUIDDescriptionPair>);
+fn assign_uuids_to_list(
+	list: Vec<(FrameInfo, Vec<PointOfInterest>)>,
+	config: &DatabaseConfig,
+) -> Result<FeaturesWithUUID, Error> {
+	let mut uuid_iterator = (metadata_database::get_max_uuid(config)? + 1)..;
+	let mut metadata_frame_list = vec![];
+	let mut all_descriptions = vec![];
+
+	for (frame, poi_list) in list {
+		// Only include static images currently
+		if frame.get_id() != 0 {
+			continue;
+		}
+
+		let mut metadata_vec = vec![];
+		for poi in poi_list {
+			let uuid = uuid_iterator.next().unwrap();
+			metadata_vec.push((uuid, poi.metadata));
+			all_descriptions.push(UUIDDescriptionPair::new(uuid, poi.description));
+		}
+		metadata_frame_list.push((frame, metadata_vec));
+	}
+
+	return Ok((metadata_frame_list, all_descriptions));
+}
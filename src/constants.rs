@@ -14,13 +14,55 @@ than system memory.
 To add images use the -a option and to query images use the -f option.
 ";
 
-pub const DATABASE_FOLDER_PATH: &str = "./database/";
-pub const SQLITE_DATABASE_PATH: &str = "./database/metadata.sqlite3";
-pub const THREADED_INSERT: bool = false;
+// Read when it exists, see DatabaseConfig for what it can hold
+pub const CONFIG_FILE_PATH: &str = "./feature_database.toml";
+// Every collection is a database of its own in a folder of this name in the
+// database folder, with a config file of its own read on top of the one above
+pub const COLLECTIONS_FOLDER_NAME: &str = "collections";
+pub const COLLECTION_CONFIG_FILE_NAME: &str = "feature_database.toml";
 
-pub const MAX_LEAF_NODE_SIZE: u64 = 4096 * 2;
-pub const MAX_FILE_NODE_DEPTH: usize = 8;
-pub const FILE_NODE_MEMORY_SAVER: bool = false;
+// Defaults of DatabaseConfig. The sqlite3 database is kept in the database
+// folder unless it is given a path of its own.
+pub const DEFAULT_DATABASE_FOLDER_PATH: &str = "./database/";
+pub const SQLITE_DATABASE_FILE_NAME: &str = "metadata.sqlite3";
+pub const DEFAULT_THREADED_INSERT: bool = false;
+pub const DEFAULT_MAX_LEAF_NODE_SIZE: u64 = 4096 * 2;
+pub const DEFAULT_MAX_FILE_NODE_DEPTH: usize = 8;
+pub const DEFAULT_VANTAGE_STRATEGY: crate::feature_tree::vantage::VantageStrategy =
+	crate::feature_tree::vantage::VantageStrategy::LargestSpread;
+pub const DEFAULT_FILE_NODE_MEMORY_SAVER: bool = false;
+pub const DEFAULT_THREADED_SEARCH: bool = true;
+pub const DEFAULT_BATCH_SEARCH: bool = true;
+pub const DEFAULT_MEMORY_MAPPED_SEARCH: bool = false;
+
+// Added to the name of a VP tree file while its replacement is being written
+pub const TEMP_FILE_EXTENSION: &str = ".tmp";
+// Folders in the database folder holding changes to many files of the tree
+// while they are made and once they are ready to be moved over
+pub const STAGING_FOLDER_NAME: &str = "staged_tree";
+pub const STAGING_READY_FOLDER_NAME: &str = "staged_tree.ready";
+// Holds the features of an insert until they are in both databases
+pub const INSERT_LOG_FILE_NAME: &str = "pending_insert.log";
+// Exists while an insert log is pending and the tree may have been changed
+pub const INSERT_LOG_TREE_MARKER_FILE_NAME: &str = "pending_insert.tree";
+
+// Parsed files kept in memory between searches, counted by their size on disk
+pub const NODE_CACHE_MAX_BYTES: usize = 512 * 1024 * 1024;
+
+// An internal node is rebuilt by --rebalance when one side holds more than
+// REBALANCE_RATIO times the features of the other side, as long as together
+// they hold at least REBALANCE_MIN_LEAVES full leaves
+pub const REBALANCE_RATIO: f64 = 4.0;
+pub const REBALANCE_MIN_LEAVES: u64 = 4;
+
+// --compact moves subtrees that grow past this many bytes into their own
+// file and merges files smaller than it into the file that points to them
+pub const COMPACTION_TARGET_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+// Number of features that LargestSpread picks the vantage point from, and
+// the number it measures each of them against
+pub const VANTAGE_CANDIDATES: usize = 8;
+pub const VANTAGE_SAMPLE_SIZE: usize = 256;
 
 pub const FEATURE_DESCRIPTION_LENGTH: usize = 32;
 // 694960 is the default radius because it is equal to
@@ -29,6 +71,16 @@ pub const FEATURE_DESCRIPTION_LENGTH: usize = 32;
 // to the origin. Experimental results give results that are
 // close to this value, so I think it is correct.
 pub const AVERAGE_EDGE_FEATURE_DISTANCE: u32 = 694_960;
+// Every byte of an edge feature is 0 or 255, so a random byte differs from it
+// in 4 bits on average, giving 32 * 4 expected differing bits.
+pub const AVERAGE_EDGE_HAMMING_DISTANCE: u32 = 128;
+// A random byte is on average 127.5 away from both 0 and 255.
+pub const AVERAGE_EDGE_MANHATTAN_DISTANCE: u32 = 4080;
+
+// Metric used when creating a new database. Databases created before the
+// metric was recorded are always squared euclidean.
+pub const DEFAULT_DISTANCE_METRIC: crate::features::distance_metric::DistanceMetric =
+	crate::features::distance_metric::DistanceMetric::Hamming;
 
 // Keys used to tell what direction a Node Path is going down
 pub const NEAR_KEY: u8 = b'n';
@@ -42,7 +94,36 @@ pub const SIGNATURE_RANGE: std::ops::Range<usize> = 0..4;
 pub const LEAF_NODE_SIGNATURE: &str = "leaf";
 pub const INTERNAL_NODE_SIGNATURE: &str = "intr";
 pub const FILE_NODE_SIGNATURE: &str = "file";
+// Layout of the nodes that each node's to_binary writes. Compaction and
+// node_view work out where nodes end from these instead of parsing them.
+// leaf: signature (4) | feature count (8) | features
+// internal: signature (4) | radius (4) | vantage | near length (8) | near
+// | far length (8) | far
+pub const UUID_DESCRIPTION_PAIR_LENGTH: usize = 8 + FEATURE_DESCRIPTION_LENGTH;
+pub const LEAF_NODE_HEADER_LENGTH: usize = 12;
+pub const INTERNAL_NODE_HEADER_LENGTH: usize = 8 + FEATURE_DESCRIPTION_LENGTH;
+pub const SIDE_LENGTH_LENGTH: usize = 8;
+// Start of the header written before the root node of every VP tree file.
+// The version has to be raised whenever the layout of the files changes.
+pub const FILE_HEADER_MAGIC: &str = "VPDB";
+pub const FILE_FORMAT_VERSION: u32 = 2;
 
 pub const DEFAULT_K: usize = 100;
 pub const MAX_K_VALUE: usize = 1000;
-pub const THREADED_SEARCH: bool = true;
+// Open the files most likely to hold a neighbor first instead of walking
+// the tree in order
+pub const BEST_FIRST_SEARCH: bool = true;
+
+// All searches of one request to the web server together give up after this
+// much work so one image can not keep the server busy for long. It is split
+// evenly between the descriptors of the request, of which an image has ~500.
+pub const SERVER_REQUEST_BUDGET: crate::feature_tree::search_result::SearchBudget =
+	crate::feature_tree::search_result::SearchBudget {
+		max_comparisons: Some(100_000_000),
+		max_leaf_visits: None,
+		max_file_loads: Some(4096),
+	};
+// Requests with more descriptors are refused. As every search gets at least
+// one of each limit, this may not be more than the smallest limit above or
+// the searches together could do more than SERVER_REQUEST_BUDGET.
+pub const MAX_DESCRIPTORS_PER_REQUEST: usize = 4096;
@@ -0,0 +1,267 @@
+use crate::database_config::DatabaseConfig;
+use crate::database_config::RequestedConfig;
+use crate::error::Error;
+use crate::extract_from_image::PointOfInterest;
+use crate::feature_tree::compaction::CompactionCounts;
+use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::search_result::SearchOptions;
+use crate::features::feature_description::FeatureDescription;
+use crate::frame_info::FrameInfo;
+use crate::insert_log::ReplayedInsert;
+use crate::metadata_database::FileSelector;
+use crate::search::CountedSearchResult;
+use crate::verify::DatabaseReport;
+use crate::verify::RepairReport;
+
+use crate::features_database;
+use crate::insert_log;
+use crate::metadata_database;
+
+use rocket::serde::Serialize;
+
+// A handle to one database or collection. Everything it does goes to the
+// files named by its config, so handles to different collections can be
+// used side by side. Cloning a handle does not open the database again.
+#[derive(Clone, Debug)]
+pub struct Database {
+	config: DatabaseConfig,
+	recovery: Recovery,
+}
+
+// What Database::open did about changes that were interrupted the last time
+// the database was used
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Recovery {
+	// Staged changes to the tree whose commit was interrupted, see
+	// feature_tree::staging
+	pub finished_tree_change: bool,
+	pub replayed_insert: Option<ReplayedInsert>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DatabaseStats {
+	pub files: u64,
+	pub features: u64,
+	pub tree_files: usize,
+	pub tree_bytes: u64,
+	pub metric: &'static str,
+	pub max_leaf_node_size: u64,
+	pub max_file_node_depth: usize,
+}
+
+impl Database {
+	// Creates the database if it does not exist yet and finishes any change
+	// that was interrupted, see feature_tree::staging and insert_log::replay
+	pub fn open(requested: &RequestedConfig) -> Result<Database, Error> {
+		let mut config = requested.to_config_without_database_settings();
+		metadata_database::initialize_database(&config)?;
+		metadata_database::apply_database_settings(&mut config, requested)?;
+		let recovery = Recovery {
+			finished_tree_change: crate::feature_tree::staging::recover(&config)?,
+			replayed_insert: insert_log::replay(&config)?,
+		};
+		return Ok(Database {
+			config: config,
+			recovery: recovery,
+		});
+	}
+
+	// Opens an existing database without changing anything, so it can be
+	// checked as it was left. Interrupted changes are not finished, which
+	// Database::verify reports.
+	pub fn open_read_only(requested: &RequestedConfig) -> Result<Database, Error> {
+		let mut config = requested.to_config_without_database_settings();
+		if std::path::Path::new(&config.sqlite_database_path).exists() == false {
+			return Err(Error::InvalidInput(format!(
+				"There is no database at {}",
+				config.sqlite_database_path
+			)));
+		}
+		metadata_database::read_database_settings(&mut config, requested)?;
+		return Ok(Database {
+			config: config,
+			recovery: Recovery::default(),
+		});
+	}
+
+	pub fn get_config(&self) -> &DatabaseConfig {
+		return &self.config;
+	}
+
+	pub fn get_recovery(&self) -> Recovery {
+		return self.recovery;
+	}
+
+	// The md5 and extension of the image are taken from its file name
+	pub fn add_image(&self, file_path: &str) -> Result<(), Error> {
+		return crate::add::add_image_to_database(file_path, &self.config);
+	}
+
+	pub fn add_features(
+		&self,
+		frame: FrameInfo,
+		features: Vec<PointOfInterest>,
+	) -> Result<(), Error> {
+		return crate::add::add_features_to_database(frame, features, &self.config);
+	}
+
+	pub fn add_python_binary(&self, file_path: &str) -> Result<(), Error> {
+		return crate::add::add_python_binary_to_database(file_path, &self.config);
+	}
+
+	// Rebuilds the whole tree, so every feature is held in memory
+	pub fn bulk_add_python_binary(&self, file_path: &str) -> Result<(), Error> {
+		return crate::add::bulk_add_python_binary_to_database(file_path, &self.config);
+	}
+
+	// Returns the results of every description in the order they were given
+	pub fn search(
+		&self,
+		descriptions: Vec<FeatureDescription>,
+		options: SearchOptions,
+	) -> Result<Vec<CountedSearchResult>, Error> {
+		return crate::search::search_for_all_descriptions(descriptions, options, &self.config);
+	}
+
+	pub fn search_image(
+		&self,
+		file_path: &str,
+		options: SearchOptions,
+	) -> Result<Vec<CountedSearchResult>, Error> {
+		let descriptions = crate::extract_from_image::get_features_from_image_path(file_path)?
+			.into_iter()
+			.map(|e| e.description)
+			.collect();
+		return self.search(descriptions, options);
+	}
+
+	// Returns how many features and how many files were removed
+	pub fn remove(&self, selector: FileSelector) -> Result<(u64, usize), Error> {
+		return crate::remove::remove_file_from_database(selector, &self.config);
+	}
+
+	pub fn stats(&self) -> Result<DatabaseStats, Error> {
+		let (files, features) = metadata_database::count_files_and_features(&self.config)?;
+		let tree_files = NodePath::get_all_in_database(&self.config)?;
+		let tree_bytes = tree_files
+			.iter()
+			.map(|path| {
+				std::fs::metadata(path.to_file_path_string(&self.config))
+					.map(|metadata| metadata.len())
+					.unwrap_or(0)
+			})
+			.sum();
+
+		return Ok(DatabaseStats {
+			files: files,
+			features: features,
+			tree_files: tree_files.len(),
+			tree_bytes: tree_bytes,
+			metric: self.config.metric.to_name(),
+			max_leaf_node_size: self.config.max_leaf_node_size,
+			max_file_node_depth: self.config.max_file_node_depth,
+		});
+	}
+
+	// Rebuilds the whole tree balanced, holding every feature in memory.
+	// Returns how many features the tree holds.
+	pub fn rebuild(&self) -> Result<usize, Error> {
+		return features_database::bulk_insert_description_vec_into_database(vec![], &self.config);
+	}
+
+	// Returns how many features the tree holds and how many subtrees were
+	// rebuilt
+	pub fn rebalance(&self) -> Result<(u64, u64), Error> {
+		return features_database::rebalance_database(&self.config);
+	}
+
+	pub fn compact(&self) -> Result<CompactionCounts, Error> {
+		return features_database::compact_database(&self.config);
+	}
+
+	pub fn verify(&self) -> Result<DatabaseReport, Error> {
+		return crate::verify::check_database(&self.config);
+	}
+
+	pub fn repair(&self) -> Result<RepairReport, Error> {
+		return crate::verify::repair_database(&self.config);
+	}
+
+	// `file_path` is a file of the tree, which is printed as if it was the root
+	pub fn print_file(&self, file_path: String) -> Result<(), Error> {
+		return features_database::print_path(file_path, &self.config);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn add_image(database: &Database, md5: &str, seed: u64) -> Vec<FeatureDescription> {
+		let frame = FrameInfo::new(String::from(md5), String::from("png"), 0);
+		let points = PointOfInterest::seeded_random(seed, 20);
+		let descriptions = points.iter().map(|e| e.description.clone()).collect();
+		database.add_features(frame, points).unwrap();
+		return descriptions;
+	}
+
+	#[test]
+	fn database_handles_add_search_and_remove() {
+		let requested = RequestedConfig::for_test_database("database_handle");
+		let database = Database::open(&requested).unwrap();
+		let recovery = database.get_recovery();
+		assert!(recovery.finished_tree_change == false);
+		assert!(recovery.replayed_insert.is_none());
+
+		let first = add_image(&database, "first", 91);
+		let second = add_image(&database, "second", 92);
+		let stats = database.stats().unwrap();
+		assert_eq!((stats.files, stats.features), (2, 40));
+		assert_eq!(stats.metric, database.get_config().metric.to_name());
+
+		// Every feature is found with the metadata of its image, in the order
+		// they were asked for
+		let searched = vec![first[3].clone(), second[5].clone()];
+		let results = database.search(searched, SearchOptions::new(1)).unwrap();
+		assert_eq!(results.len(), 2);
+		for (counted_results, md5) in results.iter().zip(["first", "second"]) {
+			let (result, metadata) = &counted_results.results[0];
+			assert_eq!(result.get_distance(), 0);
+			assert_eq!(metadata.uuid, result.get_result_uuid());
+			assert_eq!(metadata.md5, md5);
+		}
+
+		// Clones share the database
+		let clone = database.clone();
+		assert_eq!(
+			clone
+				.remove(FileSelector::Md5(String::from("first")))
+				.unwrap(),
+			(20, 1)
+		);
+		let results = database
+			.search(vec![first[3].clone()], SearchOptions::new(1))
+			.unwrap();
+		assert!(results[0].results[0].1.md5 != "first");
+		assert_eq!(database.stats().unwrap().features, 20);
+		assert!(database.verify().unwrap().is_consistent());
+
+		// Opening it again finds the same database
+		let reopened = Database::open_read_only(&requested).unwrap();
+		assert_eq!(reopened.stats().unwrap().features, 20);
+		database.get_config().remove_test_database();
+	}
+
+	#[test]
+	fn read_only_handles_need_an_existing_database() {
+		let requested = RequestedConfig::for_test_database("database_read_only");
+		assert!(Database::open_read_only(&requested)
+			.unwrap_err()
+			.is_invalid_input());
+		assert!(
+			std::path::Path::new(requested.database_folder_path.as_ref().unwrap()).exists()
+				== false
+		);
+	}
+}
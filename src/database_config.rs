@@ -0,0 +1,354 @@
+use crate::error::Error;
+use crate::feature_tree::vantage::VantageStrategy;
+use crate::features::distance_metric::DistanceMetric;
+
+use rocket::figment::providers::Format;
+use rocket::figment::providers::Toml;
+use rocket::figment::value::Value;
+use rocket::figment::Figment;
+use rocket::serde::Deserialize;
+
+// Settings that used to be compile time constants, passed to everything that
+// touches the files of a database. The metric, the split parameters and the
+// vantage strategy belong to the database rather than to the program, so they are recorded in its
+// 'settings' table when it is created and it is always opened with them, see
+// metadata_database::apply_database_settings.
+#[derive(Clone, Debug)]
+pub struct DatabaseConfig {
+	pub database_folder_path: String,
+	pub sqlite_database_path: String,
+	pub threaded_insert: bool,
+	pub threaded_search: bool,
+	// Search for all features of an image in one walk of the tree instead
+	// of one walk per feature. Takes priority over threaded_search and
+	// BEST_FIRST_SEARCH, which only apply to searches of one feature.
+	pub batch_search: bool,
+	// Search memory mapped database files in place instead of parsing them
+	// into nodes first. Searches done this way skip NODE_CACHE_MAX_BYTES
+	// entirely, so it stays off until it has been measured against the cache.
+	pub memory_mapped_search: bool,
+	pub file_node_memory_saver: bool,
+	// Images can only be removed over the network with this token, see
+	// network::remove_image
+	pub remove_token: Option<String>,
+
+	pub metric: DistanceMetric,
+	pub max_leaf_node_size: u64,
+	pub max_file_node_depth: usize,
+	pub vantage_strategy: VantageStrategy,
+
+	// Set while changes to the tree are staged, see feature_tree::staging
+	pub(crate) staging_folder_path: Option<String>,
+}
+
+impl DatabaseConfig {
+	pub fn path_in_folder(&self, file_name: &str) -> String {
+		return std::path::Path::new(&self.database_folder_path)
+			.join(file_name)
+			.to_string_lossy()
+			.into_owned();
+	}
+
+	// Makes renames and removals in the database folder survive a crash
+	pub fn sync_database_folder(&self) -> std::io::Result<()> {
+		return std::fs::File::open(&self.database_folder_path)?.sync_all();
+	}
+}
+
+// What was asked for by the config file and the command line. Everything
+// left out uses its default, or for per database settings whatever the
+// database was built with.
+#[derive(Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct RequestedConfig {
+	pub database_folder_path: Option<String>,
+	pub sqlite_database_path: Option<String>,
+	pub threaded_insert: Option<bool>,
+	pub threaded_search: Option<bool>,
+	pub batch_search: Option<bool>,
+	pub memory_mapped_search: Option<bool>,
+	pub file_node_memory_saver: Option<bool>,
+	pub remove_token: Option<String>,
+
+	pub metric: Option<String>,
+	pub max_leaf_node_size: Option<u64>,
+	pub max_file_node_depth: Option<usize>,
+	pub vantage_strategy: Option<String>,
+}
+
+impl RequestedConfig {
+	// Reads the TOML file at `config_file_path` if there is one, and then
+	// applies `overrides`, which are name=value pairs from the command line.
+	// A collection reads its own config file on top of the first one, but
+	// always keeps its files in its own folder whatever the configs say. The
+	// settings that belong to a database are not taken from the first file,
+	// as they were given for the main database.
+	pub fn load(
+		config_file_path: &str,
+		collection: Option<&str>,
+		overrides: &[&str],
+	) -> Result<RequestedConfig, Error> {
+		let figment = Figment::from(Toml::string(&read_config_file(config_file_path)?));
+		let name = match collection {
+			Some(name) => name,
+			None => return extract(merge_overrides(figment, overrides)?),
+		};
+
+		let folder_path = extract(merge_overrides(figment.clone(), overrides)?)?
+			.get_collection_folder_path(name)?;
+		let collection_config_path = std::path::Path::new(&folder_path)
+			.join(crate::constants::COLLECTION_CONFIG_FILE_NAME)
+			.to_string_lossy()
+			.into_owned();
+		let collection_figment =
+			Figment::from(Toml::string(&read_config_file(&collection_config_path)?));
+		let own = extract(merge_overrides(collection_figment.clone(), overrides)?)?;
+
+		let mut requested = extract(merge_overrides(
+			figment.merge(collection_figment),
+			overrides,
+		)?)?;
+		requested.database_folder_path = Some(folder_path);
+		requested.sqlite_database_path = None;
+		requested.metric = own.metric;
+		requested.max_leaf_node_size = own.max_leaf_node_size;
+		requested.max_file_node_depth = own.max_file_node_depth;
+		requested.vantage_strategy = own.vantage_strategy;
+		return Ok(requested);
+	}
+
+	fn get_database_folder_path(&self) -> String {
+		return self
+			.database_folder_path
+			.clone()
+			.unwrap_or_else(|| String::from(crate::constants::DEFAULT_DATABASE_FOLDER_PATH));
+	}
+
+	// Collections live next to each other below the database folder. Names
+	// come from the command line and from urls, so they are kept to
+	// characters that can not lead out of that folder.
+	pub fn get_collection_folder_path(&self, name: &str) -> Result<String, Error> {
+		if is_valid_collection_name(name) == false {
+			return Err(Error::InvalidInput(format!(
+				"Collection name {} may only contain letters, digits, '_' and '-'",
+				name
+			)));
+		}
+		return Ok(std::path::Path::new(&self.get_database_folder_path())
+			.join(crate::constants::COLLECTIONS_FOLDER_NAME)
+			.join(name)
+			.to_string_lossy()
+			.into_owned());
+	}
+
+	// Every collection that has been created so far
+	pub fn get_collection_names(&self) -> Result<Vec<String>, Error> {
+		let collections_path = std::path::Path::new(&self.get_database_folder_path())
+			.join(crate::constants::COLLECTIONS_FOLDER_NAME);
+		let entries = match std::fs::read_dir(&collections_path) {
+			Ok(entries) => entries,
+			Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+			Err(error) => return Err(read_error(error)),
+		};
+
+		let mut names = vec![];
+		for entry in entries {
+			let entry = entry.map_err(read_error)?;
+			let name = entry.file_name().to_string_lossy().into_owned();
+			if entry.path().is_dir() && is_valid_collection_name(&name) {
+				names.push(name);
+			}
+		}
+		names.sort();
+		return Ok(names);
+
+		fn read_error(error: std::io::Error) -> Error {
+			return Error::io(String::from("Reading the collections folder failed"), error);
+		}
+	}
+
+	pub fn get_metric(&self) -> Result<Option<DistanceMetric>, Error> {
+		return self
+			.metric
+			.as_ref()
+			.map(|name| {
+				DistanceMetric::from_name(name).ok_or_else(|| {
+					Error::InvalidInput(format!("Config asks for unknown metric {}", name))
+				})
+			})
+			.transpose();
+	}
+
+	pub fn get_vantage_strategy(&self) -> Result<Option<VantageStrategy>, Error> {
+		return self
+			.vantage_strategy
+			.as_ref()
+			.map(|name| {
+				VantageStrategy::from_name(name).ok_or_else(|| {
+					Error::InvalidInput(format!(
+						"Config asks for unknown vantage strategy {}",
+						name
+					))
+				})
+			})
+			.transpose();
+	}
+
+	// Names of the settings that are recorded in the database they were
+	// given for
+	pub fn is_database_setting(name: &str) -> bool {
+		return name == "metric"
+			|| name == "max_leaf_node_size"
+			|| name == "max_file_node_depth"
+			|| name == "vantage_strategy";
+	}
+
+	// Settings stored in the database are only filled in by
+	// metadata_database::apply_database_settings
+	pub fn to_config_without_database_settings(&self) -> DatabaseConfig {
+		let mut config = DatabaseConfig {
+			database_folder_path: self.get_database_folder_path(),
+			sqlite_database_path: String::new(),
+			threaded_insert: self
+				.threaded_insert
+				.unwrap_or(crate::constants::DEFAULT_THREADED_INSERT),
+			threaded_search: self
+				.threaded_search
+				.unwrap_or(crate::constants::DEFAULT_THREADED_SEARCH),
+			batch_search: self
+				.batch_search
+				.unwrap_or(crate::constants::DEFAULT_BATCH_SEARCH),
+			memory_mapped_search: self
+				.memory_mapped_search
+				.unwrap_or(crate::constants::DEFAULT_MEMORY_MAPPED_SEARCH),
+			file_node_memory_saver: self
+				.file_node_memory_saver
+				.unwrap_or(crate::constants::DEFAULT_FILE_NODE_MEMORY_SAVER),
+			remove_token: self.remove_token.clone(),
+			metric: crate::constants::DEFAULT_DISTANCE_METRIC,
+			max_leaf_node_size: crate::constants::DEFAULT_MAX_LEAF_NODE_SIZE,
+			max_file_node_depth: crate::constants::DEFAULT_MAX_FILE_NODE_DEPTH,
+			vantage_strategy: crate::constants::DEFAULT_VANTAGE_STRATEGY,
+			staging_folder_path: None,
+		};
+		config.sqlite_database_path = self
+			.sqlite_database_path
+			.clone()
+			.unwrap_or_else(|| config.path_in_folder(crate::constants::SQLITE_DATABASE_FILE_NAME));
+		return config;
+	}
+}
+
+fn read_config_file(path: &str) -> Result<String, Error> {
+	return match std::fs::read_to_string(path) {
+		Ok(contents) => Ok(contents),
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+		Err(error) => Err(Error::io(
+			format!("Reading config file {} failed", path),
+			error,
+		)),
+	};
+}
+
+fn merge_overrides(mut figment: Figment, overrides: &[&str]) -> Result<Figment, Error> {
+	for pair in overrides {
+		let (name, value) = pair.split_once('=').ok_or_else(|| {
+			Error::InvalidInput(format!(
+				"Config override {} is not of the form name=value",
+				pair
+			))
+		})?;
+		let value = value
+			.parse::<Value>()
+			.expect("Parsing a config override can not fail");
+		figment = figment.merge((name, value));
+	}
+	return Ok(figment);
+}
+
+fn extract(figment: Figment) -> Result<RequestedConfig, Error> {
+	return figment
+		.extract()
+		.map_err(|error| Error::InvalidInput(format!("Config is not valid: {}", error)));
+}
+
+fn is_valid_collection_name(name: &str) -> bool {
+	return name.is_empty() == false
+		&& name
+			.chars()
+			.all(|e| e.is_ascii_alphanumeric() || e == '_' || e == '-');
+}
+
+#[cfg(test)]
+impl DatabaseConfig {
+	// A new database in its own folder below the temporary folder, with
+	// split parameters small enough that a few features make many files.
+	// Remove it with remove_test_database.
+	pub(crate) fn new_test_database(name: &str) -> DatabaseConfig {
+		return crate::database::Database::open(&RequestedConfig::for_test_database(name))
+			.expect("Creating a test database failed")
+			.get_config()
+			.clone();
+	}
+
+	pub(crate) fn remove_test_database(&self) {
+		std::fs::remove_dir_all(&self.database_folder_path)
+			.expect("Removing a test database failed");
+	}
+}
+
+#[cfg(test)]
+impl RequestedConfig {
+	// What DatabaseConfig::new_test_database opens, for tests that change
+	// some of it first
+	pub(crate) fn for_test_database(name: &str) -> RequestedConfig {
+		let folder_path = std::env::temp_dir()
+			.join(format!("feature_database_{}_{}", std::process::id(), name))
+			.to_string_lossy()
+			.into_owned();
+		let _ = std::fs::remove_dir_all(&folder_path);
+
+		return RequestedConfig {
+			database_folder_path: Some(folder_path),
+			threaded_insert: Some(false),
+			threaded_search: Some(false),
+			max_leaf_node_size: Some(4),
+			max_file_node_depth: Some(2),
+			..RequestedConfig::default()
+		};
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn collection_names_stay_in_their_folder() {
+		for name in ["photos", "frames_2024", "a-b", "X"] {
+			assert!(is_valid_collection_name(name), "{}", name);
+		}
+		for name in ["", "..", "../other", "a/b", "a\\b", ".hidden", "a b", "ä"] {
+			assert!(is_valid_collection_name(name) == false, "{}", name);
+		}
+	}
+
+	#[test]
+	fn collection_folders_are_below_the_collections_folder() {
+		let requested = RequestedConfig {
+			database_folder_path: Some(String::from("database")),
+			..RequestedConfig::default()
+		};
+		let expected = std::path::Path::new("database")
+			.join(crate::constants::COLLECTIONS_FOLDER_NAME)
+			.join("photos");
+		assert_eq!(
+			requested.get_collection_folder_path("photos").unwrap(),
+			expected.to_string_lossy()
+		);
+		assert!(matches!(
+			requested.get_collection_folder_path("../photos"),
+			Err(Error::InvalidInput(_))
+		));
+	}
+}
@@ -0,0 +1,91 @@
+use crate::feature_tree::tree_error::TreeError;
+
+use std::fmt;
+
+// Everything that can go wrong while using a database. Problems in the files
+// of the tree are TreeErrors, which name the file that is broken. `context`
+// says what was being done when the error happened.
+#[derive(Debug)]
+pub enum Error {
+	Io {
+		context: String,
+		error: std::io::Error,
+	},
+	Tree(TreeError),
+	Sqlite {
+		context: String,
+		error: rusqlite::Error,
+	},
+	OpenCv {
+		context: String,
+		error: opencv::Error,
+	},
+	// The sqlite3 database or the insert log holds something this build can
+	// not make sense of
+	Corrupt(String),
+	// The request itself is wrong, like an image that can not be decoded or
+	// a setting that does not match the database
+	InvalidInput(String),
+	// A thread doing part of the work panicked, `context` says which one
+	Panicked(String),
+}
+
+impl Error {
+	pub fn io(context: String, error: std::io::Error) -> Error {
+		return Error::Io {
+			context: context,
+			error: error,
+		};
+	}
+
+	pub fn sqlite(context: &str, error: rusqlite::Error) -> Error {
+		return Error::Sqlite {
+			context: String::from(context),
+			error: error,
+		};
+	}
+
+	pub fn opencv(context: String, error: opencv::Error) -> Error {
+		return Error::OpenCv {
+			context: context,
+			error: error,
+		};
+	}
+
+	// Whether the one asking is at fault rather than the database
+	pub fn is_invalid_input(&self) -> bool {
+		return matches!(self, Error::InvalidInput(_));
+	}
+}
+
+impl From<TreeError> for Error {
+	fn from(error: TreeError) -> Error {
+		return Error::Tree(error);
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return match self {
+			Error::Io { context, error } => write!(f, "{}: {}", context, error),
+			Error::Tree(error) => write!(f, "{}", error),
+			Error::Sqlite { context, error } => write!(f, "{}: {}", context, error),
+			Error::OpenCv { context, error } => write!(f, "{}: {}", context, error),
+			Error::Corrupt(reason) => write!(f, "Database is corrupt: {}", reason),
+			Error::InvalidInput(reason) => write!(f, "{}", reason),
+			Error::Panicked(context) => write!(f, "{} panicked", context),
+		};
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		return match self {
+			Error::Io { error, .. } => Some(error),
+			Error::Tree(error) => Some(error),
+			Error::Sqlite { error, .. } => Some(error),
+			Error::OpenCv { error, .. } => Some(error),
+			_ => None,
+		};
+	}
+}
@@ -1,6 +1,7 @@
 // Used this as a point of reference for how to use OpenCV in rust
 // https://github.com/donkeyteethUX/abow/blob/09afd87afa856afb8f720a8942edcd32febc5a27/src/opencv_utils.rs
 
+use crate::error::Error;
 use crate::features::feature_description::FeatureDescription;
 
 use opencv::core::KeyPoint;
@@ -15,24 +16,28 @@ pub struct PointOfInterest {
 	pub description: FeatureDescription,
 }
 
-pub fn get_features_from_image_path(image_path: &str) -> Vec<PointOfInterest> {
-	let image = load_image_path(image_path);
-	let features = get_features_from_image(&image);
-	return features;
+pub fn get_features_from_image_path(image_path: &str) -> Result<Vec<PointOfInterest>, Error> {
+	let image = load_image_path(image_path)?;
+	let features = get_features_from_image(&image).map_err(|error| {
+		Error::opencv(
+			format!("Computing keypoints for image {} failed", image_path),
+			error,
+		)
+	})?;
+	return Ok(features);
 }
 
-fn get_features_from_image(image: &CvImage) -> Vec<PointOfInterest> {
+fn get_features_from_image(image: &CvImage) -> opencv::Result<Vec<PointOfInterest>> {
 	// If really want to edit the defaults later
 	// https://docs.rs/opencv/0.53.1/opencv/features2d/trait.ORB.html#method.create
-	let mut orb = <dyn opencv::features2d::ORB>::default().unwrap();
+	let mut orb = <dyn opencv::features2d::ORB>::default()?;
 
 	let mask = CvMat::default();
 	let mut keypoints = opencv::types::VectorOfKeyPoint::new();
 	let mut descriptions = CvMat::default();
-	orb.detect_and_compute(image, &mask, &mut keypoints, &mut descriptions, false)
-		.expect("Computing keypoints for image failed");
+	orb.detect_and_compute(image, &mask, &mut keypoints, &mut descriptions, false)?;
 
-	let descriptions = matrix_to_vec_of_descriptions(descriptions, keypoints.len() as i32, 32);
+	let descriptions = matrix_to_vec_of_descriptions(descriptions, keypoints.len() as i32, 32)?;
 
 	let mut points_of_interest = vec![];
 	for (keypoint, description) in keypoints.into_iter().zip(descriptions.into_iter()) {
@@ -42,41 +47,63 @@ fn get_features_from_image(image: &CvImage) -> Vec<PointOfInterest> {
 		});
 	}
 
-	return points_of_interest;
+	return Ok(points_of_interest);
 }
 
 fn matrix_to_vec_of_descriptions(
 	matrix: CvImage,
 	rows: i32,
 	columns: i32,
-) -> Vec<FeatureDescription> {
+) -> opencv::Result<Vec<FeatureDescription>> {
 	let mut all_rows = vec![];
 	for i in 0..rows {
 		let mut this_row = vec![];
 		for j in 0..columns {
-			this_row.push(
-				*matrix
-					.at_2d::<u8>(i, j)
-					.expect("Somehow accessed invalid index in image"),
-			);
+			this_row.push(*matrix.at_2d::<u8>(i, j)?);
 		}
 		all_rows.push(FeatureDescription::new_from_vec(this_row));
 	}
-	return all_rows;
+	return Ok(all_rows);
 }
 
-fn load_image_path(image_path: &str) -> CvImage {
+fn load_image_path(image_path: &str) -> Result<CvImage, Error> {
 	let image = opencv::imgcodecs::imread(
 		image_path,
 		opencv::imgcodecs::IMREAD_COLOR, // https://docs.rs/opencv/0.53.1/opencv/imgcodecs/enum.ImreadModes.html
 	)
-	.expect("Should error here but doesn't");
+	.map_err(|error| Error::opencv(format!("Reading image {} failed", image_path), error))?;
 
 	// If the image cannot be read (because of missing file, improper permissions,
 	// unsupported or invalid format), the function returns an empty matrix.
 	if image.cols() == 0 && image.rows() == 0 {
-		panic!("Image loaded but zero valued columns and rows mean error reading");
+		return Err(Error::InvalidInput(format!(
+			"Image {} is missing or could not be decoded",
+			image_path
+		)));
 	}
 
-	return image;
+	return Ok(image);
+}
+
+#[cfg(test)]
+impl PointOfInterest {
+	// `count` random features as if they were found in an image, the same
+	// ones for the same seed
+	pub(crate) fn seeded_random(seed: u64, count: usize) -> Vec<PointOfInterest> {
+		return FeatureDescription::seeded_random(seed)
+			.take(count)
+			.enumerate()
+			.map(|(i, description)| PointOfInterest {
+				metadata: KeyPoint {
+					pt: opencv::core::Point_::new(i as f32, i as f32),
+					size: 1.0,
+					angle: 0.0,
+					response: 1.0,
+					octave: 0,
+					class_id: 1,
+				},
+				description: description,
+			})
+			.collect();
+	}
 }
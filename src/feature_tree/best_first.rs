@@ -0,0 +1,149 @@
+use crate::feature_tree::file_node::FileNode;
+use crate::feature_tree::node::Node;
+use crate::feature_tree::node::TreeNode;
+use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::search_result::LowerBound;
+use crate::feature_tree::search_result::SearchResultList;
+use crate::feature_tree::tree_error::TreeError;
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// Reading files is what makes a search slow, so instead of following files
+// in the order the tree is walked, every file that is reached is put into a
+// queue and the one that could hold the closest feature is opened next. Nodes
+// inside of a single file are still searched depth first.
+pub struct PendingFile {
+	bound: LowerBound,
+	path_in_tree: NodePath,
+}
+
+impl PendingFile {
+	pub fn new(bound: LowerBound, path_in_tree: NodePath) -> PendingFile {
+		return PendingFile {
+			bound,
+			path_in_tree,
+		};
+	}
+}
+
+// Reversed so that the BinaryHeap pops the smallest bound first
+impl Ord for PendingFile {
+	fn cmp(&self, other: &Self) -> Ordering {
+		return other
+			.bound
+			.true_metric
+			.partial_cmp(&self.bound.true_metric)
+			.unwrap_or(Ordering::Equal);
+	}
+}
+
+impl PartialOrd for PendingFile {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Eq for PendingFile {}
+
+impl PartialEq for PendingFile {
+	fn eq(&self, other: &Self) -> bool {
+		return self.cmp(other) == Ordering::Equal;
+	}
+}
+
+pub type PendingFiles = BinaryHeap<PendingFile>;
+
+pub fn find_best_first(root: &Node, results: &mut SearchResultList) -> Result<(), TreeError> {
+	let mut pending = PendingFiles::new();
+	root.find_best_first(results, LowerBound::zero(), &mut pending)?;
+
+	return search_pending_files(results, pending, |path_in_tree, results, bound, pending| {
+		return FileNode::read_contents(path_in_tree, results.get_config())?
+			.find_best_first(results, bound, pending);
+	});
+}
+
+// Opens pending files closest first with `search_file`, which may add more
+// files to the queue
+pub fn search_pending_files<F>(
+	results: &mut SearchResultList,
+	mut pending: PendingFiles,
+	mut search_file: F,
+) -> Result<(), TreeError>
+where
+	F: FnMut(
+		&NodePath,
+		&mut SearchResultList,
+		LowerBound,
+		&mut PendingFiles,
+	) -> Result<(), TreeError>,
+{
+	while let Some(file) = pending.pop() {
+		// The bound may have stopped being good enough while the file waited
+		if results.could_contain(file.bound) == false {
+			continue;
+		}
+
+		if results.try_to_load_file() == false {
+			return Ok(());
+		}
+
+		search_file(&file.path_in_tree, results, file.bound, &mut pending)?;
+	}
+	return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database_config::DatabaseConfig;
+	use crate::feature_tree::search_result::SearchOptions;
+	use crate::features::feature_description::FeatureDescription;
+	use crate::features::uuid_description_pair::UUIDDescriptionPair;
+	use crate::features_database;
+
+	// Distances and uuids of the results, as features at the same distance
+	// can be found in any order
+	fn found(results: SearchResultList) -> Vec<(u32, u64)> {
+		let mut found = results
+			.get_results()
+			.iter()
+			.map(|e| (e.get_distance(), e.get_result_uuid()))
+			.collect::<Vec<(u32, u64)>>();
+		found.sort_unstable();
+		return found;
+	}
+
+	#[test]
+	fn best_first_finds_what_depth_first_finds() {
+		let config = DatabaseConfig::new_test_database("best_first");
+		let all = UUIDDescriptionPair::seeded_random(21, 1, 300);
+		features_database::insert_description_vec_into_database(all.clone(), &config).unwrap();
+		let root = Node::get_root_node();
+
+		for target in FeatureDescription::seeded_random(22).take(10) {
+			let mut depth_first =
+				SearchResultList::new(SearchOptions::new(10), target.clone(), &config);
+			root.find(&mut depth_first).unwrap();
+			let mut best_first =
+				SearchResultList::new(SearchOptions::new(10), target.clone(), &config);
+			find_best_first(&root, &mut best_first).unwrap();
+			assert!(best_first.is_exact());
+
+			// Features as far away as the 10th can be either of them
+			let distances =
+				|found: Vec<(u32, u64)>| found.iter().map(|e| e.0).collect::<Vec<u32>>();
+			let expected = found(depth_first);
+			assert_eq!(distances(found(best_first)), distances(expected.clone()));
+
+			let options = SearchOptions::new_range(expected[9].0, None);
+			let mut depth_first = SearchResultList::new(options, target.clone(), &config);
+			root.find(&mut depth_first).unwrap();
+			let mut best_first = SearchResultList::new(options, target, &config);
+			find_best_first(&root, &mut best_first).unwrap();
+			assert_eq!(found(best_first), found(depth_first));
+		}
+		config.remove_test_database();
+	}
+}
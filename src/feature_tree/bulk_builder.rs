@@ -0,0 +1,166 @@
+use crate::database_config::DatabaseConfig;
+use crate::feature_tree::file_node::FileNode;
+use crate::feature_tree::internal_node::InternalNode;
+use crate::feature_tree::leaf_node::LeafNode;
+use crate::feature_tree::node::Node;
+use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::tree_error::TreeError;
+use crate::features::uuid_description_pair::UUIDDescriptionPair;
+
+// Builds a tree top down from every feature at once. Each internal node is
+// split at the median distance of everything below it, so unlike adding
+// features one at a time the resulting tree is balanced. Files are written
+// as soon as their contents are built and the root file is written last.
+pub fn build_tree(
+	pairs: Vec<UUIDDescriptionPair>,
+	config: &DatabaseConfig,
+) -> Result<(), TreeError> {
+	let root_path = get_root_path();
+	let root = build_node(pairs, root_path.clone(), config)?;
+	return FileNode::write_contents(&root_path, &root, config);
+}
+
+// Replaces the subtree at `path` with a balanced one. Only meant for staged
+// changes, see feature_tree::staging. The files below `path` are marked as
+// removed before the new ones are written, as they may share names, and are
+// only removed once the new ones have replaced them.
+pub fn rebuild_subtree(
+	pairs: Vec<UUIDDescriptionPair>,
+	path: NodePath,
+	config: &DatabaseConfig,
+) -> Result<Node, TreeError> {
+	remove_files_below(&path, config)?;
+	return build_node(pairs, path, config);
+}
+
+// Removes every file whose path starts with `path`, an empty path removes
+// every file of the tree
+pub fn remove_files_below(path: &NodePath, config: &DatabaseConfig) -> Result<(), TreeError> {
+	for file_path in NodePath::get_all_in_database(config)? {
+		if file_path.starts_with(path) {
+			FileNode::delete_file(&file_path, config)?;
+		}
+	}
+	return Ok(());
+}
+
+pub fn get_root_path() -> NodePath {
+	let mut root_path = NodePath::new_empty();
+	root_path.add_direction(crate::constants::FILE_KEY);
+	return root_path;
+}
+
+// `path` follows the same rules as the paths given to Node::add, so a tree
+// built here keeps growing the same way when features are added later.
+fn build_node(
+	pairs: Vec<UUIDDescriptionPair>,
+	path: NodePath,
+	config: &DatabaseConfig,
+) -> Result<Node, TreeError> {
+	if path.should_split_to_new_file(config) {
+		let mut file_path = path.clone();
+		file_path.add_direction(crate::constants::FILE_KEY);
+		let contents = build_node(pairs, file_path, config)?;
+		FileNode::write_contents(&path, &contents, config)?;
+		return Ok(FileNode::new_at_location(path));
+	}
+
+	if pairs.len() as u64 <= config.max_leaf_node_size {
+		return Ok(LeafNode::new(pairs));
+	}
+
+	let (vantage, radius, near_pairs, far_pairs) =
+		crate::feature_tree::internal_node::split_with_median_radius(pairs, config);
+
+	// Every feature is the same distance from the vantage, so splitting
+	// again would never end
+	if near_pairs.is_empty() || far_pairs.is_empty() {
+		let mut pairs = near_pairs;
+		pairs.extend(far_pairs);
+		return Ok(LeafNode::new(pairs));
+	}
+
+	let mut near_path = path.clone();
+	near_path.add_direction(crate::constants::NEAR_KEY);
+	let mut far_path = path;
+	far_path.add_direction(crate::constants::FAR_KEY);
+
+	return Ok(InternalNode::new(
+		vantage,
+		radius,
+		build_node(near_pairs, near_path, config)?,
+		build_node(far_pairs, far_path, config)?,
+	));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::feature_tree::search_result::SearchOptions;
+	use crate::feature_tree::verify::TreeReport;
+	use crate::features::feature_description::FeatureDescription;
+	use crate::features_database;
+
+	use std::collections::HashSet;
+
+	fn assert_is_whole(report: &TreeReport, uuids: &HashSet<u64>) {
+		assert!(report.unreadable_files.is_empty());
+		assert!(report.missing_files.is_empty());
+		assert!(report.misplaced_features.is_empty());
+		assert!(report.duplicate_uuids.is_empty());
+		assert!(report.orphaned_files.is_empty());
+		assert_eq!(&report.uuids, uuids);
+	}
+
+	fn nearest_distances(target: &FeatureDescription, config: &DatabaseConfig) -> Vec<u32> {
+		return features_database::find_feature_description_in_database(
+			target.clone(),
+			SearchOptions::new(10),
+			config,
+		)
+		.unwrap()
+		.get_results()
+		.iter()
+		.map(|e| e.get_distance())
+		.collect();
+	}
+
+	#[test]
+	fn bulk_build_matches_adding_one_at_a_time() {
+		let added = DatabaseConfig::new_test_database("bulk_builder_added");
+		let built = DatabaseConfig::new_test_database("bulk_builder_built");
+		let all = UUIDDescriptionPair::seeded_random(31, 1, 300);
+		let uuids = all.iter().map(|pair| pair.get_uuid()).collect();
+
+		features_database::insert_description_vec_into_database(all.clone(), &added).unwrap();
+		// Part of the features are already in the tree when it is rebuilt
+		features_database::insert_description_vec_into_database(all[..100].to_vec(), &built)
+			.unwrap();
+		assert_eq!(
+			features_database::bulk_insert_description_vec_into_database(
+				all[100..].to_vec(),
+				&built
+			)
+			.unwrap(),
+			300
+		);
+
+		assert_is_whole(&features_database::verify_database(&added).unwrap(), &uuids);
+		assert_is_whole(&features_database::verify_database(&built).unwrap(), &uuids);
+		for target in FeatureDescription::seeded_random(32).take(10) {
+			assert_eq!(
+				nearest_distances(&target, &built),
+				nearest_distances(&target, &added)
+			);
+		}
+
+		// Rebuilding keeps everything
+		assert_eq!(
+			features_database::bulk_insert_description_vec_into_database(vec![], &built).unwrap(),
+			300
+		);
+		assert_is_whole(&features_database::verify_database(&built).unwrap(), &uuids);
+		added.remove_test_database();
+		built.remove_test_database();
+	}
+}
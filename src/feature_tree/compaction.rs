@@ -0,0 +1,199 @@
+use crate::database_config::DatabaseConfig;
+use crate::feature_tree::file_node::FileNode;
+use crate::feature_tree::node::Node;
+use crate::feature_tree::node::TreeNode;
+use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::tree_error::TreeError;
+
+// Rewrites where the tree is split into files so that every file holds about
+// COMPACTION_TARGET_FILE_SIZE bytes, without changing the tree itself. Files
+// are compacted bottom up with TreeNode::compact. The changes are staged like
+// a rebuild, so an interrupted compaction leaves the tree as it was, see
+// feature_tree::staging.
+//
+// New files are named after the path to their node like the ones made by
+// Node::add. Two files can only share a name if they start at the same node,
+// so nothing that is still pointed to is ever overwritten.
+pub struct Compaction {
+	config: DatabaseConfig,
+	// Changes to the file currently being compacted
+	has_changed: bool,
+	merged_files: Vec<NodePath>,
+
+	pub counts: CompactionCounts,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompactionCounts {
+	pub files_rewritten: u64,
+	pub files_merged: u64,
+	pub files_split: u64,
+}
+
+impl Compaction {
+	// `config` has to be staged, see staging::begin
+	pub fn new(config: &DatabaseConfig) -> Compaction {
+		return Compaction {
+			config: config.clone(),
+			has_changed: false,
+			merged_files: vec![],
+			counts: CompactionCounts::default(),
+		};
+	}
+
+	pub fn get_config(&self) -> &DatabaseConfig {
+		return &self.config;
+	}
+
+	// Compacts `contents`, the node a file holds, and saves it if anything in
+	// it changed. Returns its size in bytes.
+	pub fn compact_file(
+		&mut self,
+		path_in_tree: &NodePath,
+		contents: &mut Node,
+		current_path: NodePath,
+	) -> Result<u64, TreeError> {
+		let outer_has_changed = std::mem::replace(&mut self.has_changed, false);
+		let outer_merged_files = std::mem::take(&mut self.merged_files);
+
+		let size = contents.compact(current_path, self)?;
+		if self.has_changed {
+			FileNode::write_contents(path_in_tree, contents, &self.config)?;
+			self.counts.files_rewritten += 1;
+			for merged_path in self.merged_files.iter() {
+				FileNode::delete_file(merged_path, &self.config)?;
+			}
+		}
+
+		self.has_changed = outer_has_changed;
+		self.merged_files = outer_merged_files;
+		return Ok(size);
+	}
+}
+
+// Compacts a child of an internal node and decides which file it belongs in.
+// Returns how many bytes it takes up in the file of its parent.
+pub fn compact_child(
+	child: &mut Node,
+	current_path: NodePath,
+	compaction: &mut Compaction,
+) -> Result<u64, TreeError> {
+	let size = child.compact(current_path.clone(), compaction)?;
+	let target = crate::constants::COMPACTION_TARGET_FILE_SIZE;
+
+	if let Node::File(file) = child {
+		if size < target {
+			let merged_path = file.get_path_in_tree().clone();
+			let contents = file.take_contents();
+			let _old_node = std::mem::replace(child, contents);
+			compaction.merged_files.push(merged_path);
+			compaction.has_changed = true;
+			compaction.counts.files_merged += 1;
+			return Ok(size);
+		}
+		file.close();
+		return Ok(child.to_binary().len() as u64);
+	}
+
+	if size > target {
+		// A file merged away earlier may have started at this node as well
+		compaction.merged_files.retain(|path| path != &current_path);
+		FileNode::write_contents(&current_path, child, &compaction.config)?;
+		let _old_node = std::mem::replace(child, FileNode::new_at_location(current_path));
+		compaction.has_changed = true;
+		compaction.counts.files_split += 1;
+		return Ok(child.to_binary().len() as u64);
+	}
+
+	return Ok(size);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::feature_tree::search_result::SearchOptions;
+	use crate::feature_tree::verify::TreeReport;
+	use crate::features::uuid_description_pair::UUIDDescriptionPair;
+	use crate::features_database;
+
+	use std::collections::HashSet;
+
+	fn assert_is_whole(report: &TreeReport, uuids: &HashSet<u64>) {
+		assert!(report.unreadable_files.is_empty());
+		assert!(report.missing_files.is_empty());
+		assert!(report.misplaced_features.is_empty());
+		assert!(report.duplicate_uuids.is_empty());
+		assert!(report.orphaned_files.is_empty());
+		assert!(report.orphaned_temp_files.is_empty());
+		assert_eq!(&report.uuids, uuids);
+	}
+
+	#[test]
+	fn compaction_keeps_every_feature() {
+		let config = DatabaseConfig::new_test_database("compaction_keeps_features");
+		let inserted = UUIDDescriptionPair::seeded_random(7, 1, 200);
+		features_database::insert_description_vec_into_database(inserted.clone(), &config).unwrap();
+		let removed = (1..201).step_by(3).collect::<HashSet<u64>>();
+		features_database::remove_uuids_from_database(&removed, &config).unwrap();
+		let kept = inserted
+			.iter()
+			.map(|pair| pair.get_uuid())
+			.filter(|uuid| removed.contains(uuid) == false)
+			.collect::<HashSet<u64>>();
+
+		let before = features_database::verify_database(&config).unwrap();
+		assert!(before.files_checked > 1);
+		assert_is_whole(&before, &kept);
+
+		features_database::compact_database(&config).unwrap();
+
+		// Every file is far below the target size, so all are merged into the
+		// root file
+		let after = features_database::verify_database(&config).unwrap();
+		assert_eq!(after.files_checked, 1);
+		assert_is_whole(&after, &kept);
+
+		// The tree itself did not change, so every feature is still found
+		for pair in inserted
+			.iter()
+			.filter(|pair| kept.contains(&pair.get_uuid()))
+		{
+			let results = features_database::find_feature_description_in_database(
+				pair.get_description().clone(),
+				SearchOptions::new(1),
+				&config,
+			)
+			.unwrap()
+			.get_results();
+			assert_eq!(results[0].get_distance(), 0);
+		}
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn compacting_twice_changes_nothing() {
+		let config = DatabaseConfig::new_test_database("compaction_twice");
+		let inserted = UUIDDescriptionPair::seeded_random(7, 1, 100);
+		features_database::insert_description_vec_into_database(inserted.clone(), &config).unwrap();
+		features_database::compact_database(&config).unwrap();
+
+		let root_path =
+			crate::feature_tree::bulk_builder::get_root_path().to_file_path_string(&config);
+		let modified = std::fs::metadata(&root_path).unwrap().modified().unwrap();
+		assert_eq!(
+			features_database::compact_database(&config).unwrap(),
+			CompactionCounts::default()
+		);
+		assert_eq!(
+			std::fs::metadata(&root_path).unwrap().modified().unwrap(),
+			modified
+		);
+
+		let uuids = inserted.iter().map(|pair| pair.get_uuid()).collect();
+		assert_is_whole(
+			&features_database::verify_database(&config).unwrap(),
+			&uuids,
+		);
+		config.remove_test_database();
+	}
+}
@@ -0,0 +1,333 @@
+use crate::database_config::DatabaseConfig;
+use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::tree_error::TreeError;
+use crate::features::distance_metric::DistanceMetric;
+
+use std::convert::TryInto;
+
+// Every VP tree file starts with this header so that a tree built with other
+// settings is refused instead of being searched as if nothing was wrong.
+// Files from before the header existed start directly with a node signature.
+// They are still read, and get a header the next time they are saved.
+//
+// magic (4) | version (4) | descriptor length (4) | metric (4)
+// | max leaf node size (8) | max file node depth (8)
+// | payload length (8) | payload crc32 (4)
+pub const HEADER_LENGTH: usize = 44;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileHeader {
+	version: u32,
+	descriptor_length: u32,
+	metric: DistanceMetric,
+	max_leaf_node_size: u64,
+	max_file_node_depth: u64,
+}
+
+impl FileHeader {
+	pub fn current(config: &DatabaseConfig) -> FileHeader {
+		return FileHeader {
+			version: crate::constants::FILE_FORMAT_VERSION,
+			descriptor_length: crate::constants::FEATURE_DESCRIPTION_LENGTH as u32,
+			metric: config.metric,
+			max_leaf_node_size: config.max_leaf_node_size,
+			max_file_node_depth: config.max_file_node_depth as u64,
+		};
+	}
+
+	// `payload` is the root node that is written after the header
+	pub fn to_binary(&self, payload: &[u8]) -> Vec<u8> {
+		let mut results = vec![];
+		results.append(&mut crate::constants::FILE_HEADER_MAGIC.as_bytes().to_vec());
+		results.append(&mut self.version.to_le_bytes().to_vec());
+		results.append(&mut self.descriptor_length.to_le_bytes().to_vec());
+		results.append(&mut self.metric.to_id().to_le_bytes().to_vec());
+		results.append(&mut self.max_leaf_node_size.to_le_bytes().to_vec());
+		results.append(&mut self.max_file_node_depth.to_le_bytes().to_vec());
+		results.append(&mut (payload.len() as u64).to_le_bytes().to_vec());
+		results.append(&mut crc32fast::hash(payload).to_le_bytes().to_vec());
+		return results;
+	}
+}
+
+fn has_header(binary: &[u8]) -> bool {
+	return binary.starts_with(crate::constants::FILE_HEADER_MAGIC.as_bytes());
+}
+
+// Checks the header of a file against the settings of the database and the
+// checksum against the payload, and returns where the root node of the file
+// starts. The split parameters only matter to files that are about to be
+// changed, so a tree built with other ones can still be searched and rebuilt.
+pub fn check_header(
+	binary: &[u8],
+	path_in_tree: &NodePath,
+	config: &DatabaseConfig,
+	is_changing: bool,
+) -> Result<usize, TreeError> {
+	let header_length = check_header_fields(binary, path_in_tree, config, is_changing)?;
+	// Files without a header have no checksum
+	if header_length == 0 {
+		return Ok(header_length);
+	}
+
+	let payload = &binary[header_length..];
+	let payload_length = read_u64(&binary[32..40]);
+	if payload.len() as u64 != payload_length {
+		return Err(TreeError::corrupt(
+			path_in_tree,
+			format!(
+				"the header says it holds {} bytes, but {} were found",
+				payload_length,
+				payload.len()
+			),
+		));
+	}
+
+	let checksum = read_u32(&binary[40..44]);
+	if crc32fast::hash(payload) != checksum {
+		return Err(TreeError::corrupt(
+			path_in_tree,
+			String::from("the checksum does not match its contents"),
+		));
+	}
+
+	return Ok(header_length);
+}
+
+// The part of check_header that does not read the payload
+fn check_header_fields(
+	binary: &[u8],
+	path_in_tree: &NodePath,
+	config: &DatabaseConfig,
+	is_changing: bool,
+) -> Result<usize, TreeError> {
+	if has_header(binary) == false {
+		return Ok(0);
+	}
+
+	if binary.len() < 8 {
+		return Err(TreeError::corrupt(
+			path_in_tree,
+			String::from("the header is cut short"),
+		));
+	}
+
+	// Nothing after the version can be trusted to be laid out the same way
+	let version = read_u32(&binary[4..8]);
+	if version != crate::constants::FILE_FORMAT_VERSION {
+		return Err(TreeError::incompatible_format(
+			path_in_tree,
+			format!(
+				"it uses format version {}, but this build only reads version {}",
+				version,
+				crate::constants::FILE_FORMAT_VERSION
+			),
+		));
+	}
+	if binary.len() < HEADER_LENGTH {
+		return Err(TreeError::corrupt(
+			path_in_tree,
+			String::from("the header is cut short"),
+		));
+	}
+
+	let metric_id = read_u32(&binary[12..16]);
+	let found_metric = DistanceMetric::from_id(metric_id).ok_or_else(|| {
+		TreeError::incompatible_format(path_in_tree, format!("unknown metric {}", metric_id))
+	})?;
+	let found = FileHeader {
+		version: version,
+		descriptor_length: read_u32(&binary[8..12]),
+		metric: found_metric,
+		max_leaf_node_size: read_u64(&binary[16..24]),
+		max_file_node_depth: read_u64(&binary[24..32]),
+	};
+	let expected = FileHeader::current(config);
+
+	check_field(
+		path_in_tree,
+		"descriptor length",
+		found.descriptor_length,
+		expected.descriptor_length,
+	)?;
+	check_field(
+		path_in_tree,
+		"metric",
+		found.metric.to_name(),
+		expected.metric.to_name(),
+	)?;
+	if is_changing {
+		check_field(
+			path_in_tree,
+			"max_leaf_node_size",
+			found.max_leaf_node_size,
+			expected.max_leaf_node_size,
+		)?;
+		check_field(
+			path_in_tree,
+			"max_file_node_depth",
+			found.max_file_node_depth,
+			expected.max_file_node_depth,
+		)?;
+	}
+
+	return Ok(HEADER_LENGTH);
+
+	fn check_field<T: PartialEq + std::fmt::Display>(
+		path_in_tree: &NodePath,
+		name: &str,
+		found: T,
+		expected: T,
+	) -> Result<(), TreeError> {
+		if found != expected {
+			return Err(TreeError::incompatible_format(
+				path_in_tree,
+				format!(
+					"it was built with {} {}, but this database uses {}",
+					name, found, expected
+				),
+			));
+		}
+		return Ok(());
+	}
+}
+
+fn read_u32(binary: &[u8]) -> u32 {
+	return u32::from_le_bytes(binary.try_into().expect("Slice has bad length"));
+}
+
+fn read_u64(binary: &[u8]) -> u64 {
+	return u64::from_le_bytes(binary.try_into().expect("Slice has bad length"));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database_config::RequestedConfig;
+
+	fn config() -> DatabaseConfig {
+		return RequestedConfig::default().to_config_without_database_settings();
+	}
+
+	// An empty leaf
+	fn payload() -> Vec<u8> {
+		let mut payload = crate::constants::LEAF_NODE_SIGNATURE.as_bytes().to_vec();
+		payload.append(&mut 0u64.to_le_bytes().to_vec());
+		return payload;
+	}
+
+	fn file(config: &DatabaseConfig) -> Vec<u8> {
+		let mut file = FileHeader::current(config).to_binary(&payload());
+		file.append(&mut payload());
+		return file;
+	}
+
+	fn is_corrupt(result: Result<usize, TreeError>) -> bool {
+		return matches!(result, Err(TreeError::Corrupt { .. }));
+	}
+
+	fn is_incompatible(result: Result<usize, TreeError>) -> bool {
+		return matches!(result, Err(TreeError::IncompatibleFormat { .. }));
+	}
+
+	#[test]
+	fn header_round_trips() {
+		let config = config();
+		let file = file(&config);
+		let path = NodePath::new_empty();
+		assert_eq!(file.len(), HEADER_LENGTH + payload().len());
+		assert_eq!(
+			check_header(&file, &path, &config, true).unwrap(),
+			HEADER_LENGTH
+		);
+		assert_eq!(
+			check_header_fields(&file, &path, &config, true).unwrap(),
+			HEADER_LENGTH
+		);
+	}
+
+	#[test]
+	fn files_without_a_header_start_with_the_root() {
+		let config = config();
+		assert_eq!(
+			check_header(&payload(), &NodePath::new_empty(), &config, true).unwrap(),
+			0
+		);
+	}
+
+	#[test]
+	fn changed_payload_fails_the_checksum() {
+		let config = config();
+		let path = NodePath::new_empty();
+		let mut file = file(&config);
+		let last = file.len() - 1;
+		file[last] ^= 1;
+		assert!(is_corrupt(check_header(&file, &path, &config, false)));
+		// Only the full check reads the payload
+		assert!(check_header_fields(&file, &path, &config, false).is_ok());
+	}
+
+	#[test]
+	fn cut_short_files_are_corrupt() {
+		let config = config();
+		let path = NodePath::new_empty();
+		let file = file(&config);
+		assert!(is_corrupt(check_header(
+			&file[..(file.len() - 1)],
+			&path,
+			&config,
+			false
+		)));
+		assert!(is_corrupt(check_header(
+			&file[..(HEADER_LENGTH - 1)],
+			&path,
+			&config,
+			false
+		)));
+		assert!(is_corrupt(check_header(&file[..6], &path, &config, false)));
+	}
+
+	#[test]
+	fn other_metric_is_refused() {
+		let config = config();
+		let mut other = config.clone();
+		other.metric = match config.metric {
+			DistanceMetric::Hamming => DistanceMetric::Manhattan,
+			_ => DistanceMetric::Hamming,
+		};
+		let file = file(&other);
+		assert!(is_incompatible(check_header(
+			&file,
+			&NodePath::new_empty(),
+			&config,
+			false
+		)));
+	}
+
+	// A tree built with other split parameters can be searched, but not changed
+	#[test]
+	fn other_split_parameters_are_refused_only_when_changing() {
+		let config = config();
+		let mut other = config.clone();
+		other.max_leaf_node_size += 1;
+		let file = file(&other);
+		let path = NodePath::new_empty();
+		assert!(check_header(&file, &path, &config, false).is_ok());
+		assert!(is_incompatible(check_header(&file, &path, &config, true)));
+	}
+
+	#[test]
+	fn other_versions_are_refused() {
+		let config = config();
+		for version in [1, crate::constants::FILE_FORMAT_VERSION + 1] {
+			let mut file = file(&config);
+			file[4..8].copy_from_slice(&version.to_le_bytes());
+			assert!(is_incompatible(check_header(
+				&file,
+				&NodePath::new_empty(),
+				&config,
+				false
+			)));
+		}
+	}
+}
@@ -1,50 +1,114 @@
+use crate::database_config::DatabaseConfig;
+use crate::feature_tree::best_first::PendingFile;
+use crate::feature_tree::best_first::PendingFiles;
+use crate::feature_tree::compaction::Compaction;
+use crate::feature_tree::file_header;
+use crate::feature_tree::file_header::FileHeader;
 use crate::feature_tree::node::Node;
 use crate::feature_tree::node::TreeNode;
+use crate::feature_tree::node_cache;
 use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::search_result::LowerBound;
 use crate::feature_tree::search_result::SearchResultList;
+use crate::feature_tree::staging;
+use crate::feature_tree::tree_error::TreeError;
+use crate::feature_tree::verify::TreeVerifier;
 use crate::features::uuid_description_pair::UUIDDescriptionPair;
 
+use std::collections::HashSet;
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::io::Write;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct FileNode {
 	path_in_tree: NodePath,
 	file_contents: Option<Box<Node>>,
 	has_changed: bool,
+	// Set when the file is opened to be changed, so it can be saved later
+	config: Option<DatabaseConfig>,
 }
 
-fn get_node_from_file(file_path: String) -> Node {
-	let binary = read_file(file_path);
-	if binary.len() == 0 {
-		return Node::new_empty();
-	} else {
-		return Node::from_binary(&binary);
-	};
+fn get_node_from_file(path_in_tree: &NodePath, config: &DatabaseConfig) -> Result<Node, TreeError> {
+	return node_from_binary(read_file(path_in_tree, config)?, path_in_tree, config, true);
+}
+
+// The whole file is checked before anything is parsed, so a corrupt file is
+// reported here instead of part way through a search
+fn node_from_binary(
+	binary: Vec<u8>,
+	path_in_tree: &NodePath,
+	config: &DatabaseConfig,
+	is_changing: bool,
+) -> Result<Node, TreeError> {
+	let start = file_header::check_header(&binary, path_in_tree, config, is_changing)?;
+	if binary.len() == start {
+		return Ok(Node::new_empty());
+	}
+
+	return Node::from_binary(&binary[start..], path_in_tree);
 }
 
-fn read_file(file_path: String) -> Vec<u8> {
+// Files that do not exist yet are empty
+fn read_file(path_in_tree: &NodePath, config: &DatabaseConfig) -> Result<Vec<u8>, TreeError> {
+	let file_path = match staging::path_to_read(path_in_tree, config) {
+		Some(file_path) => file_path,
+		None => return Ok(vec![]),
+	};
 	let file = OpenOptions::new()
 		.read(true)
 		.write(true)
 		.create(true)
 		.open(file_path)
-		.expect("Opening a VP database file failed");
+		.map_err(|error| TreeError::read(path_in_tree, error))?;
 	let mut buf_reader = std::io::BufReader::new(file);
 	let mut contents = vec![];
-	let _size = buf_reader.read_to_end(&mut contents).unwrap();
-	return contents;
+	buf_reader
+		.read_to_end(&mut contents)
+		.map_err(|error| TreeError::read(path_in_tree, error))?;
+	return Ok(contents);
 }
 
-fn overwrite_node_to_file(file_path: String, data: Vec<u8>) {
-	OpenOptions::new()
-		.write(true)
-		.create(true)
-		.open(file_path)
-		.expect("Opening a VP database file failed")
-		.write_all(&data)
-		.expect("Writing a VP database file failed");
+// The node is written to a temporary file that is then renamed over the old
+// one, so a crash leaves either the old file or the new one and never a mix
+// of both. Files that are memory mapped keep their old contents.
+fn overwrite_node_to_file(
+	path_in_tree: &NodePath,
+	node: &Node,
+	config: &DatabaseConfig,
+) -> Result<(), TreeError> {
+	let payload = node.to_binary();
+	let mut data = FileHeader::current(config).to_binary(&payload);
+	data.extend(payload);
+
+	let file_path = staging::path_to_write(path_in_tree, config)?;
+	let temp_path = file_path.clone() + crate::constants::TEMP_FILE_EXTENSION;
+	let write_temp_file = || -> std::io::Result<()> {
+		let mut file = OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(true)
+			.open(&temp_path)?;
+		file.write_all(&data)?;
+		return file.sync_all();
+	};
+	write_temp_file()
+		.and_then(|_| std::fs::rename(&temp_path, &file_path))
+		.map_err(|error| TreeError::write(path_in_tree, error))?;
+	// Staged files are synced when they are committed
+	if staging::is_staged(config) {
+		return Ok(());
+	}
+	node_cache::invalidate(&file_path);
+	return sync_database_folder(path_in_tree, config);
+}
+
+// `path_in_tree` is the file that was renamed or removed
+fn sync_database_folder(path_in_tree: &NodePath, config: &DatabaseConfig) -> Result<(), TreeError> {
+	return config
+		.sync_database_folder()
+		.map_err(|error| TreeError::sync(path_in_tree, error));
 }
 
 impl FileNode {
@@ -53,35 +117,117 @@ impl FileNode {
 			path_in_tree: path_in_tree,
 			file_contents: None,
 			has_changed: false,
+			config: None,
 		});
 	}
 
-	fn open(&mut self) {
+	// `binary` has to have passed check_binary
+	pub fn from_checked_binary(binary: &[u8]) -> Node {
+		return FileNode::new_at_location(NodePath::from_binary(binary));
+	}
+
+	pub fn get_path_in_tree(&self) -> &NodePath {
+		return &self.path_in_tree;
+	}
+
+	// Only for files that were opened and have no unsaved changes
+	pub fn take_contents(&mut self) -> Node {
+		return *self
+			.file_contents
+			.take()
+			.expect("Tried to take the contents of a file that was not open");
+	}
+
+	pub fn close(&mut self) {
+		self.file_contents = None;
+	}
+
+	fn open(&mut self, config: &DatabaseConfig) -> Result<(), TreeError> {
+		self.config = Some(config.clone());
 		if self.file_contents.is_none() {
-			let node = get_node_from_file(self.path_in_tree.to_file_path_string());
+			let node = get_node_from_file(&self.path_in_tree, config)?;
 			self.file_contents = Some(Box::new(node));
 		}
+		return Ok(());
 	}
 
-	fn save(&mut self) {
+	// A file that fails to save is not tried again when it is dropped
+	fn save(&mut self) -> Result<(), TreeError> {
+		let config = self
+			.config
+			.as_ref()
+			.expect("Tried to save a file node that was never opened");
 		let node = self
 			.file_contents
 			.as_mut()
 			.expect("Tried to save an empty file node");
-		overwrite_node_to_file(self.path_in_tree.to_file_path_string(), node.to_binary());
-		self.file_contents = None;
 		self.has_changed = false;
+		overwrite_node_to_file(&self.path_in_tree, node, config)?;
+		self.file_contents = None;
+		return Ok(());
+	}
+
+	// Files below this one that are still open are saved when it is dropped
+	pub fn save_if_changed(&mut self) -> Result<(), TreeError> {
+		if self.has_changed {
+			return self.save();
+		}
+		return Ok(());
+	}
+
+	fn open_temporarily(&self, config: &DatabaseConfig) -> Result<Arc<Node>, TreeError> {
+		return FileNode::read_contents(&self.path_in_tree, config);
+	}
+
+	// Nodes read this way are shared through the cache and must not be changed.
+	// The file is only checked when it is first read. Files with staged
+	// changes are kept out of the cache until they are committed.
+	pub fn read_contents(
+		path_in_tree: &NodePath,
+		config: &DatabaseConfig,
+	) -> Result<Arc<Node>, TreeError> {
+		let load = || {
+			let binary = read_file(path_in_tree, config)?;
+			let length = binary.len();
+			let node = node_from_binary(binary, path_in_tree, config, false)?;
+			Ok((node, length))
+		};
+		let file_path = path_in_tree.to_file_path_string(config);
+		if staging::is_staged(config)
+			&& staging::path_to_read(path_in_tree, config).as_ref() != Some(&file_path)
+		{
+			return Ok(Arc::new(load()?.0));
+		}
+		return node_cache::get_or_load(&file_path, load);
 	}
 
-	fn open_temporarily(&self) -> Node {
-		return get_node_from_file(self.path_in_tree.to_file_path_string());
+	pub fn write_contents(
+		path_in_tree: &NodePath,
+		contents: &Node,
+		config: &DatabaseConfig,
+	) -> Result<(), TreeError> {
+		return overwrite_node_to_file(path_in_tree, contents, config);
+	}
+
+	pub fn delete_file(path_in_tree: &NodePath, config: &DatabaseConfig) -> Result<(), TreeError> {
+		if staging::is_staged(config) {
+			return staging::remove(path_in_tree, config);
+		}
+		let file_path = path_in_tree.to_file_path_string(config);
+		std::fs::remove_file(&file_path).map_err(|error| TreeError::remove(path_in_tree, error))?;
+		node_cache::invalidate(&file_path);
+		return sync_database_folder(path_in_tree, config);
 	}
 }
 
 impl Drop for FileNode {
+	// There is no way to return the error from here. The features that were
+	// not saved are found by --verify.
 	fn drop(&mut self) {
 		if self.has_changed {
-			self.save();
+			if let Err(error) = self.save() {
+				eprintln!("{}", error);
+			}
 		}
 	}
 }
@@ -91,42 +237,161 @@ impl TreeNode for FileNode {
 		return FileNode::new_at_location(NodePath::new_empty());
 	}
 
-	fn add(&mut self, to_add: UUIDDescriptionPair, mut current_path: NodePath) -> bool {
-		self.open();
+	fn add(
+		&mut self,
+		to_add: UUIDDescriptionPair,
+		mut current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<bool, TreeError> {
+		self.open(config)?;
 
 		current_path.add_direction(crate::constants::FILE_KEY);
 		let did_change = self
 			.file_contents
 			.as_mut()
 			.expect("Tried to add node to file that was not open")
-			.add(to_add, current_path);
+			.add(to_add, current_path, config)?;
 
 		if did_change == true {
 			self.has_changed = true;
-			if crate::constants::FILE_NODE_MEMORY_SAVER {
-				self.save();
+			if config.file_node_memory_saver {
+				self.save()?;
 			}
 		}
 
-		return false;
+		return Ok(false);
+	}
+
+	fn find(&self, results: &mut SearchResultList) -> Result<(), TreeError> {
+		if results.try_to_load_file() == false {
+			return Ok(());
+		}
+		return self.open_temporarily(results.get_config())?.find(results);
+	}
+
+	fn find_best_first(
+		&self,
+		_results: &mut SearchResultList,
+		bound: LowerBound,
+		pending: &mut PendingFiles,
+	) -> Result<(), TreeError> {
+		pending.push(PendingFile::new(bound, self.path_in_tree.clone()));
+		return Ok(());
+	}
+
+	fn find_batch(
+		&self,
+		batch: &mut [SearchResultList],
+		active: &[usize],
+	) -> Result<(), TreeError> {
+		let active = active
+			.iter()
+			.copied()
+			.filter(|&index| batch[index].try_to_load_file())
+			.collect::<Vec<usize>>();
+		if active.is_empty() == false {
+			self.open_temporarily(batch[active[0]].get_config())?
+				.find_batch(batch, &active)?;
+		}
+		return Ok(());
+	}
+
+	// Every file in the tree may need to be visited, so each one is closed
+	// again before moving on instead of keeping the whole tree in memory.
+	fn remove(
+		&mut self,
+		to_remove: &HashSet<u64>,
+		config: &DatabaseConfig,
+	) -> Result<u64, TreeError> {
+		self.open(config)?;
+
+		let removed = self
+			.file_contents
+			.as_mut()
+			.expect("Tried to remove nodes from file that was not open")
+			.remove(to_remove, config)?;
+
+		if removed > 0 || self.has_changed {
+			self.save()?;
+		} else {
+			self.file_contents = None;
+		}
+
+		return Ok(removed);
 	}
 
-	fn find(&self, results: &mut SearchResultList) {
-		return self.open_temporarily().find(results);
+	fn size(&self, config: &DatabaseConfig) -> Result<u64, TreeError> {
+		return self.open_temporarily(config)?.size(config);
+	}
+
+	fn rebalance(
+		&mut self,
+		mut current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<(u64, u64), TreeError> {
+		self.open(config)?;
+
+		current_path.add_direction(crate::constants::FILE_KEY);
+		let (size, rebuilt) = self
+			.file_contents
+			.as_mut()
+			.expect("Tried to rebalance file that was not open")
+			.rebalance(current_path, config)?;
+
+		if rebuilt > 0 || self.has_changed {
+			self.save()?;
+		} else {
+			self.file_contents = None;
+		}
+
+		return Ok((size, rebuilt));
+	}
+
+	fn collect_features(
+		&self,
+		into: &mut Vec<UUIDDescriptionPair>,
+		config: &DatabaseConfig,
+	) -> Result<(), TreeError> {
+		return self
+			.open_temporarily(config)?
+			.collect_features(into, config);
+	}
+
+	fn compact(
+		&mut self,
+		mut current_path: NodePath,
+		compaction: &mut Compaction,
+	) -> Result<u64, TreeError> {
+		self.open(compaction.get_config())?;
+
+		current_path.add_direction(crate::constants::FILE_KEY);
+		let contents = self
+			.file_contents
+			.as_mut()
+			.expect("Tried to compact file that was not open");
+		return compaction.compact_file(&self.path_in_tree, contents, current_path);
 	}
 
-	fn size(&self) -> u64 {
-		return self.open_temporarily().size();
+	fn verify(&self, mut current_path: NodePath, verifier: &mut TreeVerifier) {
+		if verifier.enter_file(&self.path_in_tree) == false {
+			return;
+		}
+
+		current_path.add_direction(crate::constants::FILE_KEY);
+		match self.open_temporarily(verifier.get_config()) {
+			Ok(node) => node.verify(current_path, verifier),
+			Err(error) => verifier.file_failed(error),
+		}
 	}
 
-	fn print(&self, depth: u32) {
+	fn print(&self, depth: u32, config: &DatabaseConfig) -> Result<(), TreeError> {
 		let padding = (0..depth).map(|_e| String::from(" ")).collect::<String>();
 		println!(
 			"{}Entering new file {}",
 			padding,
-			self.path_in_tree.to_file_path_string()
+			self.path_in_tree.to_file_path_string(config)
 		);
-		self.open_temporarily().print(depth + 1);
+		return self.open_temporarily(config)?.print(depth + 1, config);
 	}
 
 	fn to_binary(&self) -> Vec<u8> {
@@ -136,9 +401,77 @@ impl TreeNode for FileNode {
 		return results;
 	}
 
-	fn from_binary(binary: &[u8]) -> Node {
-		let _node_type = &binary[crate::constants::SIGNATURE_RANGE];
-		let path = NodePath::from_binary(binary);
-		return FileNode::new_at_location(path);
+	fn from_binary(binary: &[u8], path_in_tree: &NodePath) -> Result<Node, TreeError> {
+		FileNode::check_binary(binary)
+			.map_err(|reason| TreeError::corrupt(path_in_tree, reason))?;
+		return Ok(FileNode::from_checked_binary(binary));
+	}
+
+	fn check_binary(binary: &[u8]) -> Result<(), String> {
+		let path_length = crate::feature_tree::node::read_length(binary, 4)?;
+		if path_length.checked_add(12) != Some(binary.len() as u64) {
+			return Err(format!(
+				"a file node with a path of {} steps is {} bytes long",
+				path_length,
+				binary.len()
+			));
+		}
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::feature_tree::leaf_node::LeafNode;
+
+	fn leaf(seed: u64) -> Node {
+		return LeafNode::new(UUIDDescriptionPair::seeded_random(seed, 1, 3));
+	}
+
+	fn read_back(path_in_tree: &NodePath, config: &DatabaseConfig) -> Vec<u8> {
+		return get_node_from_file(path_in_tree, config)
+			.unwrap()
+			.to_binary();
+	}
+
+	#[test]
+	fn writes_leave_no_temporary_file_behind() {
+		let config = DatabaseConfig::new_test_database("file_node_write");
+		let path_in_tree = crate::feature_tree::bulk_builder::get_root_path();
+		let file_path = path_in_tree.to_file_path_string(&config);
+		let temp_path = file_path.clone() + crate::constants::TEMP_FILE_EXTENSION;
+
+		FileNode::write_contents(&path_in_tree, &leaf(61), &config).unwrap();
+		assert_eq!(read_back(&path_in_tree, &config), leaf(61).to_binary());
+		assert!(std::path::Path::new(&temp_path).exists() == false);
+
+		// A temporary file left by a crash is neither read nor kept
+		std::fs::write(&temp_path, b"cut short").unwrap();
+		assert_eq!(read_back(&path_in_tree, &config), leaf(61).to_binary());
+		FileNode::write_contents(&path_in_tree, &leaf(62), &config).unwrap();
+		assert_eq!(read_back(&path_in_tree, &config), leaf(62).to_binary());
+		assert!(std::path::Path::new(&temp_path).exists() == false);
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn failed_writes_keep_the_old_file() {
+		let config = DatabaseConfig::new_test_database("file_node_failed_write");
+		let path_in_tree = crate::feature_tree::bulk_builder::get_root_path();
+		let file_path = path_in_tree.to_file_path_string(&config);
+		FileNode::write_contents(&path_in_tree, &leaf(61), &config).unwrap();
+
+		// The temporary file can not be created where a folder is
+		std::fs::create_dir(file_path + crate::constants::TEMP_FILE_EXTENSION).unwrap();
+		assert!(matches!(
+			FileNode::write_contents(&path_in_tree, &leaf(62), &config),
+			Err(TreeError::Io {
+				action: "Writing VP tree file",
+				..
+			})
+		));
+		assert_eq!(read_back(&path_in_tree, &config), leaf(61).to_binary());
+		config.remove_test_database();
 	}
 }
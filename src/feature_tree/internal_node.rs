@@ -1,79 +1,235 @@
+use crate::database_config::DatabaseConfig;
+use crate::feature_tree::best_first::PendingFiles;
+use crate::feature_tree::compaction::Compaction;
 use crate::feature_tree::leaf_node::LeafNode;
 use crate::feature_tree::node::Node;
 use crate::feature_tree::node::TreeNode;
 use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::search_result::LowerBound;
 use crate::feature_tree::search_result::SearchResultList;
+use crate::feature_tree::tree_error::TreeError;
+use crate::feature_tree::verify::TreeVerifier;
+use crate::features::distance_metric::DistanceMetric;
+use crate::features::distance_metric::Metric;
 use crate::features::feature_description::FeatureDescription;
 use crate::features::uuid_description_pair::UUIDDescriptionPair;
 
+use std::collections::HashSet;
 use std::convert::TryInto;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::OnceLock;
 
 #[derive(Clone)]
 pub struct InternalNode {
 	vantage: FeatureDescription,
 	radius: u32,
-	near: Box<Node>,
-	far: Box<Node>,
+	near: LazyNode,
+	far: LazyNode,
+}
+
+// A child that is only parsed once something needs it. Searches only read
+// the children they descend into, so the bytes of a file are shared instead
+// of building the whole tree up front. A child is parsed at most once and
+// kept, so cached files do not decode the same nodes on every search.
+// Changing a child drops its bytes, from then on it is written out again.
+#[derive(Clone)]
+struct LazyNode {
+	parsed: OnceLock<Box<Node>>,
+	binary: Option<(Arc<Vec<u8>>, Range<usize>)>,
+}
+
+impl LazyNode {
+	fn new(node: Node) -> LazyNode {
+		return LazyNode {
+			parsed: OnceLock::from(Box::new(node)),
+			binary: None,
+		};
+	}
+
+	fn new_unparsed(binary: &Arc<Vec<u8>>, range: Range<usize>) -> LazyNode {
+		return LazyNode {
+			parsed: OnceLock::new(),
+			binary: Some((Arc::clone(binary), range)),
+		};
+	}
+
+	fn get(&self) -> &Node {
+		return self.parsed.get_or_init(|| {
+			let (binary, range) = self
+				.binary
+				.as_ref()
+				.expect("Child has neither a node nor its binary");
+			return Box::new(Node::from_shared_binary(binary, range.clone()));
+		});
+	}
+
+	fn get_mut(&mut self) -> &mut Node {
+		self.get();
+		self.binary = None;
+		return self.parsed.get_mut().expect("Child was just parsed");
+	}
+
+	fn to_binary(&self) -> Vec<u8> {
+		return match &self.binary {
+			Some((binary, range)) => binary[range.clone()].to_vec(),
+			None => self.get().to_binary(),
+		};
+	}
 }
 
 impl TreeNode for InternalNode {
+	// Without a config the metric new databases are built with is the best
+	// guess, see InternalNode::new_empty_for
 	fn new_empty() -> Node {
-		return Node::Internal(InternalNode {
-			vantage: FeatureDescription::random_edge(),
-			radius: crate::constants::AVERAGE_EDGE_FEATURE_DISTANCE,
-			near: Box::new(Node::new_empty()),
-			far: Box::new(Node::new_empty()),
-		});
+		return InternalNode::new_empty_for(crate::constants::DEFAULT_DISTANCE_METRIC);
 	}
 
-	fn add(&mut self, to_add: UUIDDescriptionPair, mut current_path: NodePath) -> bool {
-		if to_add.get_description().distance(&self.vantage) < self.radius {
+	fn add(
+		&mut self,
+		to_add: UUIDDescriptionPair,
+		mut current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<bool, TreeError> {
+		if config
+			.metric
+			.distance(to_add.get_description(), &self.vantage)
+			< self.radius
+		{
 			current_path.add_direction(crate::constants::NEAR_KEY);
-			return (*self.near).add(to_add, current_path);
+			return self.near.get_mut().add(to_add, current_path, config);
 		} else {
 			current_path.add_direction(crate::constants::FAR_KEY);
-			return (*self.far).add(to_add, current_path);
+			return self.far.get_mut().add(to_add, current_path, config);
 		}
 	}
 
-	fn find(&self, results: &mut SearchResultList) {
-		// radius belongs to far
-		let distance_to_vantage = results.distance_to_feature(&self.vantage);
-		let is_near = distance_to_vantage < self.radius;
+	fn find(&self, results: &mut SearchResultList) -> Result<(), TreeError> {
+		return find_in_sides(
+			results,
+			&self.vantage,
+			self.radius,
+			(&self.near, &self.far),
+			|side, results| side.get().find(results),
+		);
+	}
 
-		match is_near {
-			true => self.near.find(results),
-			false => self.far.find(results),
-		};
+	fn find_best_first(
+		&self,
+		results: &mut SearchResultList,
+		bound: LowerBound,
+		pending: &mut PendingFiles,
+	) -> Result<(), TreeError> {
+		return find_best_first_in_sides(
+			results,
+			bound,
+			&self.vantage,
+			self.radius,
+			(&self.near, &self.far),
+			|side, results, bound| side.get().find_best_first(results, bound, pending),
+		);
+	}
 
-		let is_lucky = match is_near {
-			true => results.get_worst_distance_to_target() + distance_to_vantage < self.radius,
-			false => self.radius + results.get_worst_distance_to_target() <= distance_to_vantage,
-		};
+	fn find_batch(
+		&self,
+		batch: &mut [SearchResultList],
+		active: &[usize],
+	) -> Result<(), TreeError> {
+		return find_batch_in_sides(
+			batch,
+			active,
+			&self.vantage,
+			self.radius,
+			(&self.near, &self.far),
+			|side, batch, active| side.get().find_batch(batch, active),
+		);
+	}
 
-		if is_lucky == false {
-			match is_near {
-				true => self.far.find(results),
-				false => self.near.find(results),
-			};
-		}
+	// The radius is left alone, even if one side becomes empty
+	fn remove(
+		&mut self,
+		to_remove: &HashSet<u64>,
+		config: &DatabaseConfig,
+	) -> Result<u64, TreeError> {
+		return Ok(self.near.get_mut().remove(to_remove, config)?
+			+ self.far.get_mut().remove(to_remove, config)?);
+	}
+
+	fn size(&self, config: &DatabaseConfig) -> Result<u64, TreeError> {
+		return Ok(self.near.get().size(config)? + self.far.get().size(config)?);
+	}
+
+	fn rebalance(
+		&mut self,
+		current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<(u64, u64), TreeError> {
+		let (near_size, far_size, rebuilt) = self.rebalance_children(current_path, config)?;
+		return Ok((near_size + far_size, rebuilt));
+	}
+
+	fn collect_features(
+		&self,
+		into: &mut Vec<UUIDDescriptionPair>,
+		config: &DatabaseConfig,
+	) -> Result<(), TreeError> {
+		self.near.get().collect_features(into, config)?;
+		return self.far.get().collect_features(into, config);
 	}
 
-	fn size(&self) -> u64 {
-		return (*self.near).size() + (*self.far).size();
+	fn compact(
+		&mut self,
+		current_path: NodePath,
+		compaction: &mut Compaction,
+	) -> Result<u64, TreeError> {
+		let mut near_path = current_path.clone();
+		near_path.add_direction(crate::constants::NEAR_KEY);
+		let near_size = crate::feature_tree::compaction::compact_child(
+			self.near.get_mut(),
+			near_path,
+			compaction,
+		)?;
+
+		let mut far_path = current_path;
+		far_path.add_direction(crate::constants::FAR_KEY);
+		let far_size = crate::feature_tree::compaction::compact_child(
+			self.far.get_mut(),
+			far_path,
+			compaction,
+		)?;
+
+		return Ok(InternalNode::binary_length(near_size, far_size));
 	}
 
-	fn print(&self, depth: u32) {
+	fn verify(&self, current_path: NodePath, verifier: &mut TreeVerifier) {
+		let mut near_path = current_path.clone();
+		near_path.add_direction(crate::constants::NEAR_KEY);
+		verifier.enter_side(&self.vantage, self.radius, true, &current_path);
+		self.near.get().verify(near_path, verifier);
+		verifier.leave_side();
+
+		let mut far_path = current_path.clone();
+		far_path.add_direction(crate::constants::FAR_KEY);
+		verifier.enter_side(&self.vantage, self.radius, false, &current_path);
+		self.far.get().verify(far_path, verifier);
+		verifier.leave_side();
+	}
+
+	fn print(&self, depth: u32, config: &DatabaseConfig) -> Result<(), TreeError> {
 		let padding = (0..depth).map(|_e| String::from(" ")).collect::<String>();
+		let near = self.near.get();
+		let far = self.far.get();
+		let near_size = near.size(config)?;
+		let far_size = far.size(config)?;
 		println!(
 			"{}{}, n={:12}, f={:12}",
 			padding,
-			self.size(),
-			self.near.size(),
-			self.far.size()
+			near_size + far_size,
+			near_size,
+			far_size
 		);
-		self.near.print(depth + 1);
-		self.far.print(depth + 1);
+		near.print(depth + 1, config)?;
+		return far.print(depth + 1, config);
 	}
 
 	fn to_binary(&self) -> Vec<u8> {
@@ -97,80 +253,452 @@ impl TreeNode for InternalNode {
 		return results;
 	}
 
-	fn from_binary(binary: &[u8]) -> Node {
-		let _node_type = &binary[0..4];
-		let radius = u32::from_le_bytes(binary[4..8].try_into().expect("Slice has bad length"));
-		let vantage = FeatureDescription::from_binary(&binary[8..40]);
+	fn from_binary(binary: &[u8], path_in_tree: &NodePath) -> Result<Node, TreeError> {
+		InternalNode::check_binary(binary)
+			.map_err(|reason| TreeError::corrupt(path_in_tree, reason))?;
+		return Ok(InternalNode::from_shared_binary(
+			&Arc::new(binary.to_vec()),
+			0..binary.len(),
+		));
+	}
+
+	fn check_binary(binary: &[u8]) -> Result<(), String> {
+		let near_start =
+			crate::constants::INTERNAL_NODE_HEADER_LENGTH + crate::constants::SIDE_LENGTH_LENGTH;
+		if binary.len() < near_start {
+			return Err(format!(
+				"an internal node is only {} bytes long",
+				binary.len()
+			));
+		}
+
+		let near_length = crate::feature_tree::node::read_length(
+			binary,
+			crate::constants::INTERNAL_NODE_HEADER_LENGTH,
+		)?;
+		let near_end = (near_start as u64)
+			.checked_add(near_length)
+			.filter(|&e| e <= binary.len() as u64)
+			.ok_or_else(|| String::from("the near side of an internal node is cut short"))?
+			as usize;
+
+		let far_length = crate::feature_tree::node::read_length(binary, near_end)?;
+		let far_start = near_end + crate::constants::SIDE_LENGTH_LENGTH;
+		if (far_start as u64).checked_add(far_length) != Some(binary.len() as u64) {
+			return Err(String::from(
+				"the far side of an internal node does not end with the node",
+			));
+		}
+
+		Node::check_binary(&binary[near_start..near_end])?;
+		return Node::check_binary(&binary[far_start..]);
+	}
+}
 
-		let near_length =
-			u64::from_le_bytes(binary[40..48].try_into().expect("Slice has bad length"));
-		let near_end = (48 + near_length) as usize;
-		let near_block = Node::from_binary(&binary[48..near_end]);
+impl InternalNode {
+	pub fn new(vantage: FeatureDescription, radius: u32, near: Node, far: Node) -> Node {
+		return Node::Internal(InternalNode {
+			vantage: vantage,
+			radius: radius,
+			near: LazyNode::new(near),
+			far: LazyNode::new(far),
+		});
+	}
+
+	// A random edge vantage with the radius a random feature is expected to
+	// be at from it under `metric`
+	pub fn new_empty_for(metric: DistanceMetric) -> Node {
+		return InternalNode::new(
+			FeatureDescription::random_edge(),
+			metric.default_radius(),
+			Node::new_empty(),
+			Node::new_empty(),
+		);
+	}
+
+	// Only the vantage and radius are read, the near and far blocks are kept
+	// as ranges of `binary` until they are needed
+	pub fn from_shared_binary(binary: &Arc<Vec<u8>>, range: Range<usize>) -> Node {
+		let start = range.start;
+		let _node_type = &binary[start..(start + 4)];
+		let radius = u32::from_le_bytes(
+			binary[(start + 4)..(start + 8)]
+				.try_into()
+				.expect("Slice has bad length"),
+		);
+		let header_end = start + crate::constants::INTERNAL_NODE_HEADER_LENGTH;
+		let vantage = FeatureDescription::from_binary(&binary[(start + 8)..header_end]);
+
+		let near_length = u64::from_le_bytes(
+			binary[header_end..(header_end + crate::constants::SIDE_LENGTH_LENGTH)]
+				.try_into()
+				.expect("Slice has bad length"),
+		);
+		let near_start = header_end + crate::constants::SIDE_LENGTH_LENGTH;
+		let near_range = near_start..(near_start + near_length as usize);
 
-		let far_length_range = near_end..(near_end + 8);
+		let far_length_range =
+			near_range.end..(near_range.end + crate::constants::SIDE_LENGTH_LENGTH);
 		let far_length = u64::from_le_bytes(
-			binary[far_length_range]
+			binary[far_length_range.clone()]
 				.try_into()
 				.expect("Slice has bad length"),
 		);
-		let far_range = (near_end + 8)..(near_end + 8 + far_length as usize);
-		let far_block = Node::from_binary(&binary[far_range]);
+		let far_range = far_length_range.end..(far_length_range.end + far_length as usize);
 
 		return Node::Internal(InternalNode {
 			radius: radius,
 			vantage: vantage,
-			near: Box::new(near_block),
-			far: Box::new(far_block),
+			near: LazyNode::new_unparsed(binary, near_range),
+			far: LazyNode::new_unparsed(binary, far_range),
 		});
 	}
+
+	// Length of to_binary for a node whose sides are this long
+	pub fn binary_length(near_length: u64, far_length: u64) -> u64 {
+		return (crate::constants::INTERNAL_NODE_HEADER_LENGTH
+			+ 2 * crate::constants::SIDE_LENGTH_LENGTH) as u64
+			+ near_length
+			+ far_length;
+	}
+
+	pub fn child_sizes(&self, config: &DatabaseConfig) -> Result<(u64, u64), TreeError> {
+		return Ok((self.near.get().size(config)?, self.far.get().size(config)?));
+	}
+
+	// Returns the sizes of the near and far sides after rebalancing them and
+	// how many subtrees were rebuilt
+	pub fn rebalance_children(
+		&mut self,
+		current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<(u64, u64, u64), TreeError> {
+		let mut near_path = current_path.clone();
+		near_path.add_direction(crate::constants::NEAR_KEY);
+		let (near_size, near_rebuilt) = self.near.get_mut().rebalance(near_path, config)?;
+
+		let mut far_path = current_path;
+		far_path.add_direction(crate::constants::FAR_KEY);
+		let (far_size, far_rebuilt) = self.far.get_mut().rebalance(far_path, config)?;
+
+		return Ok((near_size, far_size, near_rebuilt + far_rebuilt));
+	}
+
+	// Builds a balanced replacement for this node, including every file below it
+	pub fn rebuild(
+		&self,
+		current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<Node, TreeError> {
+		let mut pairs = vec![];
+		self.collect_features(&mut pairs, config)?;
+		return crate::feature_tree::bulk_builder::rebuild_subtree(pairs, current_path, config);
+	}
+
+	pub fn new_from_leaf(
+		node: &mut LeafNode,
+		split_point_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<Node, TreeError> {
+		return split_leaf_with_median_radius(node, split_point_path, config);
+	}
 }
 
-impl InternalNode {
-	pub fn new_from_leaf(node: &mut LeafNode, split_point_path: NodePath) -> Node {
-		return split_leaf_with_median_radius(node, split_point_path);
+// The order the sides of an internal node are searched in and when the second
+// one is skipped. Shared with node_view, which reaches the sides of a node
+// differently. `sides` are the near and far side, the radius belongs to far.
+pub fn find_in_sides<S>(
+	results: &mut SearchResultList,
+	vantage: &FeatureDescription,
+	radius: u32,
+	sides: (S, S),
+	mut find: impl FnMut(S, &mut SearchResultList) -> Result<(), TreeError>,
+) -> Result<(), TreeError> {
+	let distance_to_vantage = results.distance_to_feature(vantage);
+	let is_near = distance_to_vantage < radius;
+	let (near, far) = sides;
+	let (first, second) = match is_near {
+		true => (near, far),
+		false => (far, near),
+	};
+
+	find(first, results)?;
+	if results.should_search_other_side(distance_to_vantage, radius, is_near) {
+		find(second, results)?;
 	}
+	return Ok(());
+}
+
+// `bound` is a lower bound on the distance to every feature below the node
+pub fn find_best_first_in_sides<S>(
+	results: &mut SearchResultList,
+	bound: LowerBound,
+	vantage: &FeatureDescription,
+	radius: u32,
+	sides: (S, S),
+	mut find: impl FnMut(S, &mut SearchResultList, LowerBound) -> Result<(), TreeError>,
+) -> Result<(), TreeError> {
+	let distance_to_vantage = results.distance_to_feature(vantage);
+	let is_near = distance_to_vantage < radius;
+	let near_bound = bound.max(results.lower_bound_of_side(distance_to_vantage, radius, true));
+	let far_bound = bound.max(results.lower_bound_of_side(distance_to_vantage, radius, false));
+
+	let (near, far) = sides;
+	let (first, first_bound, second, second_bound) = match is_near {
+		true => (near, near_bound, far, far_bound),
+		false => (far, far_bound, near, near_bound),
+	};
+
+	find(first, results, first_bound)?;
+	if results.could_contain(second_bound) {
+		find(second, results, second_bound)?;
+	}
+	return Ok(());
+}
+
+// Whichever side most searches are on is visited first. Searches on the
+// other side have to decide about the first side before they have seen
+// their own, which prunes less but keeps it to one walk of the tree.
+pub fn find_batch_in_sides<S>(
+	batch: &mut [SearchResultList],
+	active: &[usize],
+	vantage: &FeatureDescription,
+	radius: u32,
+	sides: (S, S),
+	mut find: impl FnMut(S, &mut [SearchResultList], &[usize]) -> Result<(), TreeError>,
+) -> Result<(), TreeError> {
+	let distances = active
+		.iter()
+		.map(|&index| batch[index].distance_to_feature(vantage))
+		.collect::<Vec<u32>>();
+	let near_count = distances.iter().filter(|&&e| e < radius).count();
+	let near_first = near_count * 2 >= active.len();
+
+	let (near, far) = sides;
+	let (first, second) = match near_first {
+		true => (near, far),
+		false => (far, near),
+	};
+
+	for (side, is_first) in [(first, true), (second, false)] {
+		let mut side_active = vec![];
+		for (&index, &distance) in active.iter().zip(distances.iter()) {
+			let is_near = distance < radius;
+			if (is_near == near_first) == is_first
+				|| batch[index].should_search_other_side(distance, radius, is_near)
+			{
+				side_active.push(index);
+			}
+		}
+
+		if side_active.is_empty() == false {
+			find(side, batch, &side_active)?;
+		}
+	}
+	return Ok(());
 }
 
 #[allow(dead_code)]
-fn split_leaf_with_default_radius(node: &mut LeafNode, split_point_path: NodePath) -> Node {
-	let mut new_node = InternalNode::new_empty();
+fn split_leaf_with_default_radius(
+	node: &mut LeafNode,
+	split_point_path: NodePath,
+	config: &DatabaseConfig,
+) -> Result<Node, TreeError> {
+	let mut new_node = InternalNode::new_empty_for(config.metric);
 	for pair in node.get_owned_features() {
-		new_node.add(pair, split_point_path.clone());
+		new_node.add(pair, split_point_path.clone(), config)?;
 	}
-	return new_node;
+	return Ok(new_node);
 }
 
 // Does not produce a perfectly balanced tree, but because the radius is chosen
-// from a sample of crate::constants::MAX_LEAF_NODE_SIZE nodes, it is a more
+// from a sample of max_leaf_node_size nodes, it is a more
 // inteligent guess of what a good radius would be. Having a more balanced tree
 // should obviously be desired, but with the less-balanced version, there were
 // issues where file names became too long.
 // This method could be improved for speed, but runs fast enough for now.
-fn split_leaf_with_median_radius(node: &mut LeafNode, split_point_path: NodePath) -> Node {
-	let pairs = node.get_owned_features();
-	let vantage = FeatureDescription::random_edge();
-	let mut distances: Vec<u32> = pairs
-		.iter()
-		.map(|e| e.get_description().distance(&vantage))
-		.collect();
-	distances.sort();
-	let median = distances[pairs.len() / 2];
+fn split_leaf_with_median_radius(
+	node: &mut LeafNode,
+	split_point_path: NodePath,
+	config: &DatabaseConfig,
+) -> Result<Node, TreeError> {
+	let (vantage, median, near_pairs, far_pairs) =
+		split_with_median_radius(node.get_owned_features(), config);
 
 	let mut near = LeafNode::new_empty();
 	let mut far = LeafNode::new_empty();
-	for pair in pairs {
-		let distance = pair.get_description().distance(&vantage);
+	for pair in near_pairs {
+		near.add(pair, split_point_path.clone(), config)?;
+	}
+	for pair in far_pairs {
+		far.add(pair, split_point_path.clone(), config)?;
+	}
+
+	return Ok(InternalNode::new(vantage, median, near, far));
+}
+
+pub type SplitPairs = (
+	FeatureDescription,
+	u32,
+	Vec<UUIDDescriptionPair>,
+	Vec<UUIDDescriptionPair>,
+);
+
+// Picks a vantage point with the vantage strategy of the database and splits the pairs at their median distance to it,
+// returning the vantage, the radius and the near and far pairs.
+pub fn split_with_median_radius(
+	pairs: Vec<UUIDDescriptionPair>,
+	config: &DatabaseConfig,
+) -> SplitPairs {
+	let metric = config.metric;
+	let vantage =
+		crate::feature_tree::vantage::select_vantage(config.vantage_strategy, &pairs, metric);
+	let distances: Vec<u32> = pairs
+		.iter()
+		.map(|e| metric.distance(e.get_description(), &vantage))
+		.collect();
+	let mut sorted_distances = distances.clone();
+	sorted_distances.sort();
+	let median = sorted_distances[pairs.len() / 2];
+
+	let mut near = vec![];
+	let mut far = vec![];
+	for (pair, distance) in pairs.into_iter().zip(distances.into_iter()) {
 		if distance < median {
-			near.add(pair, split_point_path.clone());
+			near.push(pair);
 		} else {
-			far.add(pair, split_point_path.clone());
+			far.push(pair);
 		}
 	}
 
-	return Node::Internal(InternalNode {
-		vantage: vantage,
-		radius: median,
-		near: Box::new(near),
-		far: Box::new(far),
-	});
+	return (vantage, median, near, far);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::feature_tree::search_result::SearchOptions;
+	use crate::features_database;
+
+	fn small_tree() -> Node {
+		let mut pairs = UUIDDescriptionPair::seeded_random(52, 1, 6).into_iter();
+		return InternalNode::new(
+			FeatureDescription::random_edge(),
+			100,
+			LeafNode::new(pairs.by_ref().take(3).collect()),
+			LeafNode::new(pairs.collect()),
+		);
+	}
+
+	fn parsed_sides(node: &Node) -> (bool, bool) {
+		return match node {
+			Node::Internal(node) => (
+				node.near.parsed.get().is_some(),
+				node.far.parsed.get().is_some(),
+			),
+			_ => panic!("Not an internal node"),
+		};
+	}
+
+	#[test]
+	fn children_are_only_parsed_once_searched() {
+		let config = DatabaseConfig::new_test_database("internal_node_lazy");
+		let binary = small_tree().to_binary();
+		let node = Node::from_binary(&binary, &NodePath::new_empty()).unwrap();
+		assert_eq!(parsed_sides(&node), (false, false));
+		// Unchanged children are written out from the bytes they came from
+		assert_eq!(node.to_binary(), binary);
+		assert_eq!(parsed_sides(&node), (false, false));
+
+		let mut results = SearchResultList::new(
+			SearchOptions::new(10),
+			FeatureDescription::random_edge(),
+			&config,
+		);
+		node.find(&mut results).unwrap();
+		assert_eq!(results.get_results().len(), 6);
+		assert_eq!(parsed_sides(&node), (true, true));
+		assert_eq!(node.to_binary(), binary);
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn changed_children_are_written_out_again() {
+		let config = DatabaseConfig::new_test_database("internal_node_lazy_changed");
+		let binary = small_tree().to_binary();
+		let mut node = Node::from_binary(&binary, &NodePath::new_empty()).unwrap();
+		let added = UUIDDescriptionPair::seeded_random(53, 7, 1).remove(0);
+		node.add(added, NodePath::new_empty(), &config).unwrap();
+
+		let changed = node.to_binary();
+		assert_ne!(changed, binary);
+		let mut features = vec![];
+		Node::from_binary(&changed, &NodePath::new_empty())
+			.unwrap()
+			.collect_features(&mut features, &config)
+			.unwrap();
+		let mut uuids = features
+			.iter()
+			.map(|pair| pair.get_uuid())
+			.collect::<Vec<u64>>();
+		uuids.sort_unstable();
+		assert_eq!(uuids, (1..8).collect::<Vec<u64>>());
+		config.remove_test_database();
+	}
+
+	// Features that differ from `base` in one byte each, so they all end up
+	// on the same side of every node that `base` would
+	fn near(base: &FeatureDescription, first_uuid: u64, count: usize) -> Vec<UUIDDescriptionPair> {
+		let base = base.to_binary();
+		return (0..count)
+			.map(|i| {
+				let mut data = base.clone();
+				let byte = i % data.len();
+				data[byte] = data[byte].wrapping_add(1 + (i / data.len()) as u8);
+				UUIDDescriptionPair::new(
+					first_uuid + i as u64,
+					FeatureDescription::new_from_vec(data),
+				)
+			})
+			.collect();
+	}
+
+	#[test]
+	fn rebalancing_keeps_every_feature() {
+		let config = DatabaseConfig::new_test_database("internal_node_rebalance");
+		let spread = UUIDDescriptionPair::seeded_random(51, 1, 40);
+		features_database::bulk_insert_description_vec_into_database(spread.clone(), &config)
+			.unwrap();
+		let crowded = near(spread[0].get_description(), 1000, 200);
+		features_database::insert_description_vec_into_database(crowded.clone(), &config).unwrap();
+		let all = spread.iter().chain(crowded.iter()).collect::<Vec<_>>();
+		let uuids = all
+			.iter()
+			.map(|pair| pair.get_uuid())
+			.collect::<HashSet<u64>>();
+
+		let (features, rebuilt) = features_database::rebalance_database(&config).unwrap();
+		assert_eq!(features, 240);
+		assert!(rebuilt > 0);
+
+		let report = features_database::verify_database(&config).unwrap();
+		assert!(report.unreadable_files.is_empty());
+		assert!(report.missing_files.is_empty());
+		assert!(report.misplaced_features.is_empty());
+		assert!(report.duplicate_uuids.is_empty());
+		assert!(report.orphaned_files.is_empty());
+		assert_eq!(report.uuids, uuids);
+
+		for pair in all {
+			let results = features_database::find_feature_description_in_database(
+				pair.get_description().clone(),
+				crate::feature_tree::search_result::SearchOptions::new(1),
+				&config,
+			)
+			.unwrap()
+			.get_results();
+			assert_eq!(results[0].get_distance(), 0);
+		}
+		config.remove_test_database();
+	}
 }
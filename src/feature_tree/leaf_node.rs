@@ -1,8 +1,15 @@
+use crate::database_config::DatabaseConfig;
+use crate::feature_tree::best_first::PendingFiles;
+use crate::feature_tree::compaction::Compaction;
 use crate::feature_tree::node::Node;
 use crate::feature_tree::node::TreeNode;
 use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::search_result::LowerBound;
 use crate::feature_tree::search_result::SearchResultList;
+use crate::feature_tree::tree_error::TreeError;
+use crate::feature_tree::verify::TreeVerifier;
 use crate::features::uuid_description_pair::UUIDDescriptionPair;
+use std::collections::HashSet;
 use std::convert::TryInto;
 
 #[derive(Clone)]
@@ -11,11 +18,40 @@ pub struct LeafNode {
 }
 
 impl LeafNode {
+	pub fn new(features: Vec<UUIDDescriptionPair>) -> Node {
+		return Node::Leaf(LeafNode { features: features });
+	}
+
 	pub fn get_owned_features(&mut self) -> Vec<UUIDDescriptionPair> {
 		let mut stolen = vec![];
 		std::mem::swap(&mut stolen, &mut self.features);
 		return stolen;
 	}
+
+	// Length of to_binary for a leaf of `feature_count` features
+	pub fn binary_length(feature_count: u64) -> u64 {
+		return crate::constants::LEAF_NODE_HEADER_LENGTH as u64
+			+ crate::constants::UUID_DESCRIPTION_PAIR_LENGTH as u64 * feature_count;
+	}
+
+	// `binary` has to have passed check_binary
+	pub fn from_checked_binary(binary: &[u8]) -> Node {
+		let number_nodes = u64::from_le_bytes(
+			binary[4..crate::constants::LEAF_NODE_HEADER_LENGTH]
+				.try_into()
+				.expect("Slice has bad length"),
+		);
+
+		let mut pairs = vec![];
+		for i in 0..number_nodes as usize {
+			let start = crate::constants::LEAF_NODE_HEADER_LENGTH
+				+ i * crate::constants::UUID_DESCRIPTION_PAIR_LENGTH;
+			let end = start + crate::constants::UUID_DESCRIPTION_PAIR_LENGTH;
+			pairs.push(UUIDDescriptionPair::from_binary(&binary[start..end]));
+		}
+
+		return Node::Leaf(LeafNode { features: pairs });
+	}
 }
 
 impl TreeNode for LeafNode {
@@ -23,51 +59,134 @@ impl TreeNode for LeafNode {
 		return Node::Leaf(LeafNode { features: vec![] });
 	}
 
-	fn add(&mut self, to_add: UUIDDescriptionPair, _current_path: NodePath) -> bool {
+	fn add(
+		&mut self,
+		to_add: UUIDDescriptionPair,
+		_current_path: NodePath,
+		_config: &DatabaseConfig,
+	) -> Result<bool, TreeError> {
 		// TODO maybe a check to ensure that nodes are not added as duplicates?
 		self.features.push(to_add);
-		return true;
+		return Ok(true);
 	}
 
-	fn find(&self, results: &mut SearchResultList) {
+	fn find(&self, results: &mut SearchResultList) -> Result<(), TreeError> {
+		if results.try_to_visit_leaf() == false {
+			return Ok(());
+		}
+
 		for pair in &self.features {
+			if results.has_comparisons_left() == false {
+				return Ok(());
+			}
 			results.try_to_add(pair);
 		}
+		return Ok(());
 	}
 
-	fn size(&self) -> u64 {
-		return self.features.len() as u64;
+	fn find_best_first(
+		&self,
+		results: &mut SearchResultList,
+		_bound: LowerBound,
+		_pending: &mut PendingFiles,
+	) -> Result<(), TreeError> {
+		return self.find(results);
 	}
 
-	fn print(&self, _depth: u32) {
+	fn find_batch(
+		&self,
+		batch: &mut [SearchResultList],
+		active: &[usize],
+	) -> Result<(), TreeError> {
+		for &index in active {
+			self.find(&mut batch[index])?;
+		}
+		return Ok(());
+	}
+
+	fn remove(
+		&mut self,
+		to_remove: &HashSet<u64>,
+		_config: &DatabaseConfig,
+	) -> Result<u64, TreeError> {
+		let old_size = self.features.len();
+		self.features
+			.retain(|pair| to_remove.contains(&pair.get_uuid()) == false);
+		return Ok((old_size - self.features.len()) as u64);
+	}
+
+	fn size(&self, _config: &DatabaseConfig) -> Result<u64, TreeError> {
+		return Ok(self.features.len() as u64);
+	}
+
+	fn rebalance(
+		&mut self,
+		_current_path: NodePath,
+		_config: &DatabaseConfig,
+	) -> Result<(u64, u64), TreeError> {
+		return Ok((self.features.len() as u64, 0));
+	}
+
+	fn collect_features(
+		&self,
+		into: &mut Vec<UUIDDescriptionPair>,
+		_config: &DatabaseConfig,
+	) -> Result<(), TreeError> {
+		into.extend(self.features.iter().cloned());
+		return Ok(());
+	}
+
+	// Same as the length of to_binary
+	fn compact(
+		&mut self,
+		_current_path: NodePath,
+		_compaction: &mut Compaction,
+	) -> Result<u64, TreeError> {
+		return Ok(LeafNode::binary_length(self.features.len() as u64));
+	}
+
+	fn verify(&self, current_path: NodePath, verifier: &mut TreeVerifier) {
+		for pair in self.features.iter() {
+			verifier.check_feature(pair, &current_path);
+		}
+	}
+
+	fn print(&self, _depth: u32, _config: &DatabaseConfig) -> Result<(), TreeError> {
 		for _pair in &self.features {
 			// TODO toggle for showing the values inside leaf nodes
 			// println!("{:?}", pair);
 		}
+		return Ok(());
 	}
 
 	fn to_binary(&self) -> Vec<u8> {
 		let mut results = vec![];
 		results.append(&mut crate::constants::LEAF_NODE_SIGNATURE.as_bytes().to_vec());
-		results.append(&mut self.size().to_le_bytes().to_vec());
+		results.append(&mut (self.features.len() as u64).to_le_bytes().to_vec());
 		for pair in &self.features {
 			results.append(&mut pair.to_binary());
 		}
 		return results;
 	}
 
-	fn from_binary(binary: &[u8]) -> Node {
-		let _node_type = &binary[crate::constants::SIGNATURE_RANGE];
-		let number_nodes =
-			u64::from_le_bytes(binary[4..12].try_into().expect("Slice has bad length"));
+	fn from_binary(binary: &[u8], path_in_tree: &NodePath) -> Result<Node, TreeError> {
+		LeafNode::check_binary(binary)
+			.map_err(|reason| TreeError::corrupt(path_in_tree, reason))?;
+		return Ok(LeafNode::from_checked_binary(binary));
+	}
 
-		let mut pairs = vec![];
-		for i in 0..number_nodes {
-			let start = (12 + i * 40) as usize;
-			let end = (start + 40) as usize;
-			pairs.push(UUIDDescriptionPair::from_binary(&binary[start..end]));
+	fn check_binary(binary: &[u8]) -> Result<(), String> {
+		let number_nodes = crate::feature_tree::node::read_length(binary, 4)?;
+		let expected_length = number_nodes
+			.checked_mul(crate::constants::UUID_DESCRIPTION_PAIR_LENGTH as u64)
+			.and_then(|e| e.checked_add(crate::constants::LEAF_NODE_HEADER_LENGTH as u64));
+		if expected_length != Some(binary.len() as u64) {
+			return Err(format!(
+				"a leaf node of {} features is {} bytes long",
+				number_nodes,
+				binary.len()
+			));
 		}
-
-		return Node::Leaf(LeafNode { features: pairs });
+		return Ok(());
 	}
 }
@@ -1,11 +1,23 @@
+use crate::database_config::DatabaseConfig;
+use crate::error::Error;
+use crate::feature_tree::best_first::PendingFiles;
+use crate::feature_tree::compaction::Compaction;
 use crate::feature_tree::file_node::FileNode;
 use crate::feature_tree::internal_node::InternalNode;
 use crate::feature_tree::leaf_node::LeafNode;
 
 use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::search_result::LowerBound;
 use crate::feature_tree::search_result::SearchResultList;
+use crate::feature_tree::tree_error::TreeError;
+use crate::feature_tree::verify::TreeVerifier;
 use crate::features::uuid_description_pair::UUIDDescriptionPair;
 
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::ops::Range;
+use std::sync::Arc;
+
 #[derive(Clone)]
 pub enum Node {
 	Internal(InternalNode),
@@ -20,28 +32,96 @@ impl Node {
 		return FileNode::new_at_location(root_path);
 	}
 
-	pub fn get_file_as_root(file_path: String) -> Node {
-		return FileNode::new_at_location(NodePath::from_file_path_string(file_path));
+	pub fn get_file_as_root(file_path: String) -> Result<Node, Error> {
+		return Ok(FileNode::new_at_location(NodePath::from_file_path_string(
+			file_path,
+		)?));
+	}
+
+	// Like from_binary, but internal nodes keep pointing into `binary`
+	// instead of parsing their children. `range` has to have passed
+	// check_binary, so anything that is not an internal or a file node is a
+	// leaf.
+	pub fn from_shared_binary(binary: &Arc<Vec<u8>>, range: Range<usize>) -> Node {
+		let node_type = &binary[range.start..(range.start + 4)];
+		if node_type == crate::constants::INTERNAL_NODE_SIGNATURE.as_bytes() {
+			return InternalNode::from_shared_binary(binary, range);
+		} else if node_type == crate::constants::FILE_NODE_SIGNATURE.as_bytes() {
+			return FileNode::from_checked_binary(&binary[range]);
+		} else {
+			return LeafNode::from_checked_binary(&binary[range]);
+		}
 	}
 }
 
 // TODO am I using traits correctly?
-// TODO optimize from_binary
 // I find it unlikely that `find` can be optimized very well, but a substantial
-// amount of time is spent building the nodes themselves. Not all of the node
-// needs to be loaded into memory at the same time so if it can be made so that
-// parts of nodes are only parsed when needed, it would speed up the time to
-// find a feature.
+// amount of time is spent building the nodes themselves. Internal nodes only
+// parse their children when something needs them, see Node::from_shared_binary.
+//
+// Anything that may read a file can fail with a TreeError. from_binary checks
+// its binary with check_binary before parsing it, and files are checked in
+// full when they are read before their nodes are parsed lazily.
 pub trait TreeNode {
 	fn new_empty() -> Node;
-	fn add(&mut self, to_add: UUIDDescriptionPair, current_path: NodePath) -> bool;
-	fn find(&self, results: &mut SearchResultList);
-	fn size(&self) -> u64;
+	fn add(
+		&mut self,
+		to_add: UUIDDescriptionPair,
+		current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<bool, TreeError>;
+	fn find(&self, results: &mut SearchResultList) -> Result<(), TreeError>;
+	// Like find, but files are added to `pending` instead of being opened.
+	// `bound` is a lower bound on the distance to every feature in this node.
+	fn find_best_first(
+		&self,
+		results: &mut SearchResultList,
+		bound: LowerBound,
+		pending: &mut PendingFiles,
+	) -> Result<(), TreeError>;
+	// Searches for many targets in one walk of the tree, so that every file
+	// is read at most once. `active` are the indexes of the searches that
+	// still need to look at this node.
+	fn find_batch(&self, batch: &mut [SearchResultList], active: &[usize])
+		-> Result<(), TreeError>;
+	// Returns how many features were removed
+	fn remove(
+		&mut self,
+		to_remove: &HashSet<u64>,
+		config: &DatabaseConfig,
+	) -> Result<u64, TreeError>;
+	fn size(&self, config: &DatabaseConfig) -> Result<u64, TreeError>;
+	// Rebuilds subtrees whose near and far sides have grown too different in
+	// size. Returns the size of the node and how many subtrees were rebuilt.
+	fn rebalance(
+		&mut self,
+		current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<(u64, u64), TreeError>;
+	fn collect_features(
+		&self,
+		into: &mut Vec<UUIDDescriptionPair>,
+		config: &DatabaseConfig,
+	) -> Result<(), TreeError>;
+	// Moves everything below this node into files of about
+	// COMPACTION_TARGET_FILE_SIZE bytes. Returns the size of the node in bytes.
+	fn compact(
+		&mut self,
+		current_path: NodePath,
+		compaction: &mut Compaction,
+	) -> Result<u64, TreeError>;
+	// Records every problem found below this node in `verifier` instead of
+	// stopping at the first one
+	fn verify(&self, current_path: NodePath, verifier: &mut TreeVerifier);
 
-	fn print(&self, depth: u32);
+	fn print(&self, depth: u32, config: &DatabaseConfig) -> Result<(), TreeError>;
 
 	fn to_binary(&self) -> Vec<u8>;
-	fn from_binary(binary: &[u8]) -> Node;
+	// `path_in_tree` is the file `binary` was read from, to name it in errors
+	fn from_binary(binary: &[u8], path_in_tree: &NodePath) -> Result<Node, TreeError>;
+	// Makes sure that from_binary will not read past the end of `binary`,
+	// returning what is wrong with it otherwise
+	fn check_binary(binary: &[u8]) -> Result<(), String>;
 }
 
 // TODO find a better way call these generic functions
@@ -50,34 +130,44 @@ impl TreeNode for Node {
 		return LeafNode::new_empty();
 	}
 
-	fn add(&mut self, to_add: UUIDDescriptionPair, current_path: NodePath) -> bool {
+	fn add(
+		&mut self,
+		to_add: UUIDDescriptionPair,
+		current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<bool, TreeError> {
 		return match self {
 			Node::Leaf(node) => {
-				if current_path.should_split_to_new_file() {
+				if current_path.should_split_to_new_file(config) {
+					// The features already in the leaf move into the new file
+					let mut to_move = node.get_owned_features();
+					to_move.push(to_add);
 					let new_node = FileNode::new_at_location(current_path.clone());
 					let _old_node = std::mem::replace(self, new_node);
-					self.add(to_add, current_path);
-					true
-				} else if should_split_to_internal_node(node.size()) {
-					let new_node = InternalNode::new_from_leaf(node, current_path.clone());
+					for pair in to_move {
+						self.add(pair, current_path.clone(), config)?;
+					}
+					Ok(true)
+				} else if should_split_to_internal_node(node.size(config)?, config) {
+					let new_node = InternalNode::new_from_leaf(node, current_path.clone(), config)?;
 					let _old_node = std::mem::replace(self, new_node);
-					self.add(to_add, current_path);
-					true
+					self.add(to_add, current_path, config)?;
+					Ok(true)
 				} else {
 					// Normal leaf node
-					node.add(to_add, current_path)
+					node.add(to_add, current_path, config)
 				}
 			}
-			Node::Internal(node) => node.add(to_add, current_path),
-			Node::File(node) => node.add(to_add, current_path),
+			Node::Internal(node) => node.add(to_add, current_path, config),
+			Node::File(node) => node.add(to_add, current_path, config),
 		};
 
-		fn should_split_to_internal_node(current_size: u64) -> bool {
-			return current_size + 1 > crate::constants::MAX_LEAF_NODE_SIZE;
+		fn should_split_to_internal_node(current_size: u64, config: &DatabaseConfig) -> bool {
+			return current_size + 1 > config.max_leaf_node_size;
 		}
 	}
 
-	fn find(&self, results: &mut SearchResultList) {
+	fn find(&self, results: &mut SearchResultList) -> Result<(), TreeError> {
 		match self {
 			Node::Internal(node) => node.find(results),
 			Node::Leaf(node) => node.find(results),
@@ -85,11 +175,113 @@ impl TreeNode for Node {
 		}
 	}
 
-	fn size(&self) -> u64 {
+	fn find_best_first(
+		&self,
+		results: &mut SearchResultList,
+		bound: LowerBound,
+		pending: &mut PendingFiles,
+	) -> Result<(), TreeError> {
+		match self {
+			Node::Internal(node) => node.find_best_first(results, bound, pending),
+			Node::Leaf(node) => node.find_best_first(results, bound, pending),
+			Node::File(node) => node.find_best_first(results, bound, pending),
+		}
+	}
+
+	fn find_batch(
+		&self,
+		batch: &mut [SearchResultList],
+		active: &[usize],
+	) -> Result<(), TreeError> {
+		match self {
+			Node::Internal(node) => node.find_batch(batch, active),
+			Node::Leaf(node) => node.find_batch(batch, active),
+			Node::File(node) => node.find_batch(batch, active),
+		}
+	}
+
+	fn remove(
+		&mut self,
+		to_remove: &HashSet<u64>,
+		config: &DatabaseConfig,
+	) -> Result<u64, TreeError> {
+		match self {
+			Node::Internal(node) => node.remove(to_remove, config),
+			Node::Leaf(node) => node.remove(to_remove, config),
+			Node::File(node) => node.remove(to_remove, config),
+		}
+	}
+
+	fn size(&self, config: &DatabaseConfig) -> Result<u64, TreeError> {
+		match self {
+			Node::Internal(node) => node.size(config),
+			Node::Leaf(node) => node.size(config),
+			Node::File(node) => node.size(config),
+		}
+	}
+
+	fn rebalance(
+		&mut self,
+		current_path: NodePath,
+		config: &DatabaseConfig,
+	) -> Result<(u64, u64), TreeError> {
+		return match self {
+			// Decided from the top down, so nothing below a subtree that is
+			// rebuilt is rebuilt on its own first. Rebalancing does not change
+			// the size of a side, so the sizes are the same either way.
+			Node::Internal(node) => {
+				let (near_size, far_size) = node.child_sizes(config)?;
+				if should_rebuild(near_size, far_size, config) {
+					let new_node = node.rebuild(current_path, config)?;
+					let _old_node = std::mem::replace(self, new_node);
+					Ok((near_size + far_size, 1))
+				} else {
+					let (_, _, rebuilt) = node.rebalance_children(current_path, config)?;
+					Ok((near_size + far_size, rebuilt))
+				}
+			}
+			Node::Leaf(node) => node.rebalance(current_path, config),
+			Node::File(node) => node.rebalance(current_path, config),
+		};
+
+		fn should_rebuild(near_size: u64, far_size: u64, config: &DatabaseConfig) -> bool {
+			let smaller = near_size.min(far_size) as f64;
+			let larger = near_size.max(far_size) as f64;
+			let min_size = config.max_leaf_node_size * crate::constants::REBALANCE_MIN_LEAVES;
+			return near_size + far_size >= min_size
+				&& larger > smaller * crate::constants::REBALANCE_RATIO;
+		}
+	}
+
+	fn collect_features(
+		&self,
+		into: &mut Vec<UUIDDescriptionPair>,
+		config: &DatabaseConfig,
+	) -> Result<(), TreeError> {
+		match self {
+			Node::Internal(node) => node.collect_features(into, config),
+			Node::Leaf(node) => node.collect_features(into, config),
+			Node::File(node) => node.collect_features(into, config),
+		}
+	}
+
+	fn compact(
+		&mut self,
+		current_path: NodePath,
+		compaction: &mut Compaction,
+	) -> Result<u64, TreeError> {
+		match self {
+			Node::Internal(node) => node.compact(current_path, compaction),
+			Node::Leaf(node) => node.compact(current_path, compaction),
+			Node::File(node) => node.compact(current_path, compaction),
+		}
+	}
+
+	fn verify(&self, current_path: NodePath, verifier: &mut TreeVerifier) {
 		match self {
-			Node::Internal(node) => node.size(),
-			Node::Leaf(node) => node.size(),
-			Node::File(node) => node.size(),
+			Node::Internal(node) => node.verify(current_path, verifier),
+			Node::Leaf(node) => node.verify(current_path, verifier),
+			Node::File(node) => node.verify(current_path, verifier),
 		}
 	}
 
@@ -101,29 +293,102 @@ impl TreeNode for Node {
 		}
 	}
 
-	fn print(&self, depth: u32) {
+	fn print(&self, depth: u32, config: &DatabaseConfig) -> Result<(), TreeError> {
 		match self {
-			Node::Internal(node) => node.print(depth),
-			Node::Leaf(node) => node.print(depth),
-			Node::File(node) => node.print(depth),
+			Node::Internal(node) => node.print(depth, config),
+			Node::Leaf(node) => node.print(depth, config),
+			Node::File(node) => node.print(depth, config),
 		}
 	}
 
-	// TODO this looks ugly
-	fn from_binary(binary: &[u8]) -> Node {
-		let node_type = &binary[0..4];
-		if binary_equals_data(node_type, crate::constants::LEAF_NODE_SIGNATURE) {
-			return LeafNode::from_binary(binary);
-		} else if binary_equals_data(node_type, crate::constants::INTERNAL_NODE_SIGNATURE) {
-			return InternalNode::from_binary(binary);
-		} else if binary_equals_data(node_type, crate::constants::FILE_NODE_SIGNATURE) {
-			return FileNode::from_binary(binary);
+	fn from_binary(binary: &[u8], path_in_tree: &NodePath) -> Result<Node, TreeError> {
+		Node::check_binary(binary).map_err(|reason| TreeError::corrupt(path_in_tree, reason))?;
+		return Ok(Node::from_shared_binary(
+			&Arc::new(binary.to_vec()),
+			0..binary.len(),
+		));
+	}
+
+	fn check_binary(binary: &[u8]) -> Result<(), String> {
+		let node_type = binary
+			.get(crate::constants::SIGNATURE_RANGE)
+			.ok_or_else(|| String::from("a node is cut short before its signature"))?;
+		if node_type == crate::constants::LEAF_NODE_SIGNATURE.as_bytes() {
+			return LeafNode::check_binary(binary);
+		} else if node_type == crate::constants::INTERNAL_NODE_SIGNATURE.as_bytes() {
+			return InternalNode::check_binary(binary);
+		} else if node_type == crate::constants::FILE_NODE_SIGNATURE.as_bytes() {
+			return FileNode::check_binary(binary);
 		} else {
-			panic!("Encountered unknown node of type {:?}", node_type);
+			return Err(format!("found a node of unknown type {:?}", node_type));
 		}
+	}
+}
+
+// Reads the length stored at `start` for check_binary
+pub fn read_length(binary: &[u8], start: usize) -> Result<u64, String> {
+	let length = binary
+		.get(start..(start + 8))
+		.ok_or_else(|| String::from("a node is cut short before one of its lengths"))?;
+	return Ok(u64::from_le_bytes(
+		length.try_into().expect("Slice has bad length"),
+	));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::features::feature_description::FeatureDescription;
+
+	fn tree() -> Node {
+		let mut pairs = FeatureDescription::seeded_random(3)
+			.take(6)
+			.enumerate()
+			.map(|(i, description)| UUIDDescriptionPair::new(i as u64, description));
+		let mut near_path = NodePath::new_empty();
+		near_path.add_direction(crate::constants::NEAR_KEY);
+		return InternalNode::new(
+			FeatureDescription::random_edge(),
+			100,
+			LeafNode::new(pairs.by_ref().take(3).collect()),
+			InternalNode::new(
+				FeatureDescription::random_edge(),
+				50,
+				LeafNode::new(pairs.collect()),
+				FileNode::new_at_location(near_path),
+			),
+		);
+	}
+
+	#[test]
+	fn nodes_round_trip() {
+		let binary = tree().to_binary();
+		let parsed = Node::from_binary(&binary, &NodePath::new_empty()).unwrap();
+		assert_eq!(parsed.to_binary(), binary);
+	}
+
+	#[test]
+	fn unknown_nodes_are_corrupt() {
+		let mut binary = tree().to_binary();
+		binary[0..4].copy_from_slice(b"what");
+		assert!(matches!(
+			Node::from_binary(&binary, &NodePath::new_empty()),
+			Err(TreeError::Corrupt { .. })
+		));
+		assert!(matches!(
+			Node::from_binary(b"le", &NodePath::new_empty()),
+			Err(TreeError::Corrupt { .. })
+		));
+	}
 
-		fn binary_equals_data(binary: &[u8], data: &str) -> bool {
-			return binary.iter().zip(data.as_bytes()).all(|(a, b)| a == b);
+	#[test]
+	fn cut_short_nodes_are_corrupt() {
+		let binary = tree().to_binary();
+		for length in [binary.len() - 1, binary.len() / 2, 5] {
+			assert!(matches!(
+				Node::from_binary(&binary[..length], &NodePath::new_empty()),
+				Err(TreeError::Corrupt { .. })
+			));
 		}
 	}
 }
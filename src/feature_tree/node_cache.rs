@@ -0,0 +1,188 @@
+use crate::feature_tree::node::Node;
+use crate::feature_tree::tree_error::TreeError;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// Parsed contents of recently read files, shared by every search in the
+// process and keyed by the path of the file. Entries are dropped least
+// recently used first once the files they were parsed from add up to more
+// than NODE_CACHE_MAX_BYTES. Writes made by this process remove the entry of
+// the file they replace, but writes made by another process are not noticed.
+struct NodeCache {
+	entries: HashMap<String, CacheEntry>,
+	by_last_use: BTreeMap<u64, String>,
+	clock: u64,
+	bytes: usize,
+}
+
+struct CacheEntry {
+	node: Arc<Node>,
+	last_use: u64,
+	bytes: usize,
+}
+
+static CACHE: Mutex<Option<NodeCache>> = Mutex::new(None);
+
+impl NodeCache {
+	fn new() -> NodeCache {
+		return NodeCache {
+			entries: HashMap::new(),
+			by_last_use: BTreeMap::new(),
+			clock: 0,
+			bytes: 0,
+		};
+	}
+
+	fn get(&mut self, path: &str) -> Option<Arc<Node>> {
+		self.clock += 1;
+		let clock = self.clock;
+		let entry = self.entries.get_mut(path)?;
+		self.by_last_use.remove(&entry.last_use);
+		self.by_last_use.insert(clock, String::from(path));
+		entry.last_use = clock;
+		return Some(entry.node.clone());
+	}
+
+	fn insert(&mut self, path: String, node: Arc<Node>, bytes: usize) {
+		self.remove(&path);
+		if bytes > crate::constants::NODE_CACHE_MAX_BYTES {
+			return;
+		}
+
+		while self.bytes + bytes > crate::constants::NODE_CACHE_MAX_BYTES {
+			let oldest = match self.by_last_use.values().next() {
+				Some(oldest) => oldest.clone(),
+				None => break,
+			};
+			self.remove(&oldest);
+		}
+
+		self.clock += 1;
+		self.by_last_use.insert(self.clock, path.clone());
+		self.bytes += bytes;
+		self.entries.insert(
+			path,
+			CacheEntry {
+				node: node,
+				last_use: self.clock,
+				bytes: bytes,
+			},
+		);
+	}
+
+	fn remove(&mut self, path: &str) {
+		if let Some(entry) = self.entries.remove(path) {
+			self.by_last_use.remove(&entry.last_use);
+			self.bytes -= entry.bytes;
+		}
+	}
+}
+
+// `load` returns the parsed node and the size of the file it came from. It is
+// called without holding the lock so that other searches are not blocked.
+// Files that fail to load are not cached.
+pub fn get_or_load<F>(path: &str, load: F) -> Result<Arc<Node>, TreeError>
+where
+	F: FnOnce() -> Result<(Node, usize), TreeError>,
+{
+	if let Some(node) = with_cache(|cache| cache.get(path)) {
+		return Ok(node);
+	}
+
+	let (node, bytes) = load()?;
+	let node = Arc::new(node);
+	with_cache(|cache| cache.insert(String::from(path), node.clone(), bytes));
+	return Ok(node);
+}
+
+pub fn invalidate(path: &str) {
+	with_cache(|cache| cache.remove(path));
+}
+
+fn with_cache<F, T>(action: F) -> T
+where
+	F: FnOnce(&mut NodeCache) -> T,
+{
+	// Every entry is a whole parsed file, so a cache left behind by a panic
+	// still only hands out correct nodes
+	let mut guard = CACHE.lock().unwrap_or_else(|error| error.into_inner());
+	return action(guard.get_or_insert_with(NodeCache::new));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::feature_tree::leaf_node::LeafNode;
+
+	const MAX_BYTES: usize = crate::constants::NODE_CACHE_MAX_BYTES;
+
+	fn node() -> Arc<Node> {
+		return Arc::new(LeafNode::new(vec![]));
+	}
+
+	#[test]
+	fn least_recently_used_entries_are_dropped_first() {
+		let mut cache = NodeCache::new();
+		cache.insert(String::from("a"), node(), MAX_BYTES / 2);
+		cache.insert(String::from("b"), node(), MAX_BYTES / 2);
+		assert!(cache.get("a").is_some());
+
+		cache.insert(String::from("c"), node(), MAX_BYTES / 2);
+		assert!(cache.get("a").is_some());
+		assert!(cache.get("b").is_none());
+		assert!(cache.get("c").is_some());
+		assert_eq!(cache.bytes, MAX_BYTES / 2 * 2);
+
+		// Replacing an entry does not count it twice
+		cache.insert(String::from("c"), node(), MAX_BYTES / 4);
+		assert_eq!(cache.bytes, MAX_BYTES / 2 + MAX_BYTES / 4);
+		cache.remove("a");
+		assert_eq!(cache.bytes, MAX_BYTES / 4);
+		assert_eq!(cache.entries.len(), cache.by_last_use.len());
+	}
+
+	#[test]
+	fn files_larger_than_the_cache_are_not_kept() {
+		let mut cache = NodeCache::new();
+		cache.insert(String::from("a"), node(), MAX_BYTES / 2);
+		cache.insert(String::from("large"), node(), MAX_BYTES + 1);
+		assert!(cache.get("large").is_none());
+		assert!(cache.get("a").is_some());
+	}
+
+	#[test]
+	fn files_are_loaded_again_only_once_invalidated() {
+		// The cache is shared by the whole process, so the path is one that
+		// no other test reads
+		let path = "node_cache_test_file";
+		let loads = std::cell::Cell::new(0);
+		let load = || {
+			loads.set(loads.get() + 1);
+			return Ok((LeafNode::new(vec![]), 1));
+		};
+
+		get_or_load(path, load).unwrap();
+		get_or_load(path, load).unwrap();
+		assert_eq!(loads.get(), 1);
+		invalidate(path);
+		get_or_load(path, load).unwrap();
+		assert_eq!(loads.get(), 2);
+
+		// Failures are not cached
+		let failed = "node_cache_test_failed_file";
+		let error = || {
+			TreeError::corrupt(
+				&crate::feature_tree::bulk_builder::get_root_path(),
+				String::from("test"),
+			)
+		};
+		assert!(get_or_load(failed, || Err(error())).is_err());
+		get_or_load(failed, load).unwrap();
+		assert_eq!(loads.get(), 3);
+		invalidate(path);
+		invalidate(failed);
+	}
+}
@@ -1,6 +1,10 @@
+use crate::database_config::DatabaseConfig;
+use crate::error::Error;
+use crate::feature_tree::tree_error::TreeError;
+
 use std::convert::TryInto;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct NodePath {
 	path: Vec<u8>,
 }
@@ -14,40 +18,86 @@ impl NodePath {
 		self.path.push(direction);
 	}
 
-	pub fn from_file_path_string(file_path: String) -> NodePath {
-		let file_name = std::path::Path::new(&file_path)
+	// `file_path` names a file of the tree, like the ones to_file_name returns
+	pub fn from_file_path_string(file_path: String) -> Result<NodePath, Error> {
+		let path_string = std::path::Path::new(&file_path)
 			.file_name()
-			.unwrap()
-			.to_str()
-			.unwrap();
-		let splits = file_name.split(".").collect::<Vec<_>>();
+			.and_then(|file_name| file_name.to_str())
+			.and_then(|file_name| file_name.strip_prefix("vp_tree."))
+			.and_then(|file_name| file_name.strip_suffix(".database"))
+			.ok_or_else(|| {
+				Error::InvalidInput(format!(
+					"{} is not a VP tree file, those are named vp_tree.<path>.database",
+					file_path
+				))
+			})?;
 		let mut path = NodePath::new_empty();
-		String::from(*splits.get(1).unwrap())
-			.chars()
-			.for_each(|e| match e as u8 {
-				crate::constants::NEAR_KEY => path.add_direction(crate::constants::NEAR_KEY),
-				crate::constants::FAR_KEY => path.add_direction(crate::constants::FAR_KEY),
-				crate::constants::FILE_KEY => path.add_direction(crate::constants::FILE_KEY),
-				_ => path.add_direction(crate::constants::UNUSED_KEY),
-			});
-		return path;
+		path_string.chars().for_each(|e| match e as u8 {
+			crate::constants::NEAR_KEY => path.add_direction(crate::constants::NEAR_KEY),
+			crate::constants::FAR_KEY => path.add_direction(crate::constants::FAR_KEY),
+			crate::constants::FILE_KEY => path.add_direction(crate::constants::FILE_KEY),
+			_ => path.add_direction(crate::constants::UNUSED_KEY),
+		});
+		return Ok(path);
+	}
+
+	pub fn to_path_string(&self) -> String {
+		return self.path.iter().map(|&e| e as char).collect();
+	}
+
+	pub fn to_file_name(&self) -> String {
+		return String::from("vp_tree.") + &self.to_path_string() + ".database";
+	}
+
+	pub fn to_file_path_string(&self, config: &DatabaseConfig) -> String {
+		return config.path_in_folder(&self.to_file_name());
+	}
+
+	// Paths of every VP tree file in the database folder, whether or not
+	// they are still reachable from the root. Staged changes count as made.
+	pub fn get_all_in_database(config: &DatabaseConfig) -> Result<Vec<NodePath>, TreeError> {
+		let mut paths = NodePath::get_all_in_folder(&config.database_folder_path)?;
+		if let Some(staging_folder_path) = &config.staging_folder_path {
+			paths.retain(|path| crate::feature_tree::staging::path_to_read(path, config).is_some());
+			for path in NodePath::get_all_in_folder(staging_folder_path)? {
+				if paths.contains(&path) == false {
+					paths.push(path);
+				}
+			}
+		}
+		return Ok(paths);
 	}
 
-	pub fn to_file_path_string(&self) -> String {
-		let path_string: String = self.path.iter().map(|&e| e as char).collect();
-		let file_name = String::from("vp_tree.") + &path_string + ".database";
-		let file_path = String::from(crate::constants::DATABASE_FOLDER_PATH) + &file_name;
-		return file_path;
+	fn get_all_in_folder(folder_path: &str) -> Result<Vec<NodePath>, TreeError> {
+		return Ok(get_file_names_in_folder(folder_path)?
+			.into_iter()
+			.filter_map(|file_name| NodePath::from_file_path_string(file_name).ok())
+			.collect());
 	}
 
-	pub fn should_split_to_new_file(&self) -> bool {
+	// Full paths of the temporary files that saves of VP tree files write
+	// before renaming them over the real one
+	pub fn get_temp_files_in_database(config: &DatabaseConfig) -> Result<Vec<String>, TreeError> {
+		let suffix = String::from(".database") + crate::constants::TEMP_FILE_EXTENSION;
+		return Ok(get_file_names_in_folder(&config.database_folder_path)?
+			.into_iter()
+			.filter(|file_name| file_name.starts_with("vp_tree.") && file_name.ends_with(&suffix))
+			.map(|file_name| config.path_in_folder(&file_name))
+			.collect());
+	}
+
+	pub fn starts_with(&self, prefix: &NodePath) -> bool {
+		return self.path.starts_with(&prefix.path);
+	}
+
+	pub fn should_split_to_new_file(&self, config: &DatabaseConfig) -> bool {
 		let current_depth = self
 			.path
 			.iter()
 			.filter(|&&e| e != crate::constants::FILE_KEY)
 			.collect::<Vec<_>>()
 			.len();
-		let at_splitting_depth = current_depth % crate::constants::MAX_FILE_NODE_DEPTH == 0;
+		let at_splitting_depth = current_depth % config.max_file_node_depth == 0;
 		let last_node_is_file = self
 			.path
 			.last()
@@ -75,3 +125,23 @@ impl NodePath {
 		return NodePath { path: path };
 	}
 }
+
+fn get_file_names_in_folder(folder_path: &str) -> Result<Vec<String>, TreeError> {
+	let io_error = |error| TreeError::Io {
+		action: "Listing the files in folder",
+		file: String::from(folder_path),
+		error: error,
+	};
+
+	let mut file_names = vec![];
+	for entry in std::fs::read_dir(folder_path).map_err(io_error)? {
+		file_names.push(
+			entry
+				.map_err(io_error)?
+				.file_name()
+				.to_string_lossy()
+				.into_owned(),
+		);
+	}
+	return Ok(file_names);
+}
@@ -0,0 +1,255 @@
+use crate::feature_tree::best_first::PendingFile;
+use crate::feature_tree::best_first::PendingFiles;
+use crate::feature_tree::file_header;
+use crate::feature_tree::internal_node;
+use crate::feature_tree::leaf_node::LeafNode;
+use crate::feature_tree::node::Node;
+use crate::feature_tree::node::TreeNode;
+use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::search_result::LowerBound;
+use crate::feature_tree::search_result::SearchResultList;
+use crate::feature_tree::tree_error::TreeError;
+use crate::features::feature_description::FeatureDescription;
+use crate::features::uuid_description_pair::UUIDDescriptionPair;
+
+use memmap2::Mmap;
+use std::convert::TryInto;
+use std::fs::OpenOptions;
+
+// Searches the tree by reading straight from memory mapped database files
+// instead of building Nodes. Only the vantage points and the leaves that are
+// actually visited are ever decoded, and each feature is decoded onto the
+// stack right before it is compared, so nothing is copied into new vectors.
+// The layout read here is the one written by each node's to_binary. The
+// checksum and layout of a file are checked in full every time it is mapped,
+// so NodeView::from_binary never reads past the end of a node.
+
+enum NodeView<'a> {
+	Internal {
+		vantage: FeatureDescription,
+		radius: u32,
+		near: &'a [u8],
+		far: &'a [u8],
+	},
+	Leaf {
+		features: &'a [u8],
+	},
+	File {
+		path_in_tree: NodePath,
+	},
+}
+
+impl<'a> NodeView<'a> {
+	fn from_binary(binary: &'a [u8]) -> NodeView<'a> {
+		let node_type = &binary[crate::constants::SIGNATURE_RANGE];
+		if node_type == crate::constants::LEAF_NODE_SIGNATURE.as_bytes() {
+			let number_nodes = read_u64(&binary[4..crate::constants::LEAF_NODE_HEADER_LENGTH]);
+			let end = LeafNode::binary_length(number_nodes) as usize;
+			return NodeView::Leaf {
+				features: &binary[crate::constants::LEAF_NODE_HEADER_LENGTH..end],
+			};
+		} else if node_type == crate::constants::INTERNAL_NODE_SIGNATURE.as_bytes() {
+			let radius = u32::from_le_bytes(binary[4..8].try_into().expect("Slice has bad length"));
+			let vantage = FeatureDescription::from_binary(
+				&binary[8..crate::constants::INTERNAL_NODE_HEADER_LENGTH],
+			);
+
+			let near_start = crate::constants::INTERNAL_NODE_HEADER_LENGTH
+				+ crate::constants::SIDE_LENGTH_LENGTH;
+			let near_end = near_start
+				+ read_u64(&binary[crate::constants::INTERNAL_NODE_HEADER_LENGTH..near_start])
+					as usize;
+			let far_start = near_end + crate::constants::SIDE_LENGTH_LENGTH;
+			let far_end = far_start + read_u64(&binary[near_end..far_start]) as usize;
+
+			return NodeView::Internal {
+				vantage: vantage,
+				radius: radius,
+				near: &binary[near_start..near_end],
+				far: &binary[far_start..far_end],
+			};
+		} else if node_type == crate::constants::FILE_NODE_SIGNATURE.as_bytes() {
+			return NodeView::File {
+				path_in_tree: NodePath::from_binary(binary),
+			};
+		} else {
+			panic!("Encountered unknown node of type {:?}", node_type);
+		}
+	}
+}
+
+fn read_u64(binary: &[u8]) -> u64 {
+	return u64::from_le_bytes(binary.try_into().expect("Slice has bad length"));
+}
+
+// Files that are missing or empty hold an empty leaf, so there is nothing to
+// map or search. Returns the map and where the root node starts in it.
+fn map_file(
+	path_in_tree: &NodePath,
+	results: &SearchResultList,
+) -> Result<Option<(Mmap, usize)>, TreeError> {
+	let file_path = path_in_tree.to_file_path_string(results.get_config());
+	let file = match OpenOptions::new().read(true).open(&file_path) {
+		Ok(file) => file,
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+		Err(error) => return Err(TreeError::read(path_in_tree, error)),
+	};
+
+	let metadata = file
+		.metadata()
+		.map_err(|error| TreeError::read(path_in_tree, error))?;
+	if metadata.len() == 0 {
+		return Ok(None);
+	}
+
+	// Changing a file while it is mapped is undefined behavior. Saves rename a
+	// new file over the old one instead of writing to it, so the map keeps
+	// seeing the file as it was.
+	let map = unsafe { Mmap::map(&file) }.map_err(|error| TreeError::read(path_in_tree, error))?;
+	let start = file_header::check_header(&map, path_in_tree, results.get_config(), false)?;
+	if map.len() == start {
+		return Ok(None);
+	}
+	Node::check_binary(&map[start..]).map_err(|reason| TreeError::corrupt(path_in_tree, reason))?;
+	return Ok(Some((map, start)));
+}
+
+pub fn find(root_path: &NodePath, results: &mut SearchResultList) -> Result<(), TreeError> {
+	return find_in_file(root_path, results);
+}
+
+pub fn find_best_first(
+	root_path: &NodePath,
+	results: &mut SearchResultList,
+) -> Result<(), TreeError> {
+	let mut pending = PendingFiles::new();
+	pending.push(PendingFile::new(LowerBound::zero(), root_path.clone()));
+
+	return crate::feature_tree::best_first::search_pending_files(
+		results,
+		pending,
+		|path_in_tree, results, bound, pending| {
+			if let Some((map, start)) = map_file(path_in_tree, results)? {
+				find_best_first_in_binary(&map[start..], results, bound, pending)?;
+			}
+			return Ok(());
+		},
+	);
+}
+
+pub fn find_batch(root_path: &NodePath, batch: &mut [SearchResultList]) -> Result<(), TreeError> {
+	let active = (0..batch.len()).collect::<Vec<usize>>();
+	return find_batch_in_file(root_path, batch, &active);
+}
+
+fn find_in_file(path_in_tree: &NodePath, results: &mut SearchResultList) -> Result<(), TreeError> {
+	if results.try_to_load_file() == false {
+		return Ok(());
+	}
+	if let Some((map, start)) = map_file(path_in_tree, results)? {
+		find_in_binary(&map[start..], results)?;
+	}
+	return Ok(());
+}
+
+fn find_in_binary(binary: &[u8], results: &mut SearchResultList) -> Result<(), TreeError> {
+	match NodeView::from_binary(binary) {
+		NodeView::Internal {
+			vantage,
+			radius,
+			near,
+			far,
+		} => internal_node::find_in_sides(results, &vantage, radius, (near, far), find_in_binary)?,
+		NodeView::Leaf { features } => find_in_leaf(features, results),
+		NodeView::File { path_in_tree } => find_in_file(&path_in_tree, results)?,
+	}
+	return Ok(());
+}
+
+// Files are only queued here, so nothing can fail until they are mapped
+fn find_best_first_in_binary(
+	binary: &[u8],
+	results: &mut SearchResultList,
+	bound: LowerBound,
+	pending: &mut PendingFiles,
+) -> Result<(), TreeError> {
+	match NodeView::from_binary(binary) {
+		NodeView::Internal {
+			vantage,
+			radius,
+			near,
+			far,
+		} => internal_node::find_best_first_in_sides(
+			results,
+			bound,
+			&vantage,
+			radius,
+			(near, far),
+			|side, results, bound| find_best_first_in_binary(side, results, bound, pending),
+		)?,
+		NodeView::Leaf { features } => find_in_leaf(features, results),
+		NodeView::File { path_in_tree } => pending.push(PendingFile::new(bound, path_in_tree)),
+	}
+	return Ok(());
+}
+
+fn find_in_leaf(features: &[u8], results: &mut SearchResultList) {
+	if results.try_to_visit_leaf() == false {
+		return;
+	}
+
+	for binary in features.chunks_exact(crate::constants::UUID_DESCRIPTION_PAIR_LENGTH) {
+		if results.has_comparisons_left() == false {
+			return;
+		}
+		results.try_to_add(&UUIDDescriptionPair::from_binary(binary));
+	}
+}
+
+fn find_batch_in_file(
+	path_in_tree: &NodePath,
+	batch: &mut [SearchResultList],
+	active: &[usize],
+) -> Result<(), TreeError> {
+	let active = active
+		.iter()
+		.copied()
+		.filter(|&index| batch[index].try_to_load_file())
+		.collect::<Vec<usize>>();
+	if active.is_empty() {
+		return Ok(());
+	}
+	if let Some((map, start)) = map_file(path_in_tree, &batch[active[0]])? {
+		find_batch_in_binary(&map[start..], batch, &active)?;
+	}
+	return Ok(());
+}
+
+fn find_batch_in_binary(
+	binary: &[u8],
+	batch: &mut [SearchResultList],
+	active: &[usize],
+) -> Result<(), TreeError> {
+	match NodeView::from_binary(binary) {
+		NodeView::Internal {
+			vantage,
+			radius,
+			near,
+			far,
+		} => internal_node::find_batch_in_sides(
+			batch,
+			active,
+			&vantage,
+			radius,
+			(near, far),
+			find_batch_in_binary,
+		)?,
+		NodeView::Leaf { features } => {
+			for &index in active {
+				find_in_leaf(features, &mut batch[index]);
+			}
+		}
+		NodeView::File { path_in_tree } => find_batch_in_file(&path_in_tree, batch, active)?,
+	}
+	return Ok(());
+}
@@ -1,3 +1,6 @@
+use crate::database_config::DatabaseConfig;
+use crate::features::distance_metric::DistanceMetric;
+use crate::features::distance_metric::Metric;
 use crate::features::feature_description::FeatureDescription;
 use crate::features::uuid_description_pair::UUIDDescriptionPair;
 
@@ -44,19 +47,112 @@ impl PartialEq for SearchResult {
 	}
 }
 
+// A lower bound on the distance between the target and every feature in part
+// of the tree. The raw bound is what the triangle inequality gives when it is
+// applied to distances directly, which is only correct for true metrics.
+#[derive(Clone, Copy)]
+pub struct LowerBound {
+	pub true_metric: f64,
+	pub raw: f64,
+}
+
+impl LowerBound {
+	pub fn zero() -> LowerBound {
+		return LowerBound {
+			true_metric: 0.0,
+			raw: 0.0,
+		};
+	}
+
+	pub fn max(self, other: LowerBound) -> LowerBound {
+		return LowerBound {
+			true_metric: self.true_metric.max(other.true_metric),
+			raw: self.raw.max(other.raw),
+		};
+	}
+}
+
+// Limits on how much work one search may do. When one runs out the search
+// stops and returns the best results it found so far.
+#[derive(Clone, Copy, Default)]
+pub struct SearchBudget {
+	pub max_comparisons: Option<u64>,
+	pub max_leaf_visits: Option<u64>,
+	pub max_file_loads: Option<u64>,
+}
+
+impl SearchBudget {
+	// The budget of each of `searches` searches that together may do no more
+	// work than this one. Every search may still do at least one of each.
+	pub fn split(&self, searches: usize) -> SearchBudget {
+		let split_limit = |limit: Option<u64>| limit.map(|e| 1.max(e / 1.max(searches as u64)));
+		return SearchBudget {
+			max_comparisons: split_limit(self.max_comparisons),
+			max_leaf_visits: split_limit(self.max_leaf_visits),
+			max_file_loads: split_limit(self.max_file_loads),
+		};
+	}
+}
+
+#[derive(Clone, Copy)]
+pub struct SearchOptions {
+	pub max_features: usize,
+	// Turns the search into a range search that returns every feature at
+	// most this far away, still limited to the best `max_features` of them.
+	pub max_distance: Option<u32>,
+	// When false, branches are skipped by applying the triangle inequality
+	// to raw distances. This is faster, but for metrics that are not a true
+	// metric (squared euclidean) it may skip branches holding real neighbors.
+	pub exact_pruning: bool,
+	pub budget: SearchBudget,
+}
+
+impl SearchOptions {
+	pub fn new(max_features: usize) -> SearchOptions {
+		return SearchOptions {
+			max_features: max_features,
+			max_distance: None,
+			exact_pruning: true,
+			budget: SearchBudget::default(),
+		};
+	}
+
+	pub fn new_range(max_distance: u32, max_features: Option<usize>) -> SearchOptions {
+		return SearchOptions {
+			max_features: max_features.unwrap_or(usize::MAX),
+			max_distance: Some(max_distance),
+			exact_pruning: true,
+			budget: SearchBudget::default(),
+		};
+	}
+}
+
 pub struct SearchResultList {
 	results: BinaryHeap<SearchResult>,
-	max_features: usize,
+	options: SearchOptions,
 	target: FeatureDescription,
+	metric: DistanceMetric,
+	// Tells the search where the files of the tree are
+	config: DatabaseConfig,
 	comparisons: u64,
+	leaf_visits: u64,
+	file_loads: u64,
+	is_exact: bool,
+	is_truncated: bool,
 }
 
 impl SearchResultList {
 	pub fn try_to_add(&mut self, to_add: &UUIDDescriptionPair) {
 		self.comparisons += 1;
 
-		let distance_to_target = self.target.distance(to_add.get_description());
-		if self.results.len() < self.max_features {
+		let distance_to_target = self.metric.distance(&self.target, to_add.get_description());
+		let is_in_range = self
+			.options
+			.max_distance
+			.map_or(true, |max_distance| distance_to_target <= max_distance);
+		if is_in_range == false {
+			// Outside of the range being searched
+		} else if self.results.len() < self.options.max_features {
 			let result = SearchResult::new(to_add.clone(), distance_to_target);
 			self.results.push(result);
 		} else if distance_to_target < self.results.peek().unwrap().get_distance() {
@@ -69,27 +165,447 @@ impl SearchResultList {
 	}
 
 	pub fn distance_to_feature(&self, feature: &FeatureDescription) -> u32 {
-		return self.target.distance(feature);
+		return self.metric.distance(&self.target, feature);
 	}
 
-	pub fn get_worst_distance_to_target(&self) -> u32 {
-		return self.results.peek().unwrap().get_distance();
+	// The distance a feature has to beat to be added, and if being equal to
+	// it is good enough. Once the list is full only closer features are added,
+	// before that a range search still takes everything up to its range.
+	pub fn get_worst_distance_to_target(&self) -> Option<(u32, bool)> {
+		if self.results.len() >= self.options.max_features {
+			return self.results.peek().map(|e| (e.get_distance(), false));
+		}
+		return self.options.max_distance.map(|e| (e, true));
 	}
 
-	pub fn new(max_features: usize, target: FeatureDescription) -> SearchResultList {
+	// Decides if the side of a vantage point that the target is not on could
+	// hold a feature that would still be added to the results.
+	pub fn should_search_other_side(
+		&mut self,
+		distance_to_vantage: u32,
+		radius: u32,
+		is_near: bool,
+	) -> bool {
+		let bound = self.lower_bound_of_side(distance_to_vantage, radius, is_near == false);
+		return self.could_contain(bound);
+	}
+
+	// Smallest distance any feature on one side of a vantage point can have
+	// to the target. Features closer than the radius are on the near side.
+	pub fn lower_bound_of_side(
+		&self,
+		distance_to_vantage: u32,
+		radius: u32,
+		is_near_side: bool,
+	) -> LowerBound {
+		return LowerBound {
+			true_metric: bound_of_side(
+				self.metric.to_true_metric(distance_to_vantage),
+				self.metric.to_true_metric(radius),
+				is_near_side,
+			),
+			raw: bound_of_side(distance_to_vantage as f64, radius as f64, is_near_side),
+		};
+
+		fn bound_of_side(distance_to_vantage: f64, radius: f64, is_near_side: bool) -> f64 {
+			let bound = match is_near_side {
+				true => distance_to_vantage - radius,
+				false => radius - distance_to_vantage,
+			};
+			return bound.max(0.0);
+		}
+	}
+
+	// Decides if a part of the tree whose features are all at least `bound`
+	// away from the target could hold a feature that would be added.
+	pub fn could_contain(&mut self, bound: LowerBound) -> bool {
+		let (worst_distance, is_inclusive) = match self.get_worst_distance_to_target() {
+			Some(worst) => worst,
+			None => return true,
+		};
+
+		let should_search = is_within(
+			bound.true_metric - self.metric.tolerance(),
+			self.metric.to_true_metric(worst_distance),
+			is_inclusive,
+		);
+
+		if self.options.exact_pruning {
+			return should_search;
+		}
+
+		let raw_should_search = is_within(bound.raw, worst_distance as f64, is_inclusive);
+		if should_search && raw_should_search == false {
+			self.is_exact = false;
+		}
+		return raw_should_search;
+
+		fn is_within(bound: f64, worst: f64, is_inclusive: bool) -> bool {
+			return match is_inclusive {
+				true => bound <= worst,
+				false => bound < worst,
+			};
+		}
+	}
+
+	pub fn new(
+		options: SearchOptions,
+		target: FeatureDescription,
+		config: &DatabaseConfig,
+	) -> SearchResultList {
 		return SearchResultList {
 			results: BinaryHeap::new(),
-			max_features: max_features,
+			options: options,
 			target: target,
+			metric: config.metric,
+			config: config.clone(),
 			comparisons: 0,
+			leaf_visits: 0,
+			file_loads: 0,
+			is_exact: true,
+			is_truncated: false,
 		};
 	}
 
+	pub fn get_config(&self) -> &DatabaseConfig {
+		return &self.config;
+	}
+
 	pub fn get_comparisons(&self) -> u64 {
 		return self.comparisons;
 	}
 
+	// False when a branch that could have held a closer feature was skipped
+	pub fn is_exact(&self) -> bool {
+		return self.is_exact;
+	}
+
+	// True when the search stopped early because its budget ran out
+	pub fn is_truncated(&self) -> bool {
+		return self.is_truncated;
+	}
+
+	pub fn has_comparisons_left(&mut self) -> bool {
+		return self.spend(self.comparisons, self.options.budget.max_comparisons);
+	}
+
+	pub fn try_to_visit_leaf(&mut self) -> bool {
+		if self.spend(self.leaf_visits, self.options.budget.max_leaf_visits) == false {
+			return false;
+		}
+		self.leaf_visits += 1;
+		return true;
+	}
+
+	pub fn try_to_load_file(&mut self) -> bool {
+		if self.spend(self.file_loads, self.options.budget.max_file_loads) == false {
+			return false;
+		}
+		self.file_loads += 1;
+		return true;
+	}
+
+	fn spend(&mut self, used: u64, limit: Option<u64>) -> bool {
+		if self.is_truncated {
+			return false;
+		}
+		if limit.map_or(false, |limit| used >= limit) {
+			self.is_truncated = true;
+			return false;
+		}
+		return true;
+	}
+
 	pub fn get_results(self) -> Vec<SearchResult> {
 		return self.results.into_sorted_vec();
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::features_database;
+
+	// Distances of every feature to `target`, closest first
+	fn brute_force(
+		target: &FeatureDescription,
+		pairs: &[UUIDDescriptionPair],
+		config: &DatabaseConfig,
+	) -> Vec<u32> {
+		let mut distances = pairs
+			.iter()
+			.map(|pair| config.metric.distance(target, pair.get_description()))
+			.collect::<Vec<u32>>();
+		distances.sort_unstable();
+		return distances;
+	}
+
+	fn distances(results: Vec<SearchResult>) -> Vec<u32> {
+		return results.iter().map(|e| e.get_distance()).collect();
+	}
+
+	#[test]
+	fn list_keeps_the_nearest_features() {
+		let config = DatabaseConfig::new_test_database("search_result_nearest");
+		let all = UUIDDescriptionPair::seeded_random(11, 1, 100);
+		let target = FeatureDescription::seeded_random(12).next().unwrap();
+
+		let mut results = SearchResultList::new(SearchOptions::new(5), target.clone(), &config);
+		for pair in all.iter() {
+			results.try_to_add(pair);
+		}
+		assert_eq!(results.get_comparisons(), 100);
+		assert_eq!(
+			distances(results.get_results()),
+			brute_force(&target, &all, &config)[..5].to_vec()
+		);
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn list_keeps_every_feature_in_range() {
+		let config = DatabaseConfig::new_test_database("search_result_range");
+		let all = UUIDDescriptionPair::seeded_random(11, 1, 100);
+		let target = FeatureDescription::seeded_random(12).next().unwrap();
+		let expected = brute_force(&target, &all, &config);
+		let max_distance = expected[40];
+		let in_range = expected
+			.iter()
+			.cloned()
+			.filter(|distance| *distance <= max_distance)
+			.collect::<Vec<u32>>();
+
+		let mut results = SearchResultList::new(
+			SearchOptions::new_range(max_distance, None),
+			target.clone(),
+			&config,
+		);
+		for pair in all.iter() {
+			results.try_to_add(pair);
+		}
+		assert_eq!(distances(results.get_results()), in_range);
+
+		// Limited to the closest ones in range
+		let mut results = SearchResultList::new(
+			SearchOptions::new_range(max_distance, Some(10)),
+			target,
+			&config,
+		);
+		for pair in all.iter() {
+			results.try_to_add(pair);
+		}
+		assert_eq!(distances(results.get_results()), in_range[..10].to_vec());
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn tree_search_matches_brute_force() {
+		let config = DatabaseConfig::new_test_database("search_result_tree");
+		let all = UUIDDescriptionPair::seeded_random(11, 1, 300);
+		features_database::insert_description_vec_into_database(all.clone(), &config).unwrap();
+
+		for target in FeatureDescription::seeded_random(13).take(10) {
+			let expected = brute_force(&target, &all, &config);
+
+			let results = features_database::find_feature_description_in_database(
+				target.clone(),
+				SearchOptions::new(10),
+				&config,
+			)
+			.unwrap();
+			assert!(results.is_exact());
+			assert_eq!(distances(results.get_results()), expected[..10].to_vec());
+
+			let max_distance = expected[20];
+			let results = features_database::find_feature_description_in_database(
+				target,
+				SearchOptions::new_range(max_distance, None),
+				&config,
+			)
+			.unwrap();
+			assert_eq!(
+				distances(results.get_results()),
+				expected
+					.iter()
+					.cloned()
+					.filter(|distance| *distance <= max_distance)
+					.collect::<Vec<u32>>()
+			);
+		}
+		config.remove_test_database();
+	}
+
+	// Exact pruning may only skip branches that can not hold a result, for
+	// every metric including squared euclidean, which is not a true metric
+	#[test]
+	fn exact_pruning_matches_brute_force_for_every_metric() {
+		for metric in [
+			DistanceMetric::Hamming,
+			DistanceMetric::Euclidean,
+			DistanceMetric::SquaredEuclidean,
+			DistanceMetric::Manhattan,
+		] {
+			let mut requested = crate::database_config::RequestedConfig::for_test_database(
+				&format!("search_result_pruning_{}", metric.to_name()),
+			);
+			requested.metric = Some(String::from(metric.to_name()));
+			let config = crate::database::Database::open(&requested)
+				.unwrap()
+				.get_config()
+				.clone();
+			assert_eq!(config.metric, metric);
+			let all = UUIDDescriptionPair::seeded_random(11, 1, 300);
+			features_database::insert_description_vec_into_database(all.clone(), &config).unwrap();
+
+			for target in FeatureDescription::seeded_random(16).take(10) {
+				let expected = brute_force(&target, &all, &config);
+				let results = features_database::find_feature_description_in_database(
+					target,
+					SearchOptions::new(10),
+					&config,
+				)
+				.unwrap();
+				assert!(results.is_exact());
+				assert_eq!(
+					distances(results.get_results()),
+					expected[..10].to_vec(),
+					"{}",
+					metric.to_name()
+				);
+			}
+			config.remove_test_database();
+		}
+	}
+
+	#[test]
+	fn batch_search_matches_searching_one_at_a_time() {
+		let config = DatabaseConfig::new_test_database("search_result_batch");
+		let all = UUIDDescriptionPair::seeded_random(11, 1, 300);
+		features_database::insert_description_vec_into_database(all.clone(), &config).unwrap();
+		let targets = FeatureDescription::seeded_random(14)
+			.take(10)
+			.chain(
+				all.iter()
+					.take(5)
+					.map(|pair| pair.get_description().clone()),
+			)
+			.collect::<Vec<FeatureDescription>>();
+		let max_distance = brute_force(&targets[0], &all, &config)[30];
+
+		// Features as far away as the k-th one can be either of them, so only
+		// range searches have to find the same ones
+		for (options, same_features) in [
+			(SearchOptions::new(10), false),
+			(SearchOptions::new_range(max_distance, None), true),
+		] {
+			let batch = features_database::find_feature_descriptions_in_database(
+				targets.clone(),
+				options,
+				&config,
+			)
+			.unwrap();
+			assert_eq!(batch.len(), targets.len());
+			for (target, batch_results) in targets.iter().zip(batch) {
+				let single = features_database::find_feature_description_in_database(
+					target.clone(),
+					options,
+					&config,
+				)
+				.unwrap();
+				assert!(batch_results.is_exact());
+				let batch_found = found(batch_results.get_results());
+				let single_found = found(single.get_results());
+				assert_eq!(
+					batch_found.iter().map(|e| e.0).collect::<Vec<u32>>(),
+					single_found.iter().map(|e| e.0).collect::<Vec<u32>>()
+				);
+				if same_features {
+					assert_eq!(batch_found, single_found);
+				}
+			}
+		}
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn memory_mapped_search_matches_parsed_search() {
+		let parsed = DatabaseConfig::new_test_database("search_result_memory_mapped");
+		let mut memory_mapped = parsed.clone();
+		memory_mapped.memory_mapped_search = true;
+		let all = UUIDDescriptionPair::seeded_random(11, 1, 300);
+		features_database::insert_description_vec_into_database(all.clone(), &parsed).unwrap();
+		let targets = FeatureDescription::seeded_random(15)
+			.take(10)
+			.collect::<Vec<FeatureDescription>>();
+		let max_distance = brute_force(&targets[0], &all, &parsed)[30];
+
+		for options in [
+			SearchOptions::new(10),
+			SearchOptions::new_range(max_distance, None),
+		] {
+			for target in targets.iter() {
+				let search = |config: &DatabaseConfig| {
+					return found(
+						features_database::find_feature_description_in_database(
+							target.clone(),
+							options,
+							config,
+						)
+						.unwrap()
+						.get_results(),
+					);
+				};
+				assert_eq!(search(&memory_mapped), search(&parsed));
+			}
+
+			let search_batch = |config: &DatabaseConfig| {
+				return features_database::find_feature_descriptions_in_database(
+					targets.clone(),
+					options,
+					config,
+				)
+				.unwrap()
+				.into_iter()
+				.map(|results| found(results.get_results()))
+				.collect::<Vec<Vec<(u32, u64)>>>();
+			};
+			assert_eq!(search_batch(&memory_mapped), search_batch(&parsed));
+		}
+		parsed.remove_test_database();
+	}
+
+	// The server splits its budget between the descriptors of a request
+	#[test]
+	fn split_budgets_never_add_up_to_more_than_the_budget() {
+		let budget = crate::constants::SERVER_REQUEST_BUDGET;
+		for searches in 1..=crate::constants::MAX_DESCRIPTORS_PER_REQUEST {
+			let split = budget.split(searches);
+			for (limit, split_limit) in [
+				(budget.max_comparisons, split.max_comparisons),
+				(budget.max_leaf_visits, split.max_leaf_visits),
+				(budget.max_file_loads, split.max_file_loads),
+			] {
+				assert_eq!(limit.is_some(), split_limit.is_some());
+				if let (Some(limit), Some(split_limit)) = (limit, split_limit) {
+					assert!(split_limit >= 1);
+					assert!(
+						split_limit * searches as u64 <= limit,
+						"{} searches",
+						searches
+					);
+				}
+			}
+		}
+	}
+
+	// Distances and uuids, as features at the same distance can be found in
+	// any order
+	fn found(results: Vec<SearchResult>) -> Vec<(u32, u64)> {
+		let mut found = results
+			.iter()
+			.map(|e| (e.get_distance(), e.get_result_uuid()))
+			.collect::<Vec<(u32, u64)>>();
+		found.sort_unstable();
+		return found;
+	}
+}
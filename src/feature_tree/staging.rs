@@ -0,0 +1,276 @@
+use crate::database_config::DatabaseConfig;
+use crate::error::Error;
+use crate::feature_tree::node_cache;
+use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::tree_error::TreeError;
+
+use std::path::Path;
+
+// Changes to many files of the tree that have to happen all at once, like
+// rebuilding it. While they are staged, files that are written go to a
+// staging folder and files that are removed get an empty tombstone there, so
+// the tree in the database folder stays as it was. Committing renames the
+// staging folder to mark it as complete, then moves the new files over and
+// only then removes the old ones. When the database is opened a staging
+// folder that was never completed is thrown away and a complete one is moved
+// over again, see recover.
+
+const TOMBSTONE_EXTENSION: &str = ".removed";
+
+// Returns the config to make the staged changes with
+pub fn begin(config: &DatabaseConfig) -> Result<DatabaseConfig, Error> {
+	let folder_path = config.path_in_folder(crate::constants::STAGING_FOLDER_NAME);
+	remove_folder_if_exists(&folder_path)?;
+	std::fs::create_dir(&folder_path)
+		.map_err(|error| Error::io(format!("Creating {} failed", folder_path), error))?;
+
+	let mut staged = config.clone();
+	staged.staging_folder_path = Some(folder_path);
+	return Ok(staged);
+}
+
+// Commits the changes made with `staged` if `result` is Ok, otherwise throws
+// them away
+pub fn finish<T>(result: Result<T, TreeError>, staged: &DatabaseConfig) -> Result<T, Error> {
+	let folder_path = staged
+		.staging_folder_path
+		.as_ref()
+		.expect("Tried to finish changes that were not staged");
+	let value = match result {
+		Ok(value) => value,
+		Err(error) => {
+			remove_folder_if_exists(folder_path)?;
+			return Err(Error::from(error));
+		}
+	};
+
+	// Every staged file was synced when it was written
+	let ready_path = staged.path_in_folder(crate::constants::STAGING_READY_FOLDER_NAME);
+	std::fs::File::open(folder_path)
+		.and_then(|folder| folder.sync_all())
+		.and_then(|_| std::fs::rename(folder_path, &ready_path))
+		.and_then(|_| staged.sync_database_folder())
+		.map_err(|error| Error::io(format!("Completing {} failed", folder_path), error))?;
+	apply(staged)?;
+	return Ok(value);
+}
+
+// Finishes changes whose commit was interrupted and throws away those that
+// were never committed. Returns whether there was a commit to finish.
+pub fn recover(config: &DatabaseConfig) -> Result<bool, Error> {
+	remove_folder_if_exists(&config.path_in_folder(crate::constants::STAGING_FOLDER_NAME))?;
+	if has_pending_commit(config) {
+		apply(config)?;
+		return Ok(true);
+	}
+	return Ok(false);
+}
+
+pub fn has_pending_commit(config: &DatabaseConfig) -> bool {
+	return Path::new(&config.path_in_folder(crate::constants::STAGING_READY_FOLDER_NAME)).exists();
+}
+
+// Moving a file over is the last thing done with it, so this can be run again
+// after being interrupted at any point
+fn apply(config: &DatabaseConfig) -> Result<(), Error> {
+	let ready_path = config.path_in_folder(crate::constants::STAGING_READY_FOLDER_NAME);
+	let io_error = |error| {
+		Error::io(
+			format!("Moving staged files from {} failed", ready_path),
+			error,
+		)
+	};
+
+	let mut file_names = vec![];
+	for entry in std::fs::read_dir(&ready_path).map_err(io_error)? {
+		file_names.push(
+			entry
+				.map_err(io_error)?
+				.file_name()
+				.to_string_lossy()
+				.into_owned(),
+		);
+	}
+	let (removed_names, written_names): (Vec<String>, Vec<String>) = file_names
+		.into_iter()
+		.partition(|file_name| file_name.ends_with(TOMBSTONE_EXTENSION));
+
+	for file_name in written_names {
+		let file_path = config.path_in_folder(&file_name);
+		std::fs::rename(Path::new(&ready_path).join(&file_name), &file_path).map_err(io_error)?;
+		node_cache::invalidate(&file_path);
+	}
+	config.sync_database_folder().map_err(io_error)?;
+
+	for tombstone_name in removed_names {
+		let file_name = tombstone_name.trim_end_matches(TOMBSTONE_EXTENSION);
+		let file_path = config.path_in_folder(file_name);
+		remove_file_if_exists(&file_path).map_err(io_error)?;
+		node_cache::invalidate(&file_path);
+		std::fs::remove_file(Path::new(&ready_path).join(&tombstone_name)).map_err(io_error)?;
+	}
+	config.sync_database_folder().map_err(io_error)?;
+
+	std::fs::remove_dir(&ready_path).map_err(io_error)?;
+	return config.sync_database_folder().map_err(io_error);
+}
+
+pub fn is_staged(config: &DatabaseConfig) -> bool {
+	return config.staging_folder_path.is_some();
+}
+
+// The file holding `path_in_tree` with the staged changes applied, or None
+// when there is no such file
+pub fn path_to_read(path_in_tree: &NodePath, config: &DatabaseConfig) -> Option<String> {
+	let file_path = path_in_tree.to_file_path_string(config);
+	let folder_path = match &config.staging_folder_path {
+		Some(folder_path) => folder_path,
+		None => return Some(file_path),
+	};
+
+	let staged_path = staged_file_path(folder_path, path_in_tree);
+	if Path::new(&staged_path).exists() {
+		return Some(staged_path);
+	}
+	if Path::new(&(staged_path + TOMBSTONE_EXTENSION)).exists() {
+		return None;
+	}
+	if Path::new(&file_path).exists() {
+		return Some(file_path);
+	}
+	return None;
+}
+
+pub fn path_to_write(
+	path_in_tree: &NodePath,
+	config: &DatabaseConfig,
+) -> Result<String, TreeError> {
+	let folder_path = match &config.staging_folder_path {
+		Some(folder_path) => folder_path,
+		None => return Ok(path_in_tree.to_file_path_string(config)),
+	};
+
+	let staged_path = staged_file_path(folder_path, path_in_tree);
+	remove_file_if_exists(&(staged_path.clone() + TOMBSTONE_EXTENSION))
+		.map_err(|error| TreeError::write(path_in_tree, error))?;
+	return Ok(staged_path);
+}
+
+pub fn remove(path_in_tree: &NodePath, config: &DatabaseConfig) -> Result<(), TreeError> {
+	let folder_path = config
+		.staging_folder_path
+		.as_ref()
+		.expect("Tried to stage the removal of a file without staging");
+
+	let staged_path = staged_file_path(folder_path, path_in_tree);
+	return remove_file_if_exists(&staged_path)
+		.and_then(|_| std::fs::File::create(staged_path + TOMBSTONE_EXTENSION))
+		.map(|_| ())
+		.map_err(|error| TreeError::remove(path_in_tree, error));
+}
+
+fn staged_file_path(folder_path: &str, path_in_tree: &NodePath) -> String {
+	return Path::new(folder_path)
+		.join(path_in_tree.to_file_name())
+		.to_string_lossy()
+		.into_owned();
+}
+
+fn remove_file_if_exists(file_path: &str) -> std::io::Result<()> {
+	return match std::fs::remove_file(file_path) {
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+		result => result,
+	};
+}
+
+fn remove_folder_if_exists(folder_path: &str) -> Result<(), Error> {
+	return match std::fs::remove_dir_all(folder_path) {
+		Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(Error::io(
+			format!("Removing staged changes in {} failed", folder_path),
+			error,
+		)),
+		_ => Ok(()),
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::feature_tree::bulk_builder;
+	use crate::features::uuid_description_pair::UUIDDescriptionPair;
+
+	use std::collections::HashSet;
+
+	fn uuids_in_tree(config: &DatabaseConfig) -> HashSet<u64> {
+		let report = crate::features_database::verify_database(config).unwrap();
+		assert!(report.unreadable_files.is_empty());
+		assert!(report.missing_files.is_empty());
+		assert!(report.orphaned_files.is_empty());
+		return report.uuids;
+	}
+
+	fn uuids(pairs: &[UUIDDescriptionPair]) -> HashSet<u64> {
+		return pairs.iter().map(|pair| pair.get_uuid()).collect();
+	}
+
+	// Stages replacing the whole tree with `pairs`
+	fn stage_new_tree(pairs: &[UUIDDescriptionPair], config: &DatabaseConfig) -> DatabaseConfig {
+		let staged = begin(config).unwrap();
+		bulk_builder::remove_files_below(&NodePath::new_empty(), &staged).unwrap();
+		bulk_builder::build_tree(pairs.to_vec(), &staged).unwrap();
+		return staged;
+	}
+
+	#[test]
+	fn changes_that_were_not_committed_are_thrown_away() {
+		let config = DatabaseConfig::new_test_database("staging_not_committed");
+		let old = UUIDDescriptionPair::seeded_random(41, 1, 100);
+		let new = UUIDDescriptionPair::seeded_random(42, 1000, 100);
+		crate::features_database::insert_description_vec_into_database(old.clone(), &config)
+			.unwrap();
+
+		let _staged = stage_new_tree(&new, &config);
+		assert_eq!(uuids_in_tree(&config), uuids(&old));
+		assert!(recover(&config).unwrap() == false);
+		assert_eq!(uuids_in_tree(&config), uuids(&old));
+		assert!(
+			Path::new(&config.path_in_folder(crate::constants::STAGING_FOLDER_NAME)).exists()
+				== false
+		);
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn interrupted_commits_are_finished() {
+		let config = DatabaseConfig::new_test_database("staging_interrupted");
+		let old = UUIDDescriptionPair::seeded_random(41, 1, 100);
+		let new = UUIDDescriptionPair::seeded_random(42, 1000, 100);
+		crate::features_database::insert_description_vec_into_database(old.clone(), &config)
+			.unwrap();
+
+		// Interrupted after the staging folder was marked as complete and one
+		// of its files was moved over
+		let staged = stage_new_tree(&new, &config);
+		let ready_path = config.path_in_folder(crate::constants::STAGING_READY_FOLDER_NAME);
+		std::fs::rename(staged.staging_folder_path.as_ref().unwrap(), &ready_path).unwrap();
+		let moved_name = std::fs::read_dir(&ready_path)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+			.find(|file_name| file_name.ends_with(TOMBSTONE_EXTENSION) == false)
+			.unwrap();
+		std::fs::rename(
+			Path::new(&ready_path).join(&moved_name),
+			config.path_in_folder(&moved_name),
+		)
+		.unwrap();
+		node_cache::invalidate(&config.path_in_folder(&moved_name));
+		assert!(has_pending_commit(&config));
+
+		assert!(recover(&config).unwrap());
+		assert!(has_pending_commit(&config) == false);
+		assert_eq!(uuids_in_tree(&config), uuids(&new));
+		// Nothing is left to finish
+		assert!(recover(&config).unwrap() == false);
+		config.remove_test_database();
+	}
+}
@@ -0,0 +1,105 @@
+use crate::feature_tree::node_path::NodePath;
+
+use std::fmt;
+
+// Problems found while reading the files of the tree. Each one names the file
+// it was found in, so that file can be looked at or restored.
+#[derive(Debug)]
+pub enum TreeError {
+	// `action` says what was being done to `file`, like "Reading VP tree file"
+	Io {
+		action: &'static str,
+		file: String,
+		error: std::io::Error,
+	},
+	Corrupt {
+		file: String,
+		path_in_tree: NodePath,
+		reason: String,
+	},
+	IncompatibleFormat {
+		file: String,
+		reason: String,
+	},
+}
+
+impl TreeError {
+	pub fn read(path_in_tree: &NodePath, error: std::io::Error) -> TreeError {
+		return TreeError::io("Reading VP tree file", path_in_tree, error);
+	}
+
+	pub fn write(path_in_tree: &NodePath, error: std::io::Error) -> TreeError {
+		return TreeError::io("Writing VP tree file", path_in_tree, error);
+	}
+
+	pub fn remove(path_in_tree: &NodePath, error: std::io::Error) -> TreeError {
+		return TreeError::io("Removing VP tree file", path_in_tree, error);
+	}
+
+	// Syncing the database folder after `path_in_tree` was renamed or removed
+	pub fn sync(path_in_tree: &NodePath, error: std::io::Error) -> TreeError {
+		return TreeError::io(
+			"Syncing the database folder after changing VP tree file",
+			path_in_tree,
+			error,
+		);
+	}
+
+	fn io(action: &'static str, path_in_tree: &NodePath, error: std::io::Error) -> TreeError {
+		return TreeError::Io {
+			action: action,
+			file: path_in_tree.to_file_name(),
+			error: error,
+		};
+	}
+
+	pub fn corrupt(path_in_tree: &NodePath, reason: String) -> TreeError {
+		return TreeError::Corrupt {
+			file: path_in_tree.to_file_name(),
+			path_in_tree: path_in_tree.clone(),
+			reason: reason,
+		};
+	}
+
+	pub fn incompatible_format(path_in_tree: &NodePath, reason: String) -> TreeError {
+		return TreeError::IncompatibleFormat {
+			file: path_in_tree.to_file_name(),
+			reason: reason,
+		};
+	}
+}
+
+impl fmt::Display for TreeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return match self {
+			TreeError::Io {
+				action,
+				file,
+				error,
+			} => write!(f, "{} {} failed: {}", action, file, error),
+			TreeError::Corrupt {
+				file,
+				path_in_tree,
+				reason,
+			} => write!(
+				f,
+				"VP tree file {} (node path {:?}) is corrupt: {}",
+				file,
+				path_in_tree.to_path_string(),
+				reason
+			),
+			TreeError::IncompatibleFormat { file, reason } => {
+				write!(f, "VP tree file {} can not be read: {}", file, reason)
+			}
+		};
+	}
+}
+
+impl std::error::Error for TreeError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		return match self {
+			TreeError::Io { error, .. } => Some(error),
+			_ => None,
+		};
+	}
+}
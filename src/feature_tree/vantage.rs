@@ -0,0 +1,107 @@
+use crate::features::distance_metric::DistanceMetric;
+use crate::features::distance_metric::Metric;
+use crate::features::feature_description::FeatureDescription;
+use crate::features::uuid_description_pair::UUIDDescriptionPair;
+
+use rand::seq::SliceRandom;
+
+// How the vantage point of a new internal node is chosen. A good vantage
+// point spreads the features out so that searches can skip more of the tree.
+// Chosen per database, see DatabaseConfig.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VantageStrategy {
+	// A random vector of only 0s and 255s, which may be nowhere near the data
+	RandomEdge,
+	// One of the features being split
+	RandomFeature,
+	// Of VANTAGE_CANDIDATES random features, the one whose distances to a
+	// sample of the other features have the largest variance
+	LargestSpread,
+	// The feature farthest away from a random feature, which tends to be on
+	// the edge of the data
+	FarthestPoint,
+}
+
+impl VantageStrategy {
+	pub fn to_name(&self) -> &'static str {
+		return match self {
+			VantageStrategy::RandomEdge => "random_edge",
+			VantageStrategy::RandomFeature => "random_feature",
+			VantageStrategy::LargestSpread => "largest_spread",
+			VantageStrategy::FarthestPoint => "farthest_point",
+		};
+	}
+
+	pub fn from_name(name: &str) -> Option<VantageStrategy> {
+		return match name {
+			"random_edge" => Some(VantageStrategy::RandomEdge),
+			"random_feature" => Some(VantageStrategy::RandomFeature),
+			"largest_spread" => Some(VantageStrategy::LargestSpread),
+			"farthest_point" => Some(VantageStrategy::FarthestPoint),
+			_ => None,
+		};
+	}
+}
+
+pub fn select_vantage(
+	strategy: VantageStrategy,
+	pairs: &[UUIDDescriptionPair],
+	metric: DistanceMetric,
+) -> FeatureDescription {
+	if pairs.is_empty() {
+		return FeatureDescription::random_edge();
+	}
+
+	return match strategy {
+		VantageStrategy::RandomEdge => FeatureDescription::random_edge(),
+		VantageStrategy::RandomFeature => random_feature(pairs),
+		VantageStrategy::LargestSpread => largest_spread(pairs, metric),
+		VantageStrategy::FarthestPoint => farthest_point(pairs, metric),
+	};
+}
+
+fn random_feature(pairs: &[UUIDDescriptionPair]) -> FeatureDescription {
+	return pairs
+		.choose(&mut rand::thread_rng())
+		.unwrap()
+		.get_description()
+		.clone();
+}
+
+fn largest_spread(pairs: &[UUIDDescriptionPair], metric: DistanceMetric) -> FeatureDescription {
+	let mut rng = rand::thread_rng();
+	let sample = pairs
+		.choose_multiple(&mut rng, crate::constants::VANTAGE_SAMPLE_SIZE)
+		.collect::<Vec<&UUIDDescriptionPair>>();
+
+	let mut best = random_feature(pairs);
+	let mut best_spread = -1.0;
+	for candidate in pairs.choose_multiple(&mut rng, crate::constants::VANTAGE_CANDIDATES) {
+		let distances = sample
+			.iter()
+			.map(|e| metric.distance(candidate.get_description(), e.get_description()) as f64)
+			.collect::<Vec<f64>>();
+		let mean = distances.iter().sum::<f64>() / distances.len() as f64;
+		let spread = distances
+			.iter()
+			.map(|e| (e - mean) * (e - mean))
+			.sum::<f64>()
+			/ distances.len() as f64;
+
+		if spread > best_spread {
+			best_spread = spread;
+			best = candidate.get_description().clone();
+		}
+	}
+	return best;
+}
+
+fn farthest_point(pairs: &[UUIDDescriptionPair], metric: DistanceMetric) -> FeatureDescription {
+	let start = random_feature(pairs);
+	return pairs
+		.iter()
+		.max_by_key(|e| metric.distance(&start, e.get_description()))
+		.unwrap()
+		.get_description()
+		.clone();
+}
@@ -0,0 +1,262 @@
+use crate::database_config::DatabaseConfig;
+use crate::feature_tree::bulk_builder::get_root_path;
+use crate::feature_tree::file_node::FileNode;
+use crate::feature_tree::node_path::NodePath;
+use crate::feature_tree::tree_error::TreeError;
+use crate::features::distance_metric::Metric;
+use crate::features::feature_description::FeatureDescription;
+use crate::features::uuid_description_pair::UUIDDescriptionPair;
+
+use rocket::serde::Serialize;
+use std::collections::HashSet;
+
+// Everything found by walking every file of the tree with TreeNode::verify.
+// A file that can not be read is recorded and skipped, so one bad file does
+// not hide problems in the rest of the tree.
+pub struct TreeReport {
+	pub files_checked: u64,
+	pub features_checked: u64,
+	pub unreadable_files: Vec<UnreadableFile>,
+	pub missing_files: Vec<String>,
+	pub misplaced_features: Vec<MisplacedFeature>,
+	pub duplicate_uuids: Vec<u64>,
+	// Files in the database folder that the tree does not point to
+	pub orphaned_files: Vec<NodePath>,
+	// Temporary files of saves that never finished and staged changes that
+	// were never completed, by their full path
+	pub orphaned_temp_files: Vec<String>,
+	pub uuids: HashSet<u64>,
+
+	// Copies of the features behind misplaced_features and duplicate_uuids,
+	// so they can be added again in the right place
+	pub misplaced_pairs: Vec<UUIDDescriptionPair>,
+	pub duplicate_pairs: Vec<UUIDDescriptionPair>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UnreadableFile {
+	pub file: String,
+	pub error: String,
+}
+
+// A feature on the wrong side of one of the internal nodes above it, which
+// searches will not find when they prune that side
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MisplacedFeature {
+	pub uuid: u64,
+	pub leaf_path: String,
+	pub internal_node_path: String,
+	pub is_near: bool,
+	pub distance: u32,
+	pub radius: u32,
+}
+
+struct SideOfNode {
+	vantage: FeatureDescription,
+	radius: u32,
+	is_near: bool,
+	internal_node_path: NodePath,
+}
+
+pub struct TreeVerifier {
+	config: DatabaseConfig,
+	sides: Vec<SideOfNode>,
+	reached_files: HashSet<NodePath>,
+	report: TreeReport,
+}
+
+impl TreeVerifier {
+	pub fn new(config: &DatabaseConfig) -> TreeVerifier {
+		return TreeVerifier {
+			config: config.clone(),
+			sides: vec![],
+			reached_files: HashSet::new(),
+			report: TreeReport {
+				files_checked: 0,
+				features_checked: 0,
+				unreadable_files: vec![],
+				missing_files: vec![],
+				misplaced_features: vec![],
+				duplicate_uuids: vec![],
+				orphaned_files: vec![],
+				orphaned_temp_files: vec![],
+				uuids: HashSet::new(),
+				misplaced_pairs: vec![],
+				duplicate_pairs: vec![],
+			},
+		};
+	}
+
+	pub fn get_config(&self) -> &DatabaseConfig {
+		return &self.config;
+	}
+
+	// Every feature below this call until leave_side must be on the given
+	// side of the internal node at `internal_node_path`
+	pub fn enter_side(
+		&mut self,
+		vantage: &FeatureDescription,
+		radius: u32,
+		is_near: bool,
+		internal_node_path: &NodePath,
+	) {
+		self.sides.push(SideOfNode {
+			vantage: vantage.clone(),
+			radius: radius,
+			is_near: is_near,
+			internal_node_path: internal_node_path.clone(),
+		});
+	}
+
+	pub fn leave_side(&mut self) {
+		self.sides.pop();
+	}
+
+	pub fn check_feature(&mut self, pair: &UUIDDescriptionPair, leaf_path: &NodePath) {
+		self.report.features_checked += 1;
+		if self.report.uuids.insert(pair.get_uuid()) == false {
+			self.report.duplicate_uuids.push(pair.get_uuid());
+			self.report.duplicate_pairs.push(pair.clone());
+		}
+
+		// radius belongs to far
+		let mut is_misplaced = false;
+		for side in self.sides.iter() {
+			let distance = self
+				.config
+				.metric
+				.distance(pair.get_description(), &side.vantage);
+			if (distance < side.radius) != side.is_near {
+				is_misplaced = true;
+				self.report.misplaced_features.push(MisplacedFeature {
+					uuid: pair.get_uuid(),
+					leaf_path: leaf_path.to_path_string(),
+					internal_node_path: side.internal_node_path.to_path_string(),
+					is_near: side.is_near,
+					distance: distance,
+					radius: side.radius,
+				});
+			}
+		}
+		if is_misplaced {
+			self.report.misplaced_pairs.push(pair.clone());
+		}
+	}
+
+	// Returns whether the file should be read. Only the root may be missing,
+	// because an empty tree has not written it yet.
+	pub fn enter_file(&mut self, path_in_tree: &NodePath) -> bool {
+		self.report.files_checked += 1;
+		self.reached_files.insert(path_in_tree.clone());
+
+		let file_path = path_in_tree.to_file_path_string(&self.config);
+		if std::path::Path::new(&file_path).exists() == false {
+			if path_in_tree != &get_root_path() {
+				self.report.missing_files.push(file_path);
+			}
+			return false;
+		}
+		return true;
+	}
+
+	pub fn file_failed(&mut self, error: TreeError) {
+		let file = match &error {
+			TreeError::Io { file, .. } => file.clone(),
+			TreeError::Corrupt { file, .. } => file.clone(),
+			TreeError::IncompatibleFormat { file, .. } => file.clone(),
+		};
+		self.report.unreadable_files.push(UnreadableFile {
+			file: file,
+			error: error.to_string(),
+		});
+	}
+
+	pub fn finish(mut self) -> Result<TreeReport, TreeError> {
+		let reached_files = &self.reached_files;
+		let mut orphaned_files = NodePath::get_all_in_database(&self.config)?
+			.into_iter()
+			.filter(|path| reached_files.contains(path) == false)
+			.collect::<Vec<NodePath>>();
+		orphaned_files.sort_by_key(|path| path.to_path_string());
+		self.report.orphaned_files = orphaned_files;
+
+		let mut orphaned_temp_files = NodePath::get_temp_files_in_database(&self.config)?;
+		let staging_folder_path = self
+			.config
+			.path_in_folder(crate::constants::STAGING_FOLDER_NAME);
+		if std::path::Path::new(&staging_folder_path).exists() {
+			orphaned_temp_files.push(staging_folder_path);
+		}
+		orphaned_temp_files.sort();
+		self.report.orphaned_temp_files = orphaned_temp_files;
+		return Ok(self.report);
+	}
+}
+
+impl TreeReport {
+	// Only safe when every file the tree points to could be read, otherwise
+	// the files below an unreadable one look orphaned as well
+	pub fn remove_orphaned_files(&self, config: &DatabaseConfig) -> Result<(), TreeError> {
+		for path_in_tree in self.orphaned_files.iter() {
+			FileNode::delete_file(path_in_tree, config)?;
+		}
+		for file_path in self.orphaned_temp_files.iter() {
+			let path = std::path::Path::new(file_path);
+			let result = match path.is_dir() {
+				true => std::fs::remove_dir_all(path),
+				false => std::fs::remove_file(path),
+			};
+			result.map_err(|error| TreeError::Io {
+				action: "Removing temporary file",
+				file: file_path.clone(),
+				error: error,
+			})?;
+		}
+		return Ok(());
+	}
+}
+
+// Replaces the tree with one whose root splits at a radius of 0, so every
+// feature in `near` is misplaced, and leaves a file that no node points to
+// and the temporary file of a save that was interrupted
+#[cfg(test)]
+pub(crate) fn write_damaged_tree(
+	near: Vec<UUIDDescriptionPair>,
+	far: Vec<UUIDDescriptionPair>,
+	config: &DatabaseConfig,
+) {
+	use crate::feature_tree::internal_node::InternalNode;
+	use crate::feature_tree::leaf_node::LeafNode;
+
+	let root = InternalNode::new(
+		FeatureDescription::random_edge(),
+		0,
+		LeafNode::new(near),
+		LeafNode::new(far),
+	);
+	FileNode::write_contents(&get_root_path(), &root, config).unwrap();
+
+	let mut orphan_path = get_root_path();
+	for _ in 0..3 {
+		orphan_path.add_direction(crate::constants::FAR_KEY);
+	}
+	orphan_path.add_direction(crate::constants::FILE_KEY);
+	FileNode::write_contents(&orphan_path, &LeafNode::new(vec![]), config).unwrap();
+
+	let temp_path =
+		get_root_path().to_file_path_string(config) + crate::constants::TEMP_FILE_EXTENSION;
+	std::fs::write(temp_path, b"cut short").unwrap();
+}
+
+// Flips a bit of the root file, so its checksum no longer matches
+#[cfg(test)]
+pub(crate) fn damage_root_file(config: &DatabaseConfig) {
+	let root_path = get_root_path().to_file_path_string(config);
+	let mut binary = std::fs::read(&root_path).unwrap();
+	let last = binary.len() - 1;
+	binary[last] ^= 1;
+	std::fs::write(&root_path, binary).unwrap();
+	crate::feature_tree::node_cache::invalidate(&root_path);
+}
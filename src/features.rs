@@ -46,6 +46,26 @@ pub mod feature_description {
 				.unwrap() as u32;
 		}
 
+		// ORB descriptors are 256 bit binary strings, so the number of
+		// differing bits is the distance OpenCV itself uses to match them.
+		pub fn hamming_distance(&self, other: &FeatureDescription) -> u32 {
+			return self
+				.data
+				.iter()
+				.zip(other.data.iter())
+				.map(|(&x, &y)| (x ^ y).count_ones())
+				.sum();
+		}
+
+		pub fn manhattan_distance(&self, other: &FeatureDescription) -> u32 {
+			return self
+				.data
+				.iter()
+				.zip(other.data.iter())
+				.map(|(&x, &y)| (x as i32 - y as i32).abs() as u32)
+				.sum();
+		}
+
 		#[allow(dead_code)]
 		pub fn random() -> FeatureDescription {
 			let mut data = empty_data();
@@ -93,6 +113,298 @@ pub mod feature_description {
 	}
 }
 
+pub mod distance_metric {
+	use crate::features::feature_description::FeatureDescription;
+
+	// Anything that can measure how far apart two descriptions are. The tree
+	// only ever compares the numbers a metric returns, so any distance can be
+	// used as long as the same one is used for the whole database.
+	pub trait Metric {
+		fn distance(&self, first: &FeatureDescription, second: &FeatureDescription) -> u32;
+		fn default_radius(&self) -> u32;
+
+		// Converts a distance into one that obeys the triangle inequality.
+		// Branches of the tree may only be skipped using these values.
+		fn to_true_metric(&self, distance: u32) -> f64 {
+			return distance as f64;
+		}
+
+		// How far a bound built from `to_true_metric` values may be off
+		// because of rounding.
+		fn tolerance(&self) -> f64 {
+			return 0.0;
+		}
+	}
+
+	pub struct Hamming;
+	pub struct Euclidean;
+	pub struct SquaredEuclidean;
+	pub struct Manhattan;
+
+	impl Metric for Hamming {
+		fn distance(&self, first: &FeatureDescription, second: &FeatureDescription) -> u32 {
+			return first.hamming_distance(second);
+		}
+
+		fn default_radius(&self) -> u32 {
+			return crate::constants::AVERAGE_EDGE_HAMMING_DISTANCE;
+		}
+	}
+
+	impl Metric for Euclidean {
+		fn distance(&self, first: &FeatureDescription, second: &FeatureDescription) -> u32 {
+			return (first.distance(second) as f64).sqrt().round() as u32;
+		}
+
+		fn default_radius(&self) -> u32 {
+			return (crate::constants::AVERAGE_EDGE_FEATURE_DISTANCE as f64)
+				.sqrt()
+				.round() as u32;
+		}
+
+		// The vantage distance, the radius and the worst result are each
+		// rounded by up to 0.5
+		fn tolerance(&self) -> f64 {
+			return 1.5;
+		}
+	}
+
+	impl Metric for SquaredEuclidean {
+		fn distance(&self, first: &FeatureDescription, second: &FeatureDescription) -> u32 {
+			return first.distance(second);
+		}
+
+		fn default_radius(&self) -> u32 {
+			return crate::constants::AVERAGE_EDGE_FEATURE_DISTANCE;
+		}
+
+		// Squared distances break the triangle inequality, their roots do not
+		fn to_true_metric(&self, distance: u32) -> f64 {
+			return (distance as f64).sqrt();
+		}
+
+		fn tolerance(&self) -> f64 {
+			return 1e-6;
+		}
+	}
+
+	impl Metric for Manhattan {
+		fn distance(&self, first: &FeatureDescription, second: &FeatureDescription) -> u32 {
+			return first.manhattan_distance(second);
+		}
+
+		fn default_radius(&self) -> u32 {
+			return crate::constants::AVERAGE_EDGE_MANHATTAN_DISTANCE;
+		}
+	}
+
+	// Which metric a database was built with. The radii stored inside of
+	// the tree only make sense for the metric that created them, so this is
+	// recorded once per database and never changed afterwards.
+	#[derive(Clone, Copy, Debug, PartialEq)]
+	pub enum DistanceMetric {
+		Hamming,
+		Euclidean,
+		SquaredEuclidean,
+		Manhattan,
+	}
+
+	pub const METRIC_NAMES: [&str; 4] = ["hamming", "euclidean", "squared_euclidean", "manhattan"];
+
+	impl DistanceMetric {
+		pub fn to_name(&self) -> &'static str {
+			return match self {
+				DistanceMetric::Hamming => "hamming",
+				DistanceMetric::Euclidean => "euclidean",
+				DistanceMetric::SquaredEuclidean => "squared_euclidean",
+				DistanceMetric::Manhattan => "manhattan",
+			};
+		}
+
+		// The number VP tree files record the metric with. These must never
+		// change, unlike the order of METRIC_NAMES.
+		pub fn to_id(&self) -> u32 {
+			return match self {
+				DistanceMetric::Hamming => 0,
+				DistanceMetric::Euclidean => 1,
+				DistanceMetric::SquaredEuclidean => 2,
+				DistanceMetric::Manhattan => 3,
+			};
+		}
+
+		pub fn from_id(id: u32) -> Option<DistanceMetric> {
+			return match id {
+				0 => Some(DistanceMetric::Hamming),
+				1 => Some(DistanceMetric::Euclidean),
+				2 => Some(DistanceMetric::SquaredEuclidean),
+				3 => Some(DistanceMetric::Manhattan),
+				_ => None,
+			};
+		}
+
+		pub fn from_name(name: &str) -> Option<DistanceMetric> {
+			return match name {
+				"hamming" => Some(DistanceMetric::Hamming),
+				"euclidean" => Some(DistanceMetric::Euclidean),
+				"squared_euclidean" => Some(DistanceMetric::SquaredEuclidean),
+				"manhattan" => Some(DistanceMetric::Manhattan),
+				_ => None,
+			};
+		}
+	}
+
+	impl Metric for DistanceMetric {
+		fn distance(&self, first: &FeatureDescription, second: &FeatureDescription) -> u32 {
+			return match self {
+				DistanceMetric::Hamming => Hamming.distance(first, second),
+				DistanceMetric::Euclidean => Euclidean.distance(first, second),
+				DistanceMetric::SquaredEuclidean => SquaredEuclidean.distance(first, second),
+				DistanceMetric::Manhattan => Manhattan.distance(first, second),
+			};
+		}
+
+		fn default_radius(&self) -> u32 {
+			return match self {
+				DistanceMetric::Hamming => Hamming.default_radius(),
+				DistanceMetric::Euclidean => Euclidean.default_radius(),
+				DistanceMetric::SquaredEuclidean => SquaredEuclidean.default_radius(),
+				DistanceMetric::Manhattan => Manhattan.default_radius(),
+			};
+		}
+
+		fn to_true_metric(&self, distance: u32) -> f64 {
+			return match self {
+				DistanceMetric::Hamming => Hamming.to_true_metric(distance),
+				DistanceMetric::Euclidean => Euclidean.to_true_metric(distance),
+				DistanceMetric::SquaredEuclidean => SquaredEuclidean.to_true_metric(distance),
+				DistanceMetric::Manhattan => Manhattan.to_true_metric(distance),
+			};
+		}
+
+		fn tolerance(&self) -> f64 {
+			return match self {
+				DistanceMetric::Hamming => Hamming.tolerance(),
+				DistanceMetric::Euclidean => Euclidean.tolerance(),
+				DistanceMetric::SquaredEuclidean => SquaredEuclidean.tolerance(),
+				DistanceMetric::Manhattan => Manhattan.tolerance(),
+			};
+		}
+	}
+
+	#[cfg(test)]
+	mod tests {
+		use super::*;
+
+		const ALL_METRICS: [DistanceMetric; 4] = [
+			DistanceMetric::Hamming,
+			DistanceMetric::Euclidean,
+			DistanceMetric::SquaredEuclidean,
+			DistanceMetric::Manhattan,
+		];
+
+		fn description(first_bytes: &[u8]) -> FeatureDescription {
+			let mut data = [0; crate::constants::FEATURE_DESCRIPTION_LENGTH];
+			data[..first_bytes.len()].copy_from_slice(first_bytes);
+			return FeatureDescription::new(data);
+		}
+
+		#[test]
+		fn distances_of_known_descriptions() {
+			let first = description(&[0b1111_0000, 3, 10]);
+			let second = description(&[0b0000_0000, 0, 6]);
+
+			// 4 bits + 2 bits + 2 bits
+			assert_eq!(DistanceMetric::Hamming.distance(&first, &second), 8);
+			// 240 + 3 + 4
+			assert_eq!(DistanceMetric::Manhattan.distance(&first, &second), 247);
+			// 240^2 + 3^2 + 4^2
+			assert_eq!(
+				DistanceMetric::SquaredEuclidean.distance(&first, &second),
+				57_625
+			);
+			// The root of 57625 is 240.05
+			assert_eq!(DistanceMetric::Euclidean.distance(&first, &second), 240);
+		}
+
+		#[test]
+		fn distances_are_symmetric_and_zero_only_to_itself() {
+			let descriptions = FeatureDescription::seeded_random(1)
+				.take(20)
+				.collect::<Vec<_>>();
+			for metric in ALL_METRICS.iter() {
+				for first in descriptions.iter() {
+					assert_eq!(metric.distance(first, first), 0);
+					for second in descriptions.iter() {
+						assert_eq!(
+							metric.distance(first, second),
+							metric.distance(second, first)
+						);
+					}
+				}
+			}
+			assert_ne!(
+				DistanceMetric::Hamming.distance(&descriptions[0], &descriptions[1]),
+				0
+			);
+		}
+
+		// Pruning is only exact when the true metric values obey the triangle
+		// inequality up to the tolerance of the metric
+		#[test]
+		fn true_metric_obeys_the_triangle_inequality() {
+			let descriptions = FeatureDescription::seeded_random(2)
+				.take(12)
+				.collect::<Vec<_>>();
+			for metric in ALL_METRICS.iter() {
+				let true_distance = |first: &FeatureDescription, second: &FeatureDescription| {
+					return metric.to_true_metric(metric.distance(first, second));
+				};
+				for a in descriptions.iter() {
+					for b in descriptions.iter() {
+						for c in descriptions.iter() {
+							assert!(
+								true_distance(a, c)
+									<= true_distance(a, b)
+										+ true_distance(b, c) + metric.tolerance(),
+								"{} breaks the triangle inequality",
+								metric.to_name()
+							);
+						}
+					}
+				}
+			}
+		}
+
+		#[test]
+		fn squared_euclidean_is_made_a_metric_by_its_root() {
+			assert_eq!(DistanceMetric::SquaredEuclidean.to_true_metric(144), 12.0);
+			assert_eq!(DistanceMetric::Euclidean.to_true_metric(144), 144.0);
+		}
+
+		#[test]
+		fn names_and_ids_round_trip() {
+			for metric in ALL_METRICS.iter() {
+				assert_eq!(DistanceMetric::from_name(metric.to_name()), Some(*metric));
+				assert_eq!(DistanceMetric::from_id(metric.to_id()), Some(*metric));
+			}
+			for name in METRIC_NAMES.iter() {
+				assert!(DistanceMetric::from_name(name).is_some());
+			}
+			assert_eq!(DistanceMetric::from_name("cosine"), None);
+			assert_eq!(DistanceMetric::from_id(4), None);
+		}
+
+		// Files record these ids, so they must never change
+		#[test]
+		fn ids_are_stable() {
+			assert_eq!(DistanceMetric::Hamming.to_id(), 0);
+			assert_eq!(DistanceMetric::Euclidean.to_id(), 1);
+			assert_eq!(DistanceMetric::SquaredEuclidean.to_id(), 2);
+			assert_eq!(DistanceMetric::Manhattan.to_id(), 3);
+		}
+	}
+}
+
 pub mod uuid_description_pair {
 	use crate::features::feature_description::FeatureDescription;
 	use std::convert::TryInto;
@@ -129,4 +441,23 @@ pub mod uuid_description_pair {
 			return UUIDDescriptionPair::new(uuid, description);
 		}
 	}
+
+	#[cfg(test)]
+	impl UUIDDescriptionPair {
+		// `count` random features with consecutive uuids, the same ones for
+		// the same seed
+		pub(crate) fn seeded_random(
+			seed: u64,
+			first_uuid: u64,
+			count: usize,
+		) -> Vec<UUIDDescriptionPair> {
+			return FeatureDescription::seeded_random(seed)
+				.take(count)
+				.enumerate()
+				.map(|(i, description)| {
+					UUIDDescriptionPair::new(first_uuid + i as u64, description)
+				})
+				.collect();
+		}
+	}
 }
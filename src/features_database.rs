@@ -1,12 +1,26 @@
+use crate::database_config::DatabaseConfig;
+use crate::error::Error;
+use crate::feature_tree::bulk_builder::get_root_path;
+use crate::feature_tree::compaction::Compaction;
+use crate::feature_tree::compaction::CompactionCounts;
 use crate::feature_tree::node::Node;
 use crate::feature_tree::node::TreeNode;
 use crate::feature_tree::node_path::NodePath;
-use crate::feature_tree::search_result::SearchResult;
+use crate::feature_tree::search_result::SearchOptions;
 use crate::feature_tree::search_result::SearchResultList;
+use crate::feature_tree::staging;
+use crate::feature_tree::tree_error::TreeError;
+use crate::feature_tree::verify::TreeReport;
+use crate::feature_tree::verify::TreeVerifier;
 use crate::features::feature_description::FeatureDescription;
 use crate::features::uuid_description_pair::UUIDDescriptionPair;
 
-pub fn insert_description_vec_into_database(description_vec: Vec<UUIDDescriptionPair>) {
+use std::collections::HashSet;
+
+pub fn insert_description_vec_into_database(
+	description_vec: Vec<UUIDDescriptionPair>,
+	config: &DatabaseConfig,
+) -> Result<(), TreeError> {
 	let total = description_vec.len();
 
 	let mut root_node = Node::get_root_node();
@@ -14,19 +28,108 @@ pub fn insert_description_vec_into_database(description_vec: Vec<UUIDDescription
 		if counter % 1000000 == 0 {
 			println!("Adding node {} out of {}", counter, total);
 		}
-		root_node.add(pair, NodePath::new_empty());
+		root_node.add(pair, NodePath::new_empty(), config)?;
+	}
+	// Saved here rather than when dropped, so a failed write is returned
+	if let Node::File(root_file) = &mut root_node {
+		root_file.save_if_changed()?;
 	}
+	return Ok(());
+}
+
+// Rebuilds the whole tree from the features already in it plus `new_pairs`.
+// Everything is held in memory while the tree is rebuilt. The new tree is
+// staged, so the old one stays whole until the new one is complete. Returns
+// how many features the new tree holds.
+pub fn bulk_insert_description_vec_into_database(
+	new_pairs: Vec<UUIDDescriptionPair>,
+	config: &DatabaseConfig,
+) -> Result<usize, Error> {
+	let mut all_pairs = vec![];
+	Node::get_root_node().collect_features(&mut all_pairs, config)?;
+	all_pairs.extend(new_pairs);
+	let size = all_pairs.len();
+
+	let staged = staging::begin(config)?;
+	let result =
+		crate::feature_tree::bulk_builder::remove_files_below(&NodePath::new_empty(), &staged)
+			.and_then(|_| crate::feature_tree::bulk_builder::build_tree(all_pairs, &staged));
+	staging::finish(result, &staged)?;
+	return Ok(size);
+}
+
+// Staged like a bulk insert, as subtrees are rebuilt in place. Returns how
+// many features the tree holds and how many subtrees were rebuilt.
+pub fn rebalance_database(config: &DatabaseConfig) -> Result<(u64, u64), Error> {
+	let staged = staging::begin(config)?;
+	let result = Node::get_root_node().rebalance(NodePath::new_empty(), &staged);
+	return staging::finish(result, &staged);
+}
+
+// Staged like a bulk insert, as files are merged and split in place
+pub fn compact_database(config: &DatabaseConfig) -> Result<CompactionCounts, Error> {
+	let staged = staging::begin(config)?;
+	let mut compaction = Compaction::new(&staged);
+	let result = Node::get_root_node().compact(NodePath::new_empty(), &mut compaction);
+	staging::finish(result, &staged)?;
+	return Ok(compaction.counts);
 }
 
 pub fn find_feature_description_in_database(
 	to_find: FeatureDescription,
-	number_of_neighbors: usize,
-) -> (u64, Vec<SearchResult>) {
-	let mut results = SearchResultList::new(number_of_neighbors, to_find);
-	Node::get_root_node().find(&mut results);
-	return (results.get_comparisons(), results.get_results());
+	options: SearchOptions,
+	config: &DatabaseConfig,
+) -> Result<SearchResultList, TreeError> {
+	let mut results = SearchResultList::new(options, to_find, config);
+	if config.memory_mapped_search {
+		let root_path = get_root_path();
+		if crate::constants::BEST_FIRST_SEARCH {
+			crate::feature_tree::node_view::find_best_first(&root_path, &mut results)?;
+		} else {
+			crate::feature_tree::node_view::find(&root_path, &mut results)?;
+		}
+	} else if crate::constants::BEST_FIRST_SEARCH {
+		crate::feature_tree::best_first::find_best_first(&Node::get_root_node(), &mut results)?;
+	} else {
+		Node::get_root_node().find(&mut results)?;
+	}
+	return Ok(results);
+}
+
+pub fn find_feature_descriptions_in_database(
+	to_find: Vec<FeatureDescription>,
+	options: SearchOptions,
+	config: &DatabaseConfig,
+) -> Result<Vec<SearchResultList>, TreeError> {
+	let mut batch = to_find
+		.into_iter()
+		.map(|description| SearchResultList::new(options, description, config))
+		.collect::<Vec<SearchResultList>>();
+	if config.memory_mapped_search {
+		crate::feature_tree::node_view::find_batch(&get_root_path(), &mut batch)?;
+	} else {
+		let active = (0..batch.len()).collect::<Vec<usize>>();
+		Node::get_root_node().find_batch(&mut batch, &active)?;
+	}
+	return Ok(batch);
+}
+
+pub fn remove_uuids_from_database(
+	to_remove: &HashSet<u64>,
+	config: &DatabaseConfig,
+) -> Result<u64, TreeError> {
+	if to_remove.is_empty() {
+		return Ok(0);
+	}
+	return Node::get_root_node().remove(to_remove, config);
+}
+
+pub fn verify_database(config: &DatabaseConfig) -> Result<TreeReport, TreeError> {
+	let mut verifier = TreeVerifier::new(config);
+	Node::get_root_node().verify(NodePath::new_empty(), &mut verifier);
+	return verifier.finish();
 }
 
-pub fn print_path(path: String) {
-	Node::get_file_as_root(path).print(0);
+pub fn print_path(path: String, config: &DatabaseConfig) -> Result<(), Error> {
+	return Ok(Node::get_file_as_root(path)?.print(0, config)?);
 }
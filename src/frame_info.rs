@@ -1,3 +1,5 @@
+use crate::error::Error;
+
 use std::path::Path;
 
 pub struct FrameInfo {
@@ -7,19 +9,30 @@ pub struct FrameInfo {
 }
 
 impl FrameInfo {
-	pub fn new_from_static_image_path(file_path: &str) -> FrameInfo {
-		let file_name = Path::new(file_path).file_name().unwrap().to_str().unwrap();
+	// Images are named after their md5, like md5.png
+	pub fn new_from_static_image_path(file_path: &str) -> Result<FrameInfo, Error> {
+		let file_name = Path::new(file_path)
+			.file_name()
+			.and_then(|name| name.to_str())
+			.unwrap_or("");
 		let splits = file_name.split(".").collect::<Vec<_>>();
 
-		let md5 = String::from(*splits.get(0).unwrap());
-		let file_ext = String::from(*splits.get(1).unwrap());
+		let (md5, file_ext) = match splits[..] {
+			[md5, file_ext, ..] if md5.is_empty() == false => (md5, file_ext),
+			_ => {
+				return Err(Error::InvalidInput(format!(
+					"Image {} is not named like md5.extension",
+					file_path
+				)))
+			}
+		};
 		let frame_id = 0;
 
-		return FrameInfo {
-			md5: md5,
-			ext: file_ext,
+		return Ok(FrameInfo {
+			md5: String::from(md5),
+			ext: String::from(file_ext),
 			index: frame_id,
-		};
+		});
 	}
 
 	pub fn new(md5: String, ext: String, index: u64) -> FrameInfo {
@@ -0,0 +1,290 @@
+use crate::database_config::DatabaseConfig;
+use crate::error::Error;
+use crate::features::uuid_description_pair::UUIDDescriptionPair;
+
+use crate::features_database;
+use crate::metadata_database;
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+
+// An insert writes to SQLite and to the VP tree separately, so a crash or a
+// failure between the two would leave features in only one of them. Before
+// either is touched, the features of the insert are written to a log that is
+// only removed once both are done. If the log is still there the next time the
+// program starts, the insert is finished or undone depending on whether the
+// SQLite transaction was committed.
+//
+// The tree is only walked to take the features out again when it may have
+// been changed, which is recorded by a marker file next to the log.
+//
+// Every pair in the log is stored the same way as in a leaf node.
+
+// What replay did with an interrupted insert, by its number of features
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayedInsert {
+	Finished(usize),
+	Undone(usize),
+}
+
+pub fn write(pairs: &[UUIDDescriptionPair], config: &DatabaseConfig) -> Result<(), Error> {
+	let mut data = vec![];
+	for pair in pairs {
+		data.append(&mut pair.to_binary());
+	}
+
+	// Written under another name first, so a log that exists is always complete
+	let log_path = config.path_in_folder(crate::constants::INSERT_LOG_FILE_NAME);
+	let temp_path = log_path.clone() + crate::constants::TEMP_FILE_EXTENSION;
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(&temp_path)
+		.map_err(|error| Error::io(String::from("Opening a temporary insert log failed"), error))?;
+	file.write_all(&data)
+		.map_err(|error| Error::io(String::from("Writing a temporary insert log failed"), error))?;
+	file.sync_all()
+		.map_err(|error| Error::io(String::from("Syncing a temporary insert log failed"), error))?;
+
+	// A marker left behind by a clear that was interrupted belongs to the
+	// insert before this one
+	remove_tree_marker(config)?;
+	std::fs::rename(&temp_path, &log_path)
+		.map_err(|error| Error::io(String::from("Replacing the insert log failed"), error))?;
+	return sync_database_folder(config);
+}
+
+// Called before the features of the logged insert are added to the tree
+pub fn mark_tree_started(config: &DatabaseConfig) -> Result<(), Error> {
+	OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(config.path_in_folder(crate::constants::INSERT_LOG_TREE_MARKER_FILE_NAME))
+		.map_err(|error| Error::io(String::from("Creating the insert log marker failed"), error))?;
+	return sync_database_folder(config);
+}
+
+pub fn clear(config: &DatabaseConfig) -> Result<(), Error> {
+	std::fs::remove_file(config.path_in_folder(crate::constants::INSERT_LOG_FILE_NAME))
+		.map_err(|error| Error::io(String::from("Removing the insert log failed"), error))?;
+	remove_tree_marker(config)?;
+	return sync_database_folder(config);
+}
+
+// Whether an insert was interrupted and has not been replayed yet
+pub fn is_pending(config: &DatabaseConfig) -> bool {
+	return std::path::Path::new(&config.path_in_folder(crate::constants::INSERT_LOG_FILE_NAME))
+		.exists();
+}
+
+fn has_tree_marker(config: &DatabaseConfig) -> bool {
+	return std::path::Path::new(
+		&config.path_in_folder(crate::constants::INSERT_LOG_TREE_MARKER_FILE_NAME),
+	)
+	.exists();
+}
+
+fn remove_tree_marker(config: &DatabaseConfig) -> Result<(), Error> {
+	return match std::fs::remove_file(
+		config.path_in_folder(crate::constants::INSERT_LOG_TREE_MARKER_FILE_NAME),
+	) {
+		Ok(()) => Ok(()),
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+		Err(error) => Err(Error::io(
+			String::from("Removing the insert log marker failed"),
+			error,
+		)),
+	};
+}
+
+fn read(config: &DatabaseConfig) -> Result<Option<Vec<UUIDDescriptionPair>>, Error> {
+	let log_path = config.path_in_folder(crate::constants::INSERT_LOG_FILE_NAME);
+	let mut data = vec![];
+	match OpenOptions::new().read(true).open(&log_path) {
+		Ok(mut file) => file
+			.read_to_end(&mut data)
+			.map_err(|error| Error::io(String::from("Reading the insert log failed"), error))?,
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+		Err(error) => {
+			return Err(Error::io(
+				String::from("Opening the insert log failed"),
+				error,
+			))
+		}
+	};
+
+	if data.len() % crate::constants::UUID_DESCRIPTION_PAIR_LENGTH != 0 {
+		return Err(Error::Corrupt(format!(
+			"insert log {} is {} bytes long, which is not a whole number of features",
+			log_path,
+			data.len()
+		)));
+	}
+
+	return Ok(Some(
+		data.chunks_exact(crate::constants::UUID_DESCRIPTION_PAIR_LENGTH)
+			.map(UUIDDescriptionPair::from_binary)
+			.collect(),
+	));
+}
+
+// Brings both databases back to having all of an interrupted insert or none of
+// it. If the tree was changed the features of the insert are taken out of it
+// first, because it may hold any part of them.
+pub fn replay(config: &DatabaseConfig) -> Result<Option<ReplayedInsert>, Error> {
+	let pairs = match read(config)? {
+		Some(pairs) => pairs,
+		None => return Ok(None),
+	};
+
+	// The SQLite insert is a single transaction, so any one uuid tells whether
+	// all of them were committed
+	let is_in_metadata = match pairs.first() {
+		Some(pair) => metadata_database::contains_uuid(pair.get_uuid(), config)?,
+		None => false,
+	};
+
+	if has_tree_marker(config) {
+		let uuids = pairs
+			.iter()
+			.map(|pair| pair.get_uuid())
+			.collect::<HashSet<u64>>();
+		features_database::remove_uuids_from_database(&uuids, config)?;
+	}
+
+	let replayed = match is_in_metadata {
+		true => ReplayedInsert::Finished(pairs.len()),
+		false => ReplayedInsert::Undone(pairs.len()),
+	};
+	if is_in_metadata {
+		mark_tree_started(config)?;
+		features_database::insert_description_vec_into_database(pairs, config)?;
+	}
+
+	clear(config)?;
+	return Ok(Some(replayed));
+}
+
+fn sync_database_folder(config: &DatabaseConfig) -> Result<(), Error> {
+	return config
+		.sync_database_folder()
+		.map_err(|error| Error::io(String::from("Syncing the database folder failed"), error));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pairs(first_uuid: u64, count: usize) -> Vec<UUIDDescriptionPair> {
+		return UUIDDescriptionPair::seeded_random(first_uuid, first_uuid, count);
+	}
+
+	fn uuids_in_tree(config: &DatabaseConfig) -> Vec<u64> {
+		let report = features_database::verify_database(config).unwrap();
+		assert!(report.duplicate_uuids.is_empty());
+		let mut uuids = report.uuids.into_iter().collect::<Vec<u64>>();
+		uuids.sort_unstable();
+		return uuids;
+	}
+
+	// What the SQLite side of an insert would have committed
+	fn commit_metadata(pairs: &[UUIDDescriptionPair], config: &DatabaseConfig) {
+		let connection = rusqlite::Connection::open(&config.sqlite_database_path).unwrap();
+		connection
+			.execute(
+				"INSERT OR IGNORE INTO files (file_uuid) VALUES (1)",
+				rusqlite::params![],
+			)
+			.unwrap();
+		for pair in pairs {
+			connection
+				.execute(
+					"INSERT INTO metadata (uuid, file_uuid) VALUES (?1, 1)",
+					rusqlite::params![pair.get_uuid()],
+				)
+				.unwrap();
+		}
+	}
+
+	fn is_cleared(config: &DatabaseConfig) -> bool {
+		return is_pending(config) == false && has_tree_marker(config) == false;
+	}
+
+	#[test]
+	fn log_round_trips() {
+		let config = DatabaseConfig::new_test_database("insert_log_round_trip");
+		let written = pairs(1, 10);
+		write(&written, &config).unwrap();
+		let logged = read(&config).unwrap().unwrap();
+		assert_eq!(
+			logged.iter().map(|e| e.to_binary()).collect::<Vec<_>>(),
+			written.iter().map(|e| e.to_binary()).collect::<Vec<_>>()
+		);
+
+		clear(&config).unwrap();
+		assert!(read(&config).unwrap().is_none());
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn replay_undoes_an_insert_without_metadata() {
+		let config = DatabaseConfig::new_test_database("insert_log_undo");
+		let kept = pairs(1, 20);
+		features_database::insert_description_vec_into_database(kept.clone(), &config).unwrap();
+
+		// Interrupted after the tree got every feature, but before SQLite
+		// committed
+		let interrupted = pairs(100, 20);
+		write(&interrupted, &config).unwrap();
+		mark_tree_started(&config).unwrap();
+		features_database::insert_description_vec_into_database(interrupted, &config).unwrap();
+
+		assert_eq!(replay(&config).unwrap(), Some(ReplayedInsert::Undone(20)));
+		assert_eq!(uuids_in_tree(&config), (1..21).collect::<Vec<u64>>());
+		assert!(is_cleared(&config));
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn replay_finishes_an_insert_with_metadata() {
+		let config = DatabaseConfig::new_test_database("insert_log_finish");
+		let kept = pairs(1, 20);
+		features_database::insert_description_vec_into_database(kept, &config).unwrap();
+
+		// Interrupted after SQLite committed and part of the features
+		// reached the tree
+		let interrupted = pairs(100, 20);
+		write(&interrupted, &config).unwrap();
+		commit_metadata(&interrupted, &config);
+		mark_tree_started(&config).unwrap();
+		features_database::insert_description_vec_into_database(interrupted[..7].to_vec(), &config)
+			.unwrap();
+
+		assert_eq!(replay(&config).unwrap(), Some(ReplayedInsert::Finished(20)));
+		let expected = (1..21).chain(100..120).collect::<Vec<u64>>();
+		assert_eq!(uuids_in_tree(&config), expected);
+		assert!(is_cleared(&config));
+
+		// Replaying again changes nothing
+		assert_eq!(replay(&config).unwrap(), None);
+		assert_eq!(uuids_in_tree(&config), expected);
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn replay_without_marker_leaves_the_tree_alone() {
+		let config = DatabaseConfig::new_test_database("insert_log_untouched");
+		features_database::insert_description_vec_into_database(pairs(1, 20), &config).unwrap();
+
+		// The log holds uuids that are in the tree, which replay would remove
+		// if it walked the tree
+		write(&pairs(1, 5), &config).unwrap();
+		replay(&config).unwrap();
+		assert_eq!(uuids_in_tree(&config), (1..21).collect::<Vec<u64>>());
+		assert!(is_cleared(&config));
+		config.remove_test_database();
+	}
+}
@@ -0,0 +1,58 @@
+// The image database as a library. A Database is opened from a
+// RequestedConfig and everything else is done through its methods, the
+// modules that are public only hold the types those methods take and return.
+
+pub mod constants;
+pub mod database;
+pub mod database_config;
+pub mod error;
+pub mod features;
+pub mod frame_info;
+
+pub mod extract_from_image;
+
+mod add;
+mod features_database;
+mod insert_log;
+mod metadata_database;
+mod remove;
+mod search;
+mod verify;
+mod feature_tree {
+	pub mod best_first;
+	pub mod bulk_builder;
+	pub mod compaction;
+	mod file_header;
+	mod file_node;
+	mod internal_node;
+	mod leaf_node;
+	pub mod node;
+	mod node_cache;
+	pub mod node_path;
+	pub mod node_view;
+	pub mod search_result;
+	pub mod staging;
+	pub mod tree_error;
+	pub mod vantage;
+	pub mod verify;
+}
+
+mod python_binary;
+
+pub use crate::database::Database;
+pub use crate::database::DatabaseStats;
+pub use crate::database::Recovery;
+pub use crate::database_config::DatabaseConfig;
+pub use crate::database_config::RequestedConfig;
+pub use crate::error::Error;
+pub use crate::feature_tree::compaction::CompactionCounts;
+pub use crate::feature_tree::search_result::SearchBudget;
+pub use crate::feature_tree::search_result::SearchOptions;
+pub use crate::feature_tree::search_result::SearchResult;
+pub use crate::feature_tree::tree_error::TreeError;
+pub use crate::insert_log::ReplayedInsert;
+pub use crate::metadata_database::FileSelector;
+pub use crate::metadata_database::KeypointMetadata;
+pub use crate::search::CountedSearchResult;
+pub use crate::verify::DatabaseReport;
+pub use crate::verify::RepairReport;
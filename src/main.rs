@@ -1,34 +1,30 @@
-mod constants;
-mod features;
-mod frame_info;
-
-mod extract_from_image;
-
-mod features_database;
-mod metadata_database;
-mod feature_tree {
-	mod file_node;
-	mod internal_node;
-	mod leaf_node;
-	pub mod node;
-	pub mod node_path;
-	pub mod search_result;
-}
-
-mod python_binary;
+mod network;
 
 #[macro_use]
 extern crate rocket;
 use clap::App;
 use clap::Arg;
 
+use feature_database::constants;
+use feature_database::Database;
+use feature_database::Error;
+use feature_database::FileSelector;
+use feature_database::Recovery;
+use feature_database::ReplayedInsert;
+use feature_database::RequestedConfig;
+use feature_database::SearchBudget;
+use feature_database::SearchOptions;
+
+use rocket::serde::json::serde_json;
+use rocket::serde::json::Value;
+
 #[rocket::main]
 async fn main() {
-	let matches = App::new(crate::constants::APP_NAME)
-		.version(crate::constants::VERSION)
-		.author(crate::constants::CONTACT_INFO)
-		.about(crate::constants::ABOUT)
-		.long_about(crate::constants::LONG_ABOUT)
+	let matches = App::new(constants::APP_NAME)
+		.version(constants::VERSION)
+		.author(constants::CONTACT_INFO)
+		.about(constants::ABOUT)
+		.long_about(constants::LONG_ABOUT)
 		.arg(
 			Arg::with_name("add_image")
 				.short("a")
@@ -50,18 +46,122 @@ async fn main() {
 				.takes_value(true)
 				.help("Positive integer for the maximum number of neighbors (only used when using -f)"),
 		)
+		.arg(
+			Arg::with_name("radius")
+				.long("radius")
+				.takes_value(true)
+				.help("Return every feature within this distance instead of the k nearest, -k caps the number of results (only used when using -f)"),
+		)
+		.arg(
+			Arg::with_name("max_comparisons")
+				.long("max_comparisons")
+				.takes_value(true)
+				.help("Stop each search after this many distance comparisons and return the best results so far (only used when using -f)"),
+		)
+		.arg(
+			Arg::with_name("max_leaf_visits")
+				.long("max_leaf_visits")
+				.takes_value(true)
+				.help("Stop each search after visiting this many leaf nodes (only used when using -f)"),
+		)
+		.arg(
+			Arg::with_name("max_file_loads")
+				.long("max_file_loads")
+				.takes_value(true)
+				.help("Stop each search after opening this many database files (only used when using -f)"),
+		)
+		.arg(
+			Arg::with_name("inexact_pruning")
+				.long("inexact_pruning")
+				.help("Skip branches using raw distances, faster but may miss neighbors with squared_euclidean (only used when using -f)"),
+		)
+		.arg(
+			Arg::with_name("remove_image")
+				.long("remove_image")
+				.takes_value(true)
+				.help("Md5 of an image whose features and metadata should be removed from the database"),
+		)
+		.arg(
+			Arg::with_name("remove_file_uuid")
+				.long("remove_file_uuid")
+				.takes_value(true)
+				.help("File uuid whose features and metadata should be removed from the database"),
+		)
 		.arg(
 			Arg::with_name("python_binary")
 				.long("python_binary")
 				.takes_value(true)
 				.help("Filepath to a binary file containing ORB output that was extracted with python long ago"),
 		)
+		.arg(
+			Arg::with_name("bulk_load")
+				.long("bulk_load")
+				.takes_value(true)
+				.help("Like --python_binary, but rebuilds the whole tree balanced from every feature at once"),
+		)
+		.arg(
+			Arg::with_name("rebuild")
+				.long("rebuild")
+				.help("Rebuilds the whole tree balanced from the features already in it"),
+		)
+		.arg(
+			Arg::with_name("rebalance")
+				.long("rebalance")
+				.help("Rebuilds only the subtrees whose near and far sides have grown too different in size"),
+		)
+		.arg(
+			Arg::with_name("compact")
+				.long("compact")
+				.help("Rewrites the files of the tree so each one holds about COMPACTION_TARGET_FILE_SIZE bytes"),
+		)
+		.arg(
+			Arg::with_name("verify")
+				.long("verify")
+				.help("Checks the tree and the metadata against each other and prints a JSON report of every problem found"),
+		)
+		.arg(
+			Arg::with_name("repair")
+				.long("repair")
+				.help("Fixes what --verify finds, except for files that can not be read"),
+		)
+		.arg(
+			Arg::with_name("stats")
+				.long("stats")
+				.help("Prints how many images and features the database holds and how large its tree is as JSON"),
+		)
 		.arg(
 			Arg::with_name("print")
 				.long("print")
 				.takes_value(true)
 				.help("Filepath to a binary file that should be printed"),
 		)
+		.arg(
+			Arg::with_name("metric")
+				.long("metric")
+				.takes_value(true)
+				.possible_values(&feature_database::features::distance_metric::METRIC_NAMES)
+				.help("Distance metric to build a new database with (existing databases keep theirs)"),
+		)
+		.arg(
+			Arg::with_name("config")
+				.long("config")
+				.takes_value(true)
+				.help("Filepath to a TOML config file, defaults to ./feature_database.toml if it exists"),
+		)
+		.arg(
+			Arg::with_name("set")
+				.long("set")
+				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1)
+				.help("Overrides a setting of the config file, given as name=value"),
+		)
+		.arg(
+			Arg::with_name("collection")
+				.long("collection")
+				.takes_value(true)
+				.help("Name of the collection to use instead of the main database, created when first used"),
+		)
 		.arg(
 			Arg::with_name("server")
 				.short("s")
@@ -70,7 +170,46 @@ async fn main() {
 		)
 		.get_matches();
 
-	metadata_database::initialize_database();
+	let config_file_path = matches
+		.value_of("config")
+		.unwrap_or(constants::CONFIG_FILE_PATH);
+	if matches.is_present("config") && std::path::Path::new(config_file_path).exists() == false {
+		eprintln!("Config file {} does not exist", config_file_path);
+		std::process::exit(1);
+	}
+	let overrides = matches
+		.values_of("set")
+		.map(|values| values.collect::<Vec<&str>>())
+		.unwrap_or_default();
+	// Settings that belong to a database only apply to the one that was asked
+	// for, not to the other collections a server opens
+	let load_requested = |collection: Option<&str>, is_selected: bool| {
+		let overrides = overrides
+			.iter()
+			.copied()
+			.filter(|pair| {
+				let name = pair.split('=').next().unwrap_or_default();
+				return is_selected || RequestedConfig::is_database_setting(name) == false;
+			})
+			.collect::<Vec<&str>>();
+		let mut requested = exit_on_error(RequestedConfig::load(
+			config_file_path,
+			collection,
+			&overrides,
+		));
+		if let Some(metric) = matches.value_of("metric").filter(|_| is_selected) {
+			requested.metric = Some(String::from(metric));
+		}
+		return requested;
+	};
+	// --verify and --repair look at the database as it was left, so nothing
+	// is changed when it is opened
+	let requested = load_requested(matches.value_of("collection"), true);
+	let database = match matches.occurrences_of("verify") + matches.occurrences_of("repair") > 0 {
+		true => exit_on_error(Database::open_read_only(&requested)),
+		false => exit_on_error(Database::open(&requested)),
+	};
+	print_recovery(database.get_recovery());
 
 	// TODO threaded insert where features are found
 	// on threads and then inserts are done on a single thread
@@ -78,338 +217,232 @@ async fn main() {
 	if matches.value_of("add_image").is_some() {
 		let image_path = matches.value_of("add_image").unwrap();
 		println!("should add image {}", image_path);
-		add::add_image_to_database(image_path);
+		exit_on_error(database.add_image(image_path));
 	} else if matches.value_of("find_image").is_some() {
 		let image_path = matches.value_of("find_image").unwrap();
-		let k = get_k_from_cli(matches.value_of("k_nearest_neighbors"));
-		search::rank_all_features_from_database(image_path, k);
+		let k = matches.value_of("k_nearest_neighbors");
+		let mut options = match matches.value_of("radius") {
+			Some(radius) => SearchOptions::new_range(
+				exit_on_error(parse_from_cli(radius, "Radius")),
+				k.map(|_| get_k_from_cli(k)),
+			),
+			None => SearchOptions::new(get_k_from_cli(k)),
+		};
+		options.exact_pruning = matches.occurrences_of("inexact_pruning") == 0;
+		options.budget = SearchBudget {
+			max_comparisons: exit_on_error(get_budget_from_cli(
+				matches.value_of("max_comparisons"),
+			)),
+			max_leaf_visits: exit_on_error(get_budget_from_cli(
+				matches.value_of("max_leaf_visits"),
+			)),
+			max_file_loads: exit_on_error(get_budget_from_cli(matches.value_of("max_file_loads"))),
+		};
+		exit_on_error(rank_all_features_from_database(
+			&database, image_path, options,
+		));
+	} else if matches.value_of("remove_image").is_some() {
+		let md5 = matches.value_of("remove_image").unwrap();
+		println!("should remove image {}", md5);
+		print_removed(exit_on_error(
+			database.remove(FileSelector::Md5(String::from(md5))),
+		));
+	} else if matches.value_of("remove_file_uuid").is_some() {
+		let file_uuid = exit_on_error(parse_from_cli(
+			matches.value_of("remove_file_uuid").unwrap(),
+			"File uuid to remove",
+		));
+		println!("should remove file uuid {}", file_uuid);
+		print_removed(exit_on_error(
+			database.remove(FileSelector::FileUUID(file_uuid)),
+		));
 	} else if matches.value_of("python_binary").is_some() {
 		let python_binary = matches.value_of("python_binary").unwrap();
 		println!("should merge binary {}", python_binary);
-		add::add_python_binary_to_database(python_binary);
+		exit_on_error(database.add_python_binary(python_binary));
+	} else if matches.value_of("bulk_load").is_some() {
+		let python_binary = matches.value_of("bulk_load").unwrap();
+		println!("should bulk load binary {}", python_binary);
+		exit_on_error(database.bulk_add_python_binary(python_binary));
+	} else if matches.occurrences_of("rebuild") > 0 {
+		println!("should rebuild tree");
+		let size = exit_on_error(database.rebuild());
+		println!("Rebuilt the tree from {} features", size);
+	} else if matches.occurrences_of("rebalance") > 0 {
+		println!("should rebalance tree");
+		let (size, rebuilt) = exit_on_error(database.rebalance());
+		println!(
+			"Rebuilt {} subtrees of a tree with {} features",
+			rebuilt, size
+		);
+	} else if matches.occurrences_of("compact") > 0 {
+		println!("should compact tree");
+		let counts = exit_on_error(database.compact());
+		println!(
+			"Rewrote {} files, merged {} files into others and split off {} new files",
+			counts.files_rewritten, counts.files_merged, counts.files_split
+		);
+	} else if matches.occurrences_of("verify") > 0 {
+		let report = exit_on_error(database.verify());
+		print_json(&report.to_json());
+		if report.is_consistent() == false {
+			std::process::exit(1);
+		}
+	} else if matches.occurrences_of("repair") > 0 {
+		let report = exit_on_error(database.repair());
+		if report.is_repaired == false {
+			eprintln!("Restore the unreadable and missing files before repairing the database");
+		}
+		print_json(&report.to_json());
+		if report.is_repaired == false {
+			std::process::exit(1);
+		}
+	} else if matches.occurrences_of("stats") > 0 {
+		let stats = exit_on_error(database.stats());
+		print_json(&serde_json::to_value(stats).expect("Serializing the stats failed"));
 	} else if matches.value_of("print").is_some() {
 		let print_path = matches.value_of("print").unwrap();
 		println!("should print {}", print_path);
-		features_database::print_path(String::from(print_path));
+		exit_on_error(database.print_file(String::from(print_path)));
 	} else if matches.occurrences_of("server") > 0 {
-		network::start().await.unwrap();
+		// Collections created while the server runs are only served after a
+		// restart
+		let collections = exit_on_error(load_requested(None, false).get_collection_names())
+			.into_iter()
+			.map(|name| {
+				let is_selected = matches.value_of("collection") == Some(name.as_str());
+				let collection =
+					exit_on_error(Database::open(&load_requested(Some(&name), is_selected)));
+				print_recovery(collection.get_recovery());
+				(name, collection)
+			})
+			.collect();
+		exit_on_error(network::start(database, collections).await);
 	} else {
 		println!("doing nothing");
 	}
 }
 
-fn get_k_from_cli(k: Option<&str>) -> usize {
-	if k.is_some() {
-		let k = k.unwrap().parse();
-		if k.is_ok() {
-			return crate::constants::MAX_K_VALUE.min(1.max(k.unwrap()));
-		}
-	}
-
-	return crate::constants::DEFAULT_K;
-}
-
-mod search {
-	use crate::feature_tree::search_result::SearchResult;
-	use crate::features::feature_description::FeatureDescription;
-	use crate::metadata_database::KeypointMetadata;
-
-	pub fn rank_all_features_from_database(file_path: &str, number_of_neighbors: usize) {
-		let image_features = crate::extract_from_image::get_features_from_image_path(file_path)
+fn rank_all_features_from_database(
+	database: &Database,
+	file_path: &str,
+	options: SearchOptions,
+) -> Result<(), Error> {
+	let image_features =
+		feature_database::extract_from_image::get_features_from_image_path(file_path)?
 			.into_iter()
 			.map(|e| e.description)
 			.collect();
-		for i in &image_features {
-			println!("{:?}", i);
-		}
-
-		let results = search_for_all_descriptions(image_features, number_of_neighbors);
-		for (id, (comparisons, search_results)) in results.into_iter().enumerate() {
-			println!(
-				"{:>5} Found {:>6} results in {:>13} comparisons",
-				id,
-				search_results.len(),
-				comparisons
-			);
-
-			println!("input  rank distance                              md5 file-ext frame file-uuid          uuid        x        y   size  angle      response octave");
-
-			for (counter, (result, metadata)) in search_results.into_iter().enumerate() {
-				println!("{:>5} {:>5} {:>8} {:>32} {:>8} {:>5} {:>9} {:>13} {:>8.2} {:>8.2} {:>6.2} {:>6.2} {:>13.10} {:>6}",
-					id,
-					counter,
-					result.get_distance(),
-					metadata.md5,
-					metadata.file_ext,
-					metadata.frame_id,
-					metadata.file_uuid,
-					metadata.uuid,
-					metadata.x,
-					metadata.y,
-					metadata.size,
-					metadata.angle,
-					metadata.response,
-					metadata.octave,
-				);
-			}
-		}
+	for i in &image_features {
+		println!("{:?}", i);
 	}
 
-	type CountedSearchResult = (u64, Vec<(SearchResult, KeypointMetadata)>);
-
-	pub fn search_for_all_descriptions(
-		descriptions: Vec<FeatureDescription>,
-		number_of_neighbors: usize,
-	) -> Vec<CountedSearchResult> {
-		let mut results = vec![];
-
-		let mut threads = vec![];
-		for description in descriptions {
-			if crate::constants::THREADED_SEARCH {
-				threads.push(std::thread::spawn(move || {
-					search_for_description(description, number_of_neighbors)
-				}));
-			} else {
-				results.push(search_for_description(description, number_of_neighbors));
-			}
-		}
-
-		if crate::constants::THREADED_SEARCH {
-			results = threads
-				.into_iter()
-				.map(|e| e.join().unwrap())
-				.collect::<Vec<CountedSearchResult>>();
-		}
-
-		return results;
-	}
-
-	pub fn search_for_description(
-		description: FeatureDescription,
-		number_of_neighbors: usize,
-	) -> CountedSearchResult {
-		let (comparisons, results) = crate::features_database::find_feature_description_in_database(
-			description,
-			number_of_neighbors,
+	let results = database.search(image_features, options)?;
+	for (id, counted_results) in results.into_iter().enumerate() {
+		let inexact_note = if counted_results.is_exact {
+			""
+		} else {
+			" (inexact)"
+		};
+		let truncated_note = if counted_results.is_truncated {
+			" (truncated)"
+		} else {
+			""
+		};
+		let search_results = counted_results.results;
+		println!(
+			"{:>5} Found {:>6} results in {:>13} comparisons{}{}",
+			id,
+			search_results.len(),
+			counted_results.comparisons,
+			inexact_note,
+			truncated_note
 		);
 
-		let metadata_list: Vec<KeypointMetadata> = results
-			.iter()
-			.map(|e| crate::metadata_database::find_metadata_from_uuid(e.get_result_uuid()))
-			.collect();
-
-		let pairs = results.into_iter().zip(metadata_list.into_iter()).collect();
+		println!("input  rank distance                              md5 file-ext frame file-uuid          uuid        x        y   size  angle      response octave");
 
-		return (comparisons, pairs);
+		for (counter, (result, metadata)) in search_results.into_iter().enumerate() {
+			println!("{:>5} {:>5} {:>8} {:>32} {:>8} {:>5} {:>9} {:>13} {:>8.2} {:>8.2} {:>6.2} {:>6.2} {:>13.10} {:>6}",
+				id,
+				counter,
+				result.get_distance(),
+				metadata.md5,
+				metadata.file_ext,
+				metadata.frame_id,
+				metadata.file_uuid,
+				metadata.uuid,
+				metadata.x,
+				metadata.y,
+				metadata.size,
+				metadata.angle,
+				metadata.response,
+				metadata.octave,
+			);
+		}
 	}
+	return Ok(());
 }
 
-mod add {
-	use crate::extract_from_image;
-	use crate::extract_from_image::PointOfInterest;
-	use crate::features::uuid_description_pair::UUIDDescriptionPair;
-	use crate::frame_info::FrameInfo;
-
-	use crate::features_database;
-	use crate::metadata_database;
-
-	use crate::python_binary;
-
-	pub fn add_image_to_database(file_path: &str) {
-		let image_features = extract_from_image::get_features_from_image_path(file_path);
-		insert_metadata_and_description_to_database(assign_uuids_to_list(vec![(
-			FrameInfo::new_from_static_image_path(file_path),
-			image_features,
-		)]));
-	}
+fn print_removed((features_removed, files_removed): (u64, usize)) {
+	println!(
+		"Removed {} files and {} features",
+		files_removed, features_removed
+	);
+}
 
-	pub fn add_python_binary_to_database(file_path: &str) {
-		let files = python_binary::parse_python_binary(file_path);
-		let files = assign_uuids_to_list(files);
-		insert_metadata_and_description_to_database(files);
+fn print_recovery(recovery: Recovery) {
+	if recovery.finished_tree_change {
+		println!("Finished an interrupted change to the tree");
 	}
-
-	fn insert_metadata_and_description_to_database(list: FeaturesWithUUID) {
-		let (metadata_list, description_pairs) = list;
-
-		if crate::constants::THREADED_INSERT {
-			let sqlite_handle = std::thread::spawn(|| {
-				metadata_database::insert_meta_data_pair_vec_to_database(metadata_list)
-			});
-			let vp_tree_handle = std::thread::spawn(|| {
-				features_database::insert_description_vec_into_database(description_pairs)
-			});
-
-			sqlite_handle.join().unwrap();
-			vp_tree_handle.join().unwrap();
-		} else {
-			metadata_database::insert_meta_data_pair_vec_to_database(metadata_list);
-			features_database::insert_description_vec_into_database(description_pairs);
+	match recovery.replayed_insert {
+		Some(ReplayedInsert::Finished(count)) => {
+			println!("Finished an interrupted insert of {} features", count)
 		}
-	}
-
-	type FrameMetaDataPair = (FrameInfo, Vec<(u64, opencv::core::KeyPoint)>);
-	type FeaturesWithUUID = (Vec<FrameMetaDataPair>, Vec<UUIDDescriptionPair>);
-	fn assign_uuids_to_list(list: Vec<(FrameInfo, Vec<PointOfInterest>)>) -> FeaturesWithUUID {
-		let mut uuid_iterator = (metadata_database::get_max_uuid() + 1)..;
-		let mut metadata_frame_list = vec![];
-		let mut all_descriptions = vec![];
-
-		for (frame, poi_list) in list {
-			// Only include static images currently
-			if frame.get_id() != 0 {
-				continue;
-			}
-
-			let mut metadata_vec = vec![];
-			for poi in poi_list {
-				let uuid = uuid_iterator.next().unwrap();
-				metadata_vec.push((uuid, poi.metadata));
-				all_descriptions.push(UUIDDescriptionPair::new(uuid, poi.description));
-			}
-			metadata_frame_list.push((frame, metadata_vec));
+		Some(ReplayedInsert::Undone(count)) => {
+			println!("Undid an interrupted insert of {} features", count)
 		}
-
-		return (metadata_frame_list, all_descriptions);
+		None => {}
 	}
 }
 
-mod network {
-	use crate::features::feature_description::FeatureDescription;
-	use crate::metadata_database::KeypointMetadata;
-
-	use rocket::http::ContentType;
-	use rocket::response::content;
-	use rocket::serde::json::json;
-	use rocket::serde::json::Json;
-	use rocket::serde::Deserialize;
-	use rocket::serde::Serialize;
-	use rocket::State;
-	use std::fs;
-	use std::sync::Arc;
-	use std::sync::Mutex;
-	use std::thread;
-
-	#[derive(Deserialize)]
-	#[serde(crate = "rocket::serde")]
-	struct Message {
-		open_cv_results: Vec<Descriptor>,
-		k: u8,
-	}
-
-	#[derive(Deserialize)]
-	#[serde(crate = "rocket::serde")]
-	#[allow(dead_code)]
-	struct Descriptor {
-		angle: f32,
-		class_id: i32,
-		descriptor: Vec<u8>,
-		octave: u8,
-		response: f32,
-		size: f32,
-		x: f32,
-		y: f32,
-	}
-
-	#[derive(Serialize)]
-	#[serde(crate = "rocket::serde")]
-	struct SearchPair {
-		distance: u32,
-		rank: usize,
-		metadata: KeypointMetadata,
-	}
-
-	#[derive(Serialize)]
-	#[serde(crate = "rocket::serde")]
-	struct VectorResult {
-		results: Vec<SearchPair>,
-		id: usize,
-		comparisons: u64,
-	}
-
-	struct IsProgramSearching {
-		value: Arc<Mutex<u8>>,
-	}
-
-	pub async fn start() -> Result<(), rocket::Error> {
-		let config = IsProgramSearching {
-			value: Arc::new(Mutex::new(0)),
-		};
-
-		return rocket::build()
-			.mount("/", routes![get_index, get_opencv, get_favicon, get_image_results])
-			.manage(config)
-			.register("/", catchers![not_found])
-			.launch()
-			.await;
+fn get_k_from_cli(k: Option<&str>) -> usize {
+	if k.is_some() {
+		let k = k.unwrap().parse();
+		if k.is_ok() {
+			return constants::MAX_K_VALUE.min(1.max(k.unwrap()));
+		}
 	}
 
-	#[post("/get_image_results.json", format = "json", data = "<message>")]
-	async fn get_image_results(
-		message: Json<Message>,
-		state: &State<IsProgramSearching>,
-	) -> content::Json<rocket::serde::json::Value> {
-		let descriptors = message
-			.open_cv_results
-			.iter()
-			.map(|e| FeatureDescription::new_from_vec(e.descriptor.clone()))
-			.collect();
-
-		// This is not clean or elegant, but under Kira's advisement, I am only
-		// letting one search be performed at a time. This is to make sure that
-		// attackers can not overload my system with attacks. It may provide a
-		// poor user experience if multiple images are trying to be searched at
-		// once and the program is CPU bound, but frankly I don't care.
-		// TODO make this pretty
-		let clone_arc = state.value.clone();
-		let results = thread::spawn(move || {
-			let mut mutex_data = clone_arc.lock().unwrap();
-			*mutex_data = 1;
-			let results =
-				crate::search::search_for_all_descriptions(descriptors, message.k as usize);
-			*mutex_data = 0;
-			results
-		})
-		.join()
-		.unwrap();
-
-		return content::Json(json!({
-			"results": results.into_iter().enumerate().map(|(id, (comparisons, list))| VectorResult {
-				id: id,
-				comparisons: comparisons,
-				results: list.into_iter().enumerate().map(|(rank, (search_results, metadata))| SearchPair {
-					rank: rank,
-					distance: search_results.get_distance(),
-					metadata: metadata
-				}).collect::<Vec<SearchPair>>(),
-			}).collect::<Vec<VectorResult>>()
-		}));
-	}
+	return constants::DEFAULT_K;
+}
 
-	#[get("/")]
-	fn get_index() -> content::Html<String> {
-		content::Html(
-			fs::read_to_string("./UI/index.html").expect("Error reading the index.html file"),
-		)
-	}
+fn get_budget_from_cli(limit: Option<&str>) -> Result<Option<u64>, Error> {
+	return limit
+		.map(|e| parse_from_cli(e, "A search budget"))
+		.transpose();
+}
 
-	#[get("/opencv.js")]
-	fn get_opencv() -> content::JavaScript<String> {
-		content::JavaScript(
-			fs::read_to_string("./UI/opencv.js").expect("Error reading the index.html file"),
-		)
-	}
+fn parse_from_cli<T: std::str::FromStr>(value: &str, name: &str) -> Result<T, Error> {
+	return value.parse().map_err(|_| {
+		Error::InvalidInput(format!(
+			"{} must be a positive integer, not {}",
+			name, value
+		))
+	});
+}
 
-	#[get("/favicon.ico")]
-	fn get_favicon() -> content::Custom<Vec<u8>> {
-		content::Custom(
-			ContentType::AVIF,
-			fs::read("./UI/favicon.ico").expect("Error reading the favicon file"),
-		)
-	}
+// The error already says what went wrong and where, so there is no need for
+// the backtrace of a panic
+fn exit_on_error<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+	return result.unwrap_or_else(|error| {
+		eprintln!("{}", error);
+		std::process::exit(1);
+	});
+}
 
-	#[catch(404)]
-	fn not_found() -> String {
-		return String::from("404");
-	}
+fn print_json(value: &Value) {
+	println!(
+		"{}",
+		serde_json::to_string_pretty(value).expect("Serializing the report failed")
+	);
 }
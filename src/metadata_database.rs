@@ -1,5 +1,10 @@
 // TODO this whole file is ugly and needs to be cleaned up
 
+use crate::database_config::DatabaseConfig;
+use crate::database_config::RequestedConfig;
+use crate::error::Error;
+use crate::feature_tree::vantage::VantageStrategy;
+use crate::features::distance_metric::DistanceMetric;
 use crate::frame_info::FrameInfo;
 
 use opencv::core::KeyPoint;
@@ -7,9 +12,11 @@ use rocket::serde::Serialize;
 use rusqlite::params;
 use rusqlite::Connection;
 use rusqlite::OpenFlags;
+use rusqlite::OptionalExtension;
 use rusqlite::Statement;
+use std::collections::BTreeSet;
 
-pub fn initialize_database() {
+pub fn initialize_database(config: &DatabaseConfig) -> Result<(), Error> {
 	const CREATE_TABLE_FILES_STRING: &str = "CREATE TABLE IF NOT EXISTS files (
 		file_uuid INTEGER PRIMARY KEY ON CONFLICT ABORT,
 		md5 TEXT,
@@ -30,78 +37,329 @@ pub fn initialize_database() {
 		CONSTRAINT references_file FOREIGN KEY (file_uuid) REFERENCES files
 	)";
 
-	let connection = open_sqlite_connection();
+	const CREATE_TABLE_SETTINGS_STRING: &str = "CREATE TABLE IF NOT EXISTS settings (
+		name TEXT PRIMARY KEY ON CONFLICT REPLACE,
+		value TEXT
+	)";
+
+	// The folder can be set in the config, so it may not exist yet
+	std::fs::create_dir_all(&config.database_folder_path).map_err(|error| {
+		Error::io(
+			format!(
+				"Creating database folder {} failed",
+				config.database_folder_path
+			),
+			error,
+		)
+	})?;
+	let connection = open_sqlite_connection(config)?;
 
 	let _num_rows_changed = connection
 		.execute(CREATE_TABLE_FILES_STRING, params![])
-		.expect("Creating database 'files' table failed");
+		.map_err(|error| Error::sqlite("Creating database 'files' table failed", error))?;
 
 	let _num_rows_changed = connection
 		.execute(CREATE_TABLE_METADATA_STRING, params![])
-		.expect("Creating database 'metadata' table failed");
+		.map_err(|error| Error::sqlite("Creating database 'metadata' table failed", error))?;
+
+	let _num_rows_changed = connection
+		.execute(CREATE_TABLE_SETTINGS_STRING, params![])
+		.map_err(|error| Error::sqlite("Creating database 'settings' table failed", error))?;
+
+	return close_sqlite_connection(connection);
+}
+
+// Fills in the settings that belong to the database from its 'settings'
+// table, recording the requested ones or the defaults if it has none yet. A
+// database that already has features but no recorded setting was built
+// before the setting was recorded, so it used the value this build used to
+// have as a constant: squared euclidean distance, the default split
+// parameters and the largest spread vantage strategy.
+pub fn apply_database_settings(
+	config: &mut DatabaseConfig,
+	requested: &RequestedConfig,
+) -> Result<(), Error> {
+	return load_database_settings(config, requested, true);
+}
+
+// Same as apply_database_settings, but settings that are not recorded yet
+// stay that way
+pub fn read_database_settings(
+	config: &mut DatabaseConfig,
+	requested: &RequestedConfig,
+) -> Result<(), Error> {
+	return load_database_settings(config, requested, false);
+}
+
+fn load_database_settings(
+	config: &mut DatabaseConfig,
+	requested: &RequestedConfig,
+	record: bool,
+) -> Result<(), Error> {
+	// Nothing is recorded for a database that was asked for with a setting
+	// it can not be built with
+	if requested.max_leaf_node_size == Some(0) {
+		return Err(Error::InvalidInput(String::from(
+			"max_leaf_node_size must be at least 1",
+		)));
+	}
+	if requested.max_file_node_depth == Some(0) {
+		return Err(Error::InvalidInput(String::from(
+			"max_file_node_depth must be at least 1",
+		)));
+	}
+
+	let metric = get_or_initialize_setting(
+		config,
+		"metric",
+		requested
+			.get_metric()?
+			.map(|metric| String::from(metric.to_name())),
+		DistanceMetric::SquaredEuclidean.to_name(),
+		crate::constants::DEFAULT_DISTANCE_METRIC.to_name(),
+		record,
+	)?;
+	config.metric = DistanceMetric::from_name(&metric).ok_or_else(|| {
+		Error::Corrupt(format!(
+			"'settings' table contains the unknown metric {}",
+			metric
+		))
+	})?;
+
+	config.max_leaf_node_size = get_or_initialize_setting(
+		config,
+		"max_leaf_node_size",
+		requested.max_leaf_node_size.map(|size| size.to_string()),
+		&crate::constants::DEFAULT_MAX_LEAF_NODE_SIZE.to_string(),
+		&crate::constants::DEFAULT_MAX_LEAF_NODE_SIZE.to_string(),
+		record,
+	)?
+	.parse()
+	.map_err(|_| {
+		Error::Corrupt(String::from(
+			"'settings' table contains a max_leaf_node_size that is not a number",
+		))
+	})?;
+
+	config.max_file_node_depth = get_or_initialize_setting(
+		config,
+		"max_file_node_depth",
+		requested.max_file_node_depth.map(|depth| depth.to_string()),
+		&crate::constants::DEFAULT_MAX_FILE_NODE_DEPTH.to_string(),
+		&crate::constants::DEFAULT_MAX_FILE_NODE_DEPTH.to_string(),
+		record,
+	)?
+	.parse()
+	.map_err(|_| {
+		Error::Corrupt(String::from(
+			"'settings' table contains a max_file_node_depth that is not a number",
+		))
+	})?;
+
+	let vantage_strategy = get_or_initialize_setting(
+		config,
+		"vantage_strategy",
+		requested
+			.get_vantage_strategy()?
+			.map(|strategy| String::from(strategy.to_name())),
+		VantageStrategy::LargestSpread.to_name(),
+		crate::constants::DEFAULT_VANTAGE_STRATEGY.to_name(),
+		record,
+	)?;
+	config.vantage_strategy = VantageStrategy::from_name(&vantage_strategy).ok_or_else(|| {
+		Error::Corrupt(format!(
+			"'settings' table contains the unknown vantage strategy {}",
+			vantage_strategy
+		))
+	})?;
+
+	if config.max_leaf_node_size == 0 || config.max_file_node_depth == 0 {
+		return Err(Error::Corrupt(String::from(
+			"'settings' table contains a split parameter of 0",
+		)));
+	}
+	return Ok(());
+}
+
+fn get_or_initialize_setting(
+	config: &DatabaseConfig,
+	name: &str,
+	requested: Option<String>,
+	legacy: &str,
+	default: &str,
+	record: bool,
+) -> Result<String, Error> {
+	let stored = get_setting(name, config)?;
+
+	let value = match stored.clone() {
+		Some(value) => value,
+		None if get_max_uuid(config)? != 0 => String::from(legacy),
+		None => requested.clone().unwrap_or_else(|| String::from(default)),
+	};
+
+	if let Some(requested) = requested {
+		if requested != value {
+			return Err(Error::InvalidInput(format!(
+				"Database was built with {} {} and can not be used with {}",
+				name, value, requested
+			)));
+		}
+	}
+
+	if stored.is_none() && record {
+		set_setting(name, &value, config)?;
+	}
 
-	close_sqlite_connection(connection);
+	return Ok(value);
 }
 
-pub fn get_max_uuid() -> u64 {
+fn get_setting(name: &str, config: &DatabaseConfig) -> Result<Option<String>, Error> {
+	let connection = open_sqlite_connection(config)?;
+
+	let value = connection
+		.query_row(
+			"SELECT value FROM settings WHERE name = ?1",
+			params![name],
+			|row| row.get(0),
+		)
+		.optional()
+		.map_err(|error| {
+			Error::sqlite(
+				"Getting a value from database table 'settings' failed",
+				error,
+			)
+		})?;
+
+	close_sqlite_connection(connection)?;
+	return Ok(value);
+}
+
+fn set_setting(name: &str, value: &str, config: &DatabaseConfig) -> Result<(), Error> {
+	let connection = open_sqlite_connection(config)?;
+
+	let _num_rows_changed = connection
+		.execute(
+			"INSERT INTO settings (name, value) VALUES (?1, ?2)",
+			params![name, value],
+		)
+		.map_err(|error| Error::sqlite("Inserting into database table 'settings' failed", error))?;
+
+	return close_sqlite_connection(connection);
+}
+
+pub fn get_max_uuid(config: &DatabaseConfig) -> Result<u64, Error> {
 	const SELECT_MAX_UUID_STRING: &str = "SELECT COALESCE(MAX(uuid), 0) FROM metadata";
 
-	let connection = open_sqlite_connection();
+	let connection = open_sqlite_connection(config)?;
 
 	let new_uuid = connection
 		.query_row(SELECT_MAX_UUID_STRING, params![], |row| row.get(0))
-		.expect("Getting a max uuid from database table 'metadata' failed");
+		.map_err(|error| {
+			Error::sqlite(
+				"Getting a max uuid from database table 'metadata' failed",
+				error,
+			)
+		})?;
+
+	close_sqlite_connection(connection)?;
+	return Ok(new_uuid);
+}
 
-	close_sqlite_connection(connection);
-	return new_uuid;
+// Returns how many files and how many features have metadata
+pub fn count_files_and_features(config: &DatabaseConfig) -> Result<(u64, u64), Error> {
+	const SELECT_COUNTS_STRING: &str =
+		"SELECT (SELECT COUNT(*) FROM files), (SELECT COUNT(*) FROM metadata)";
+
+	let connection = open_sqlite_connection(config)?;
+
+	let counts = connection
+		.query_row(SELECT_COUNTS_STRING, params![], |row| {
+			Ok((row.get(0)?, row.get(1)?))
+		})
+		.map_err(|error| {
+			Error::sqlite(
+				"Counting rows of database tables 'files' and 'metadata' failed",
+				error,
+			)
+		})?;
+
+	close_sqlite_connection(connection)?;
+	return Ok(counts);
+}
+
+pub fn contains_uuid(uuid: u64, config: &DatabaseConfig) -> Result<bool, Error> {
+	let connection = open_sqlite_connection(config)?;
+
+	let is_found = connection
+		.query_row(
+			"SELECT EXISTS (SELECT 1 FROM metadata WHERE uuid = ?1)",
+			params![uuid],
+			|row| row.get(0),
+		)
+		.map_err(|error| {
+			Error::sqlite(
+				"Looking up a uuid in database table 'metadata' failed",
+				error,
+			)
+		})?;
+
+	close_sqlite_connection(connection)?;
+	return Ok(is_found);
 }
 
 type FrameMetaDataPair = (FrameInfo, Vec<(u64, KeyPoint)>);
-pub fn insert_meta_data_pair_vec_to_database(list: Vec<FrameMetaDataPair>) {
-	let connection = open_sqlite_connection();
+pub fn insert_meta_data_pair_vec_to_database(
+	list: Vec<FrameMetaDataPair>,
+	config: &DatabaseConfig,
+) -> Result<(), Error> {
+	let connection = open_sqlite_connection(config)?;
 	connection
 		.execute_batch("BEGIN")
-		.expect("Starting transaction failed.");
+		.map_err(|error| Error::sqlite("Starting transaction failed", error))?;
 
 	let mut get_max_file_uuid_statement = connection
 		.prepare("SELECT COALESCE(MAX(file_uuid), 0) FROM files;")
-		.expect("Preparing statement to get max file_uuid failed.");
+		.map_err(|error| Error::sqlite("Preparing statement to get max file_uuid failed", error))?;
 	let mut insert_into_files_statement = connection
 		.prepare(
 			"INSERT INTO files 
 			(file_uuid, md5, file_ext, frame_id)
 			VALUES (?1, ?2, ?3, ?4);",
 		)
-		.expect("Preparing statement to insert into database table 'files' failed.");
+		.map_err(|error| {
+			Error::sqlite(
+				"Preparing statement to insert into database table 'files' failed",
+				error,
+			)
+		})?;
 	let mut insert_into_metadata_statement = connection
 		.prepare(
 			"INSERT INTO metadata
 			(uuid, file_uuid, x, y, size, angle, response, octave)
 			VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);",
 		)
-		.expect("Preparing statement to insert into database table 'metadata' failed.");
+		.map_err(|error| {
+			Error::sqlite(
+				"Preparing statement to insert into database table 'metadata' failed",
+				error,
+			)
+		})?;
 
 	let total = list.len();
 	for (counter, (frame, metadata_vec)) in list.into_iter().enumerate() {
-		let file_uuid = {
-			let result = insert_and_get_file_uuid(
-				&mut get_max_file_uuid_statement,
-				&mut insert_into_files_statement,
-				frame,
-			);
-			result.expect("Inserting into database table 'files' failed. It was likely due to a md5 duplication. VP-Tree database is likely corrupted/tainted if things were added with threads")
-			//	if result.is_err() {
-			//		continue
-			//	} else {
-			//		result.unwrap()
-			//	}
-		};
+		// Nothing from this insert is kept when a file fails, the transaction
+		// is rolled back when the connection is dropped
+		let file_uuid = insert_and_get_file_uuid(
+			&mut get_max_file_uuid_statement,
+			&mut insert_into_files_statement,
+			frame,
+		)?;
 
 		for (uuid, keypoint) in metadata_vec {
 			insert_metadata_info_into_database(
 				&mut insert_into_metadata_statement,
 				(uuid, file_uuid, keypoint),
-			);
+			)?;
 		}
 
 		if counter % 1000 == 0 {
@@ -114,18 +372,23 @@ pub fn insert_meta_data_pair_vec_to_database(list: Vec<FrameMetaDataPair>) {
 	std::mem::drop(insert_into_metadata_statement);
 	connection
 		.execute_batch("COMMIT;")
-		.expect("Committing transaction failed.");
-	close_sqlite_connection(connection);
+		.map_err(|error| Error::sqlite("Committing transaction failed", error))?;
+	return close_sqlite_connection(connection);
 }
 
 fn insert_and_get_file_uuid(
 	file_uuid_statement: &mut Statement,
 	insert_statement: &mut Statement,
 	frame: FrameInfo,
-) -> Result<u64, String> {
+) -> Result<u64, Error> {
 	let max_file_uuid: u64 = file_uuid_statement
 		.query_row(params![], |row| row.get(0))
-		.expect("Getting a max file_uuid from database table 'files' failed");
+		.map_err(|error| {
+			Error::sqlite(
+				"Getting a max file_uuid from database table 'files' failed",
+				error,
+			)
+		})?;
 	let max_file_uuid = max_file_uuid + 1;
 
 	let file_insert = insert_statement.execute(params![
@@ -135,16 +398,29 @@ fn insert_and_get_file_uuid(
 		frame.get_id()
 	]);
 
-	if file_insert.is_err() {
-		return Err(String::from(
-			"Inserting into database table 'files' failed. Likely md5 repeat, should skip",
-		));
-	} else {
-		return Ok(max_file_uuid);
-	}
+	return match file_insert {
+		Ok(_) => Ok(max_file_uuid),
+		Err(rusqlite::Error::SqliteFailure(error, _))
+			if error.code == rusqlite::ErrorCode::ConstraintViolation =>
+		{
+			Err(Error::InvalidInput(format!(
+				"Image {}.{} frame {} is already in the database",
+				frame.copy_md5(),
+				frame.copy_ext(),
+				frame.get_id()
+			)))
+		}
+		Err(error) => Err(Error::sqlite(
+			"Inserting into database table 'files' failed",
+			error,
+		)),
+	};
 }
 
-fn insert_metadata_info_into_database(statement: &mut Statement, info: (u64, u64, KeyPoint)) {
+fn insert_metadata_info_into_database(
+	statement: &mut Statement,
+	info: (u64, u64, KeyPoint),
+) -> Result<(), Error> {
 	statement
 		.execute(params![
 			info.0,
@@ -156,7 +432,8 @@ fn insert_metadata_info_into_database(statement: &mut Statement, info: (u64, u64
 			info.2.response,
 			info.2.octave
 		])
-		.expect("Failed inserting values into database");
+		.map_err(|error| Error::sqlite("Inserting into database table 'metadata' failed", error))?;
+	return Ok(());
 }
 
 #[allow(dead_code)]
@@ -179,8 +456,11 @@ pub struct KeypointMetadata {
 	pub octave: u8,
 }
 
-pub fn find_metadata_from_uuid(uuid_to_find: u64) -> KeypointMetadata {
-	let connection = open_sqlite_connection();
+pub fn find_metadata_from_uuid(
+	uuid_to_find: u64,
+	config: &DatabaseConfig,
+) -> Result<KeypointMetadata, Error> {
+	let connection = open_sqlite_connection(config)?;
 
 	let matching_row = connection
 		.query_row(
@@ -188,10 +468,15 @@ pub fn find_metadata_from_uuid(uuid_to_find: u64) -> KeypointMetadata {
 			params![uuid_to_find],
 			|row| row_to_keypoint_metadata(row),
 		)
-		.expect("Getting a max file_uuid from database table 'files' failed");
+		.map_err(|error| {
+			Error::sqlite(
+				"Getting metadata from database table 'metadata' failed",
+				error,
+			)
+		})?;
 
-	close_sqlite_connection(connection);
-	return matching_row;
+	close_sqlite_connection(connection)?;
+	return Ok(matching_row);
 
 	fn row_to_keypoint_metadata(row: &rusqlite::Row) -> Result<KeypointMetadata, rusqlite::Error> {
 		return Ok(KeypointMetadata {
@@ -211,14 +496,314 @@ pub fn find_metadata_from_uuid(uuid_to_find: u64) -> KeypointMetadata {
 	}
 }
 
-fn open_sqlite_connection() -> Connection {
+pub enum FileSelector {
+	Md5(String),
+	FileUUID(u64),
+}
+
+impl FileSelector {
+	fn to_where_clause(&self) -> (&'static str, rusqlite::types::Value) {
+		return match self {
+			FileSelector::Md5(md5) => ("md5 = ?1", rusqlite::types::Value::Text(md5.clone())),
+			FileSelector::FileUUID(file_uuid) => (
+				"file_uuid = ?1",
+				rusqlite::types::Value::Integer(*file_uuid as i64),
+			),
+		};
+	}
+}
+
+pub fn find_uuids_from_file(
+	selector: &FileSelector,
+	config: &DatabaseConfig,
+) -> Result<Vec<u64>, Error> {
+	let (where_clause, value) = selector.to_where_clause();
+	let connection = open_sqlite_connection(config)?;
+
+	let mut statement = connection
+		.prepare(&format!(
+			"SELECT uuid FROM metadata WHERE file_uuid IN (SELECT file_uuid FROM files WHERE {})",
+			where_clause
+		))
+		.map_err(|error| {
+			Error::sqlite("Preparing statement to find uuids of a file failed", error)
+		})?;
+	let uuids = statement
+		.query_map(params![value], |row| row.get(0))
+		.map_err(|error| {
+			Error::sqlite("Getting uuids from database table 'metadata' failed", error)
+		})?
+		.collect::<Result<Vec<u64>, rusqlite::Error>>()
+		.map_err(|error| {
+			Error::sqlite("Reading uuids from database table 'metadata' failed", error)
+		})?;
+
+	std::mem::drop(statement);
+	close_sqlite_connection(connection)?;
+	return Ok(uuids);
+}
+
+pub fn get_all_uuids(config: &DatabaseConfig) -> Result<Vec<u64>, Error> {
+	let connection = open_sqlite_connection(config)?;
+
+	let mut statement = connection
+		.prepare("SELECT uuid FROM metadata")
+		.map_err(|error| Error::sqlite("Preparing statement to get every uuid failed", error))?;
+	let uuids = statement
+		.query_map(params![], |row| row.get(0))
+		.map_err(|error| {
+			Error::sqlite("Getting uuids from database table 'metadata' failed", error)
+		})?
+		.collect::<Result<Vec<u64>, rusqlite::Error>>()
+		.map_err(|error| {
+			Error::sqlite("Reading uuids from database table 'metadata' failed", error)
+		})?;
+
+	std::mem::drop(statement);
+	close_sqlite_connection(connection)?;
+	return Ok(uuids);
+}
+
+// Returns how many rows were removed from the 'files' table
+pub fn remove_files_from_database(
+	selector: &FileSelector,
+	config: &DatabaseConfig,
+) -> Result<usize, Error> {
+	let (where_clause, value) = selector.to_where_clause();
+	let connection = open_sqlite_connection(config)?;
+	connection
+		.execute_batch("BEGIN")
+		.map_err(|error| Error::sqlite("Starting transaction failed", error))?;
+
+	let _num_rows_changed = connection
+		.execute(
+			&format!(
+				"DELETE FROM metadata WHERE file_uuid IN (SELECT file_uuid FROM files WHERE {})",
+				where_clause
+			),
+			params![value],
+		)
+		.map_err(|error| Error::sqlite("Removing from database table 'metadata' failed", error))?;
+	let files_removed = connection
+		.execute(
+			&format!("DELETE FROM files WHERE {}", where_clause),
+			params![value],
+		)
+		.map_err(|error| Error::sqlite("Removing from database table 'files' failed", error))?;
+
+	connection
+		.execute_batch("COMMIT;")
+		.map_err(|error| Error::sqlite("Committing transaction failed", error))?;
+	close_sqlite_connection(connection)?;
+	return Ok(files_removed);
+}
+
+// Files whose last feature is removed are removed as well, in the same
+// transaction. Returns how many rows were removed from the 'metadata' and
+// the 'files' table.
+pub fn remove_uuids_from_metadata(
+	uuids: &[u64],
+	config: &DatabaseConfig,
+) -> Result<(usize, usize), Error> {
+	let connection = open_sqlite_connection(config)?;
+	connection
+		.execute_batch("BEGIN")
+		.map_err(|error| Error::sqlite("Starting transaction failed", error))?;
+
+	let mut file_uuid_statement = connection
+		.prepare("SELECT file_uuid FROM metadata WHERE uuid = ?1")
+		.map_err(|error| {
+			Error::sqlite(
+				"Preparing statement to get a file uuid from database table 'metadata' failed",
+				error,
+			)
+		})?;
+	let mut metadata_statement = connection
+		.prepare("DELETE FROM metadata WHERE uuid = ?1")
+		.map_err(|error| {
+			Error::sqlite(
+				"Preparing statement to remove from database table 'metadata' failed",
+				error,
+			)
+		})?;
+	let mut file_uuids = BTreeSet::new();
+	let mut rows_removed = 0;
+	for uuid in uuids {
+		let file_uuid: Option<u64> = file_uuid_statement
+			.query_row(params![uuid], |row| row.get(0))
+			.optional()
+			.map_err(|error| {
+				Error::sqlite(
+					"Getting a file uuid from database table 'metadata' failed",
+					error,
+				)
+			})?;
+		file_uuids.extend(file_uuid);
+		rows_removed += metadata_statement.execute(params![uuid]).map_err(|error| {
+			Error::sqlite("Removing from database table 'metadata' failed", error)
+		})?;
+	}
+
+	let mut files_statement = connection
+		.prepare(
+			"DELETE FROM files WHERE file_uuid = ?1
+			AND NOT EXISTS (SELECT 1 FROM metadata WHERE file_uuid = ?1)",
+		)
+		.map_err(|error| {
+			Error::sqlite(
+				"Preparing statement to remove from database table 'files' failed",
+				error,
+			)
+		})?;
+	let mut files_removed = 0;
+	for file_uuid in file_uuids {
+		files_removed += files_statement
+			.execute(params![file_uuid])
+			.map_err(|error| Error::sqlite("Removing from database table 'files' failed", error))?;
+	}
+
+	std::mem::drop(file_uuid_statement);
+	std::mem::drop(metadata_statement);
+	std::mem::drop(files_statement);
+	connection
+		.execute_batch("COMMIT;")
+		.map_err(|error| Error::sqlite("Committing transaction failed", error))?;
+	close_sqlite_connection(connection)?;
+	return Ok((rows_removed, files_removed));
+}
+
+// Records the settings of `config` that the database does not have yet, see
+// read_database_settings
+pub fn record_database_settings(config: &DatabaseConfig) -> Result<(), Error> {
+	let settings = [
+		("metric", String::from(config.metric.to_name())),
+		("max_leaf_node_size", config.max_leaf_node_size.to_string()),
+		(
+			"max_file_node_depth",
+			config.max_file_node_depth.to_string(),
+		),
+		(
+			"vantage_strategy",
+			String::from(config.vantage_strategy.to_name()),
+		),
+	];
+	for (name, value) in settings.iter() {
+		if get_setting(name, config)?.is_none() {
+			set_setting(name, value, config)?;
+		}
+	}
+	return Ok(());
+}
+
+fn open_sqlite_connection(config: &DatabaseConfig) -> Result<Connection, Error> {
 	return Connection::open_with_flags(
-		crate::constants::SQLITE_DATABASE_PATH,
+		&config.sqlite_database_path,
 		OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
 	)
-	.expect("Creating database file failed");
+	.map_err(|error| Error::sqlite("Opening database file failed", error));
+}
+
+fn close_sqlite_connection(connection: Connection) -> Result<(), Error> {
+	return connection
+		.close()
+		.map_err(|(_, error)| Error::sqlite("Closing database file failed", error));
 }
 
-fn close_sqlite_connection(connection: Connection) {
-	connection.close().expect("Closing database file failed");
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database::Database;
+	use crate::extract_from_image::PointOfInterest;
+
+	fn open(requested: &RequestedConfig) -> Result<DatabaseConfig, Error> {
+		return Database::open(requested).map(|database| database.get_config().clone());
+	}
+
+	#[test]
+	fn databases_keep_the_settings_they_were_built_with() {
+		let mut requested = RequestedConfig::for_test_database("metadata_settings");
+		requested.metric = Some(String::from("hamming"));
+		requested.vantage_strategy = Some(String::from("farthest_point"));
+		let config = open(&requested).unwrap();
+
+		// Left out, they are whatever the database has
+		let mut left_out = requested.clone();
+		left_out.metric = None;
+		left_out.max_leaf_node_size = None;
+		left_out.max_file_node_depth = None;
+		left_out.vantage_strategy = None;
+		let reopened = open(&left_out).unwrap();
+		assert_eq!(reopened.metric, DistanceMetric::Hamming);
+		assert_eq!(reopened.max_leaf_node_size, 4);
+		assert_eq!(reopened.max_file_node_depth, 2);
+		assert_eq!(reopened.vantage_strategy, VantageStrategy::FarthestPoint);
+		assert!(open(&requested).is_ok());
+
+		let mut other_metric = requested.clone();
+		other_metric.metric = Some(String::from("euclidean"));
+		let mut other_leaf_size = requested.clone();
+		other_leaf_size.max_leaf_node_size = Some(5);
+		let mut other_depth = requested.clone();
+		other_depth.max_file_node_depth = Some(3);
+		let mut other_strategy = requested.clone();
+		other_strategy.vantage_strategy = Some(String::from("random_edge"));
+		for mismatched in [other_metric, other_leaf_size, other_depth, other_strategy] {
+			assert!(open(&mismatched).unwrap_err().is_invalid_input());
+		}
+		assert!(open(&left_out).is_ok());
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn settings_that_can_not_be_built_with_are_not_recorded() {
+		let requested = RequestedConfig::for_test_database("metadata_bad_settings");
+		let mut zero_leaf_size = requested.clone();
+		zero_leaf_size.max_leaf_node_size = Some(0);
+		let mut zero_depth = requested.clone();
+		zero_depth.max_file_node_depth = Some(0);
+		let mut unknown_metric = requested.clone();
+		unknown_metric.metric = Some(String::from("cosine"));
+		let mut unknown_strategy = requested.clone();
+		unknown_strategy.vantage_strategy = Some(String::from("best"));
+		for bad in [zero_leaf_size, zero_depth, unknown_metric, unknown_strategy] {
+			assert!(open(&bad).unwrap_err().is_invalid_input());
+		}
+
+		let config = open(&requested).unwrap();
+		assert_eq!(config.max_leaf_node_size, 4);
+		assert_eq!(config.metric, crate::constants::DEFAULT_DISTANCE_METRIC);
+		config.remove_test_database();
+	}
+
+	// Databases with features from before settings were recorded were built
+	// with the values that used to be constants
+	#[test]
+	fn old_databases_get_the_settings_they_were_built_with() {
+		let mut requested = RequestedConfig::for_test_database("metadata_old_settings");
+		requested.max_leaf_node_size = None;
+		requested.max_file_node_depth = None;
+		let database = Database::open(&requested).unwrap();
+		let frame = FrameInfo::new(String::from("old"), String::from("png"), 0);
+		database
+			.add_features(frame, PointOfInterest::seeded_random(81, 5))
+			.unwrap();
+		let config = database.get_config().clone();
+		let connection = open_sqlite_connection(&config).unwrap();
+		connection
+			.execute("DELETE FROM settings", params![])
+			.unwrap();
+		close_sqlite_connection(connection).unwrap();
+
+		let reopened = open(&requested).unwrap();
+		assert_eq!(reopened.metric, DistanceMetric::SquaredEuclidean);
+		assert_eq!(
+			reopened.max_leaf_node_size,
+			crate::constants::DEFAULT_MAX_LEAF_NODE_SIZE
+		);
+		assert_eq!(
+			get_setting("metric", &config).unwrap(),
+			Some(String::from("squared_euclidean"))
+		);
+		config.remove_test_database();
+	}
 }
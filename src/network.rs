@@ -0,0 +1,356 @@
+use feature_database::features::feature_description::FeatureDescription;
+use feature_database::Database;
+use feature_database::Error;
+use feature_database::FileSelector;
+use feature_database::KeypointMetadata;
+use feature_database::SearchOptions;
+
+use rocket::http::ContentType;
+use rocket::http::Status;
+use rocket::request::FromRequest;
+use rocket::request::Outcome;
+use rocket::request::Request;
+use rocket::response::content;
+use rocket::serde::json::json;
+use rocket::serde::json::Json;
+use rocket::serde::json::Value;
+use rocket::serde::Deserialize;
+use rocket::serde::Serialize;
+use rocket::State;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Message {
+	open_cv_results: Vec<Descriptor>,
+	k: u8,
+	// When present, k only caps how many features within radius are returned
+	radius: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[allow(dead_code)]
+struct Descriptor {
+	angle: f32,
+	class_id: i32,
+	descriptor: Vec<u8>,
+	octave: u8,
+	response: f32,
+	size: f32,
+	x: f32,
+	y: f32,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SearchPair {
+	distance: u32,
+	rank: usize,
+	metadata: KeypointMetadata,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct VectorResult {
+	results: Vec<SearchPair>,
+	id: usize,
+	comparisons: u64,
+	exact: bool,
+	truncated: bool,
+}
+
+// Failed requests are answered with a status and {"error": message}
+type JsonResult = Result<content::Json<Value>, (Status, content::Json<Value>)>;
+
+fn error_response(status: Status, message: &str) -> (Status, content::Json<Value>) {
+	return (status, content::Json(json!({ "error": message })));
+}
+
+// Only errors in the request are explained, the others are logged as they
+// name files on the server
+fn database_error(error: Error) -> (Status, content::Json<Value>) {
+	if error.is_invalid_input() {
+		return error_response(Status::BadRequest, &error.to_string());
+	}
+	eprintln!("{}", error);
+	return error_response(Status::InternalServerError, "The database failed");
+}
+
+// Searches of every collection share the one lock
+struct IsProgramSearching {
+	value: Arc<Mutex<u8>>,
+	database: Database,
+	collections: HashMap<String, Database>,
+}
+
+impl IsProgramSearching {
+	fn get_collection(&self, name: &str) -> Result<Database, (Status, content::Json<Value>)> {
+		return self
+			.collections
+			.get(name)
+			.cloned()
+			.ok_or_else(|| error_response(Status::NotFound, "No such collection"));
+	}
+}
+
+// `database` is served at the root and every collection below
+// /collections/<name>/
+pub async fn start(
+	database: Database,
+	collections: HashMap<String, Database>,
+) -> Result<(), rocket::Error> {
+	let state = IsProgramSearching {
+		value: Arc::new(Mutex::new(0)),
+		database: database,
+		collections: collections,
+	};
+
+	return rocket::build()
+		.mount(
+			"/",
+			routes![
+				get_index,
+				get_opencv,
+				get_favicon,
+				get_image_results,
+				get_collection_image_results,
+				delete_image,
+				delete_collection_image
+			],
+		)
+		.manage(state)
+		.register("/", catchers![not_found])
+		.launch()
+		.await;
+}
+
+#[post("/get_image_results.json", format = "json", data = "<message>")]
+async fn get_image_results(
+	message: Json<Message>,
+	state: &State<IsProgramSearching>,
+) -> JsonResult {
+	return search_images(message, state.database.clone(), state);
+}
+
+#[post(
+	"/collections/<collection>/get_image_results.json",
+	format = "json",
+	data = "<message>"
+)]
+async fn get_collection_image_results(
+	collection: &str,
+	message: Json<Message>,
+	state: &State<IsProgramSearching>,
+) -> JsonResult {
+	return search_images(message, state.get_collection(collection)?, state);
+}
+
+fn search_images(
+	message: Json<Message>,
+	database: Database,
+	state: &IsProgramSearching,
+) -> JsonResult {
+	let max_descriptors = feature_database::constants::MAX_DESCRIPTORS_PER_REQUEST;
+	if message.open_cv_results.len() > max_descriptors {
+		return Err(error_response(
+			Status::BadRequest,
+			&format!(
+				"A request may have at most {} descriptors, but this one has {}",
+				max_descriptors,
+				message.open_cv_results.len()
+			),
+		));
+	}
+	let length = feature_database::constants::FEATURE_DESCRIPTION_LENGTH;
+	if let Some(e) = message
+		.open_cv_results
+		.iter()
+		.find(|e| e.descriptor.len() != length)
+	{
+		return Err(error_response(
+			Status::BadRequest,
+			&format!(
+				"Descriptors must be {} bytes long, but one is {}",
+				length,
+				e.descriptor.len()
+			),
+		));
+	}
+	let descriptors = message
+		.open_cv_results
+		.iter()
+		.map(|e| FeatureDescription::new_from_vec(e.descriptor.clone()))
+		.collect::<Vec<FeatureDescription>>();
+	let budget = feature_database::constants::SERVER_REQUEST_BUDGET.split(descriptors.len());
+
+	// This is not clean or elegant, but under Kira's advisement, I am only
+	// letting one search be performed at a time. This is to make sure that
+	// attackers can not overload my system with attacks. It may provide a
+	// poor user experience if multiple images are trying to be searched at
+	// once and the program is CPU bound, but frankly I don't care.
+	// TODO make this pretty
+	let clone_arc = state.value.clone();
+	let results = thread::spawn(move || {
+		// The lock only holds a flag, so a search that panicked can not
+		// have left it in a bad state
+		let mut mutex_data = clone_arc.lock().unwrap_or_else(|e| e.into_inner());
+		*mutex_data = 1;
+		let mut options = match message.radius {
+			Some(radius) => SearchOptions::new_range(radius, Some(message.k as usize)),
+			None => SearchOptions::new(message.k as usize),
+		};
+		options.budget = budget;
+		let results = database.search(descriptors, options);
+		*mutex_data = 0;
+		results
+	})
+	.join()
+	.map_err(|_| error_response(Status::InternalServerError, "The database failed"))?
+	.map_err(database_error)?;
+
+	return Ok(content::Json(json!({
+		"results": results.into_iter().enumerate().map(|(id, counted_results)| VectorResult {
+			id: id,
+			comparisons: counted_results.comparisons,
+			exact: counted_results.is_exact,
+			truncated: counted_results.is_truncated,
+			results: counted_results.results.into_iter().enumerate().map(|(rank, (search_results, metadata))| SearchPair {
+				rank: rank,
+				distance: search_results.get_distance(),
+				metadata: metadata
+			}).collect::<Vec<SearchPair>>(),
+		}).collect::<Vec<VectorResult>>()
+	})));
+}
+
+// The token sent in the X-Remove-Token header, if any
+struct RemoveToken(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RemoveToken {
+	type Error = ();
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<RemoveToken, ()> {
+		let token = request.headers().get_one("X-Remove-Token");
+		return Outcome::Success(RemoveToken(token.map(String::from)));
+	}
+}
+
+// The server is public, so removing is turned off unless the database has a
+// remove_token, and then only requests that send it may remove anything.
+// Removing shares the lock with searching so a search never reads a file
+// while it is being rewritten.
+#[delete("/images/<md5>")]
+async fn delete_image(
+	md5: String,
+	token: RemoveToken,
+	state: &State<IsProgramSearching>,
+) -> JsonResult {
+	return remove_image(md5, token, state.database.clone(), state);
+}
+
+#[delete("/collections/<collection>/images/<md5>")]
+async fn delete_collection_image(
+	collection: &str,
+	md5: String,
+	token: RemoveToken,
+	state: &State<IsProgramSearching>,
+) -> JsonResult {
+	return remove_image(md5, token, state.get_collection(collection)?, state);
+}
+
+fn remove_image(
+	md5: String,
+	token: RemoveToken,
+	database: Database,
+	state: &IsProgramSearching,
+) -> JsonResult {
+	let expected = match &database.get_config().remove_token {
+		Some(expected) => expected,
+		None => {
+			return Err(error_response(
+				Status::Forbidden,
+				"Removing images over the network is turned off",
+			))
+		}
+	};
+	if token
+		.0
+		.as_ref()
+		.map_or(false, |e| is_same_token(e, expected))
+		== false
+	{
+		return Err(error_response(
+			Status::Forbidden,
+			"X-Remove-Token is missing or wrong",
+		));
+	}
+
+	let clone_arc = state.value.clone();
+	let (features_removed, files_removed) = thread::spawn(move || {
+		// The lock only holds a flag, so a search that panicked can not
+		// have left it in a bad state
+		let mut mutex_data = clone_arc.lock().unwrap_or_else(|e| e.into_inner());
+		*mutex_data = 1;
+		let removed = database.remove(FileSelector::Md5(md5));
+		*mutex_data = 0;
+		removed
+	})
+	.join()
+	.map_err(|_| error_response(Status::InternalServerError, "The database failed"))?
+	.map_err(database_error)?;
+
+	return Ok(content::Json(json!({
+		"files_removed": files_removed,
+		"features_removed": features_removed,
+	})));
+}
+
+// Looks at every byte, so how long a comparison takes does not tell how
+// much of a guessed token was right
+fn is_same_token(given: &str, expected: &str) -> bool {
+	if given.len() != expected.len() {
+		return false;
+	}
+	let difference = given
+		.bytes()
+		.zip(expected.bytes())
+		.fold(0, |difference, (a, b)| difference | (a ^ b));
+	return difference == 0;
+}
+
+#[get("/")]
+fn get_index() -> Result<content::Html<Vec<u8>>, Status> {
+	return read_ui_file("./UI/index.html").map(content::Html);
+}
+
+#[get("/opencv.js")]
+fn get_opencv() -> Result<content::JavaScript<Vec<u8>>, Status> {
+	return read_ui_file("./UI/opencv.js").map(content::JavaScript);
+}
+
+#[get("/favicon.ico")]
+fn get_favicon() -> Result<content::Custom<Vec<u8>>, Status> {
+	return read_ui_file("./UI/favicon.ico").map(|e| content::Custom(ContentType::AVIF, e));
+}
+
+// A file of the UI that is missing is not found, any other failure is logged
+fn read_ui_file(path: &str) -> Result<Vec<u8>, Status> {
+	return fs::read(path).map_err(|error| {
+		if error.kind() == std::io::ErrorKind::NotFound {
+			return Status::NotFound;
+		}
+		eprintln!("Reading {} failed: {}", path, error);
+		return Status::InternalServerError;
+	});
+}
+
+#[catch(404)]
+fn not_found() -> String {
+	return String::from("404");
+}
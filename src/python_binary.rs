@@ -1,3 +1,4 @@
+use crate::error::Error;
 use crate::extract_from_image::PointOfInterest;
 use crate::features::feature_description::FeatureDescription;
 use crate::frame_info::FrameInfo;
@@ -10,24 +11,38 @@ use std::convert::TryInto;
 use std::str;
 use std::vec::Vec;
 
-pub fn parse_python_binary(file_name: &str) -> Vec<(FrameInfo, Vec<PointOfInterest>)> {
+pub fn parse_python_binary(
+	file_name: &str,
+) -> Result<Vec<(FrameInfo, Vec<PointOfInterest>)>, Error> {
 	const ENTRY_SIZE: usize = 4096 + 16384 + 16384;
-	let (size, contents) = read_file_to_binary(file_name);
+	let (size, contents) = read_file_to_binary(file_name)?;
+	if size % ENTRY_SIZE != 0 {
+		return Err(Error::InvalidInput(format!(
+			"Python binary {} is {} bytes long, which is not a whole number of {} byte entries",
+			file_name, size, ENTRY_SIZE
+		)));
+	}
 
 	let mut results = vec![];
 	for start in (0..size).step_by(ENTRY_SIZE) {
 		let range = start..(start + ENTRY_SIZE);
-		results.push(parse_python_block(&contents[range]));
+		let entry = parse_python_block(&contents[range]).ok_or_else(|| {
+			Error::InvalidInput(format!(
+				"Python binary {} has an entry at byte {} whose md5 or extension is not text",
+				file_name, start
+			))
+		})?;
+		results.push(entry);
 	}
 
-	return results;
+	return Ok(results);
 }
 
-fn parse_python_block(data: &[u8]) -> (FrameInfo, Vec<PointOfInterest>) {
+fn parse_python_block(data: &[u8]) -> Option<(FrameInfo, Vec<PointOfInterest>)> {
 	// Header Block   0..4096
 	// Metadata Block 4096..20480
 	// Vector Block   20480..36864
-	let (num_features, frame_info) = parse_header_block(&data[0..48]);
+	let (num_features, frame_info) = parse_header_block(&data[0..48])?;
 	let metadata = parse_metadata_block(&data[4096..20480], num_features);
 	let vectors = parse_vector_block(&data[20480..36864], num_features);
 	let points_of_interest = metadata
@@ -38,14 +53,14 @@ fn parse_python_block(data: &[u8]) -> (FrameInfo, Vec<PointOfInterest>) {
 			description: v,
 		})
 		.collect();
-	return (frame_info, points_of_interest);
+	return Some((frame_info, points_of_interest));
 
-	fn parse_header_block(data: &[u8]) -> (u32, FrameInfo) {
-		let md5 = String::from(str::from_utf8(&data[0..32]).unwrap());
-		let ext = String::from(str::from_utf8(&data[32..40]).unwrap().trim());
+	fn parse_header_block(data: &[u8]) -> Option<(u32, FrameInfo)> {
+		let md5 = String::from(str::from_utf8(&data[0..32]).ok()?);
+		let ext = String::from(str::from_utf8(&data[32..40]).ok()?.trim());
 		let frame_index = u32::from_le_bytes(data[40..44].try_into().unwrap()) as u64;
 		let number_features = u32::from_le_bytes(data[44..48].try_into().unwrap());
-		return (number_features, FrameInfo::new(md5, ext, frame_index));
+		return Some((number_features, FrameInfo::new(md5, ext, frame_index)));
 	}
 
 	fn parse_vector_block(data: &[u8], num_vectors: u32) -> Vec<FeatureDescription> {
@@ -84,10 +99,12 @@ fn parse_python_block(data: &[u8]) -> (FrameInfo, Vec<PointOfInterest>) {
 	}
 }
 
-fn read_file_to_binary(file_name: &str) -> (usize, Vec<u8>) {
-	let file = File::open(file_name).unwrap();
+fn read_file_to_binary(file_name: &str) -> Result<(usize, Vec<u8>), Error> {
+	let read_error =
+		|error| Error::io(format!("Reading python binary {} failed", file_name), error);
+	let file = File::open(file_name).map_err(read_error)?;
 	let mut buf_reader = BufReader::new(file);
 	let mut contents = vec![];
-	let size = buf_reader.read_to_end(&mut contents).unwrap();
-	return (size, contents);
+	let size = buf_reader.read_to_end(&mut contents).map_err(read_error)?;
+	return Ok((size, contents));
 }
@@ -0,0 +1,93 @@
+use crate::database_config::DatabaseConfig;
+use crate::error::Error;
+use crate::metadata_database::FileSelector;
+
+use std::collections::HashSet;
+
+// The tree is cleaned first so that if something goes wrong part way
+// through, the metadata still says which features have to be removed.
+pub fn remove_file_from_database(
+	selector: FileSelector,
+	config: &DatabaseConfig,
+) -> Result<(u64, usize), Error> {
+	let uuids = crate::metadata_database::find_uuids_from_file(&selector, config)?
+		.into_iter()
+		.collect::<HashSet<u64>>();
+	let features_removed = crate::features_database::remove_uuids_from_database(&uuids, config)?;
+	let files_removed = crate::metadata_database::remove_files_from_database(&selector, config)?;
+	return Ok((features_removed, files_removed));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database::Database;
+	use crate::database_config::RequestedConfig;
+	use crate::extract_from_image::PointOfInterest;
+	use crate::frame_info::FrameInfo;
+
+	fn add_image(database: &Database, md5: &str, seed: u64) -> HashSet<u64> {
+		let selector = FileSelector::Md5(String::from(md5));
+		let frame = FrameInfo::new(String::from(md5), String::from("png"), 0);
+		database
+			.add_features(frame, PointOfInterest::seeded_random(seed, 20))
+			.unwrap();
+		return crate::metadata_database::find_uuids_from_file(&selector, database.get_config())
+			.unwrap()
+			.into_iter()
+			.collect();
+	}
+
+	// Uuids of the features in the tree and of the rows in SQLite
+	fn stored_uuids(config: &DatabaseConfig) -> (HashSet<u64>, HashSet<u64>) {
+		let tree = crate::features_database::verify_database(config)
+			.unwrap()
+			.uuids;
+		let metadata = crate::metadata_database::get_all_uuids(config)
+			.unwrap()
+			.into_iter()
+			.collect();
+		return (tree, metadata);
+	}
+
+	#[test]
+	fn removing_an_image_leaves_nothing_of_it_behind() {
+		let database = Database::open(&RequestedConfig::for_test_database("remove_image")).unwrap();
+		let config = database.get_config().clone();
+		let kept = add_image(&database, "kept", 1);
+		let removed = add_image(&database, "removed", 2);
+		assert_eq!(kept.len(), 20);
+		assert_eq!(removed.len(), 20);
+
+		assert_eq!(
+			database
+				.remove(FileSelector::Md5(String::from("removed")))
+				.unwrap(),
+			(20, 1)
+		);
+		assert_eq!(stored_uuids(&config), (kept.clone(), kept));
+		assert_eq!(
+			crate::metadata_database::count_files_and_features(&config).unwrap(),
+			(1, 20)
+		);
+		assert!(database.verify().unwrap().is_consistent());
+
+		// Removing it again finds nothing to remove
+		assert_eq!(
+			database
+				.remove(FileSelector::Md5(String::from("removed")))
+				.unwrap(),
+			(0, 0)
+		);
+
+		assert_eq!(
+			database
+				.remove(FileSelector::Md5(String::from("kept")))
+				.unwrap(),
+			(20, 1)
+		);
+		assert_eq!(stored_uuids(&config), (HashSet::new(), HashSet::new()));
+		assert!(database.verify().unwrap().is_consistent());
+		config.remove_test_database();
+	}
+}
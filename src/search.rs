@@ -0,0 +1,95 @@
+use crate::database_config::DatabaseConfig;
+use crate::error::Error;
+use crate::feature_tree::search_result::SearchOptions;
+use crate::feature_tree::search_result::SearchResult;
+use crate::feature_tree::search_result::SearchResultList;
+use crate::features::feature_description::FeatureDescription;
+use crate::metadata_database::KeypointMetadata;
+
+// Every result of a search with the metadata of the feature that was found
+pub struct CountedSearchResult {
+	pub comparisons: u64,
+	pub is_exact: bool,
+	pub is_truncated: bool,
+	pub results: Vec<(SearchResult, KeypointMetadata)>,
+}
+
+pub fn search_for_all_descriptions(
+	descriptions: Vec<FeatureDescription>,
+	options: SearchOptions,
+	config: &DatabaseConfig,
+) -> Result<Vec<CountedSearchResult>, Error> {
+	if config.batch_search {
+		return crate::features_database::find_feature_descriptions_in_database(
+			descriptions,
+			options,
+			config,
+		)?
+		.into_iter()
+		.map(|results| attach_metadata(results, config))
+		.collect();
+	}
+
+	let mut results = vec![];
+
+	let mut threads = vec![];
+	for description in descriptions {
+		if config.threaded_search {
+			let config = config.clone();
+			threads.push(std::thread::spawn(move || {
+				search_for_description(description, options, &config)
+			}));
+		} else {
+			results.push(search_for_description(description, options, config)?);
+		}
+	}
+
+	if config.threaded_search {
+		results = threads
+			.into_iter()
+			.map(|e| {
+				e.join()
+					.unwrap_or_else(|_| Err(Error::Panicked(String::from("A search thread"))))
+			})
+			.collect::<Result<Vec<CountedSearchResult>, Error>>()?;
+	}
+
+	return Ok(results);
+}
+
+fn search_for_description(
+	description: FeatureDescription,
+	options: SearchOptions,
+	config: &DatabaseConfig,
+) -> Result<CountedSearchResult, Error> {
+	let results = crate::features_database::find_feature_description_in_database(
+		description,
+		options,
+		config,
+	)?;
+	return attach_metadata(results, config);
+}
+
+fn attach_metadata(
+	search_results: SearchResultList,
+	config: &DatabaseConfig,
+) -> Result<CountedSearchResult, Error> {
+	let comparisons = search_results.get_comparisons();
+	let is_exact = search_results.is_exact();
+	let is_truncated = search_results.is_truncated();
+	let results = search_results.get_results();
+
+	let metadata_list = results
+		.iter()
+		.map(|e| crate::metadata_database::find_metadata_from_uuid(e.get_result_uuid(), config))
+		.collect::<Result<Vec<KeypointMetadata>, Error>>()?;
+
+	let pairs = results.into_iter().zip(metadata_list.into_iter()).collect();
+
+	return Ok(CountedSearchResult {
+		comparisons: comparisons,
+		is_exact: is_exact,
+		is_truncated: is_truncated,
+		results: pairs,
+	});
+}
@@ -0,0 +1,346 @@
+use crate::database_config::DatabaseConfig;
+use crate::error::Error;
+use crate::feature_tree::verify::TreeReport;
+
+use crate::features_database;
+use crate::insert_log;
+use crate::metadata_database;
+
+use rocket::serde::json::json;
+use rocket::serde::json::Value;
+use std::collections::HashSet;
+
+// Everything wrong with a database, see Database::verify
+pub struct DatabaseReport {
+	tree: TreeReport,
+	metadata_uuids: HashSet<u64>,
+	missing_from_metadata: Vec<u64>,
+	missing_from_tree: Vec<u64>,
+	orphaned_file_paths: Vec<String>,
+	// Changes that were interrupted and are finished by Database::open
+	has_pending_insert: bool,
+	has_pending_tree_change: bool,
+}
+
+impl DatabaseReport {
+	pub fn has_unreadable_files(&self) -> bool {
+		return self.tree.unreadable_files.is_empty() == false
+			|| self.tree.missing_files.is_empty() == false;
+	}
+
+	// Changes that Database::open finishes before anything else is done
+	pub fn has_pending_changes(&self) -> bool {
+		return self.has_pending_insert || self.has_pending_tree_change;
+	}
+
+	pub fn is_consistent(&self) -> bool {
+		return self.has_unreadable_files() == false
+			&& self.tree.misplaced_features.is_empty()
+			&& self.tree.duplicate_uuids.is_empty()
+			&& self.tree.orphaned_files.is_empty()
+			&& self.tree.orphaned_temp_files.is_empty()
+			&& self.missing_from_metadata.is_empty()
+			&& self.missing_from_tree.is_empty()
+			&& self.has_pending_changes() == false;
+	}
+
+	// Every list is sorted so that reports of the same database can be
+	// compared
+	pub fn to_json(&self) -> Value {
+		return json!({
+			"is_consistent": self.is_consistent(),
+			"files_checked": self.tree.files_checked,
+			"features_checked": self.tree.features_checked,
+			"metadata_rows_checked": self.metadata_uuids.len(),
+			"unreadable_files": self.tree.unreadable_files,
+			"missing_files": self.tree.missing_files,
+			"misplaced_features": self.tree.misplaced_features,
+			"duplicate_uuids": self.tree.duplicate_uuids,
+			"uuids_missing_from_metadata": self.missing_from_metadata,
+			"uuids_missing_from_tree": self.missing_from_tree,
+			"orphaned_files": self.orphaned_file_paths,
+			"pending_insert": self.has_pending_insert,
+			"pending_tree_change": self.has_pending_tree_change,
+		});
+	}
+}
+
+// Only reads the database, see Database::open_read_only
+pub fn check_database(config: &DatabaseConfig) -> Result<DatabaseReport, Error> {
+	let tree = features_database::verify_database(config)?;
+	let metadata_uuids = metadata_database::get_all_uuids(config)?
+		.into_iter()
+		.collect::<HashSet<u64>>();
+
+	let mut missing_from_metadata = tree
+		.uuids
+		.difference(&metadata_uuids)
+		.copied()
+		.collect::<Vec<u64>>();
+	missing_from_metadata.sort_unstable();
+	let mut missing_from_tree = metadata_uuids
+		.difference(&tree.uuids)
+		.copied()
+		.collect::<Vec<u64>>();
+	missing_from_tree.sort_unstable();
+
+	let orphaned_file_paths = tree
+		.orphaned_files
+		.iter()
+		.map(|path| path.to_file_path_string(config))
+		.chain(tree.orphaned_temp_files.iter().cloned())
+		.collect();
+
+	return Ok(DatabaseReport {
+		tree: tree,
+		metadata_uuids: metadata_uuids,
+		missing_from_metadata: missing_from_metadata,
+		missing_from_tree: missing_from_tree,
+		orphaned_file_paths: orphaned_file_paths,
+		has_pending_insert: insert_log::is_pending(config),
+		has_pending_tree_change: crate::feature_tree::staging::has_pending_commit(config),
+	});
+}
+
+// What Database::repair found and what it did about it
+pub struct RepairReport {
+	pub found: DatabaseReport,
+	pub is_repaired: bool,
+	pub features_removed: u64,
+	pub features_reinserted: usize,
+	pub metadata_rows_removed: usize,
+	pub file_rows_removed: usize,
+	pub files_removed: usize,
+}
+
+impl RepairReport {
+	pub fn to_json(&self) -> Value {
+		if self.is_repaired == false {
+			return json!({ "found": self.found.to_json() });
+		}
+		return json!({
+			"found": self.found.to_json(),
+			"features_removed": self.features_removed,
+			"features_reinserted": self.features_reinserted,
+			"metadata_rows_removed": self.metadata_rows_removed,
+			"file_rows_removed": self.file_rows_removed,
+			"files_removed": self.files_removed,
+		});
+	}
+}
+
+// Fixes everything check_database finds except files that can not be read,
+// which have to be restored first because every fix walks the whole tree.
+// Features without metadata are removed from the tree, misplaced or
+// duplicated features are removed and added again once, and orphaned files
+// are deleted. The metadata does not hold descriptors, so metadata rows
+// without a feature in the tree can only be removed, along with files that
+// have no features left. `config` may come from Database::open_read_only,
+// interrupted changes are only finished once every file could be read.
+pub fn repair_database(config: &DatabaseConfig) -> Result<RepairReport, Error> {
+	let mut report = check_database(config)?;
+	if report.has_unreadable_files() == false && report.has_pending_changes() {
+		let _finished_commit = crate::feature_tree::staging::recover(config)?;
+		let _replayed = insert_log::replay(config)?;
+		report = check_database(config)?;
+	}
+	if report.has_unreadable_files() {
+		return Ok(RepairReport {
+			found: report,
+			is_repaired: false,
+			features_removed: 0,
+			features_reinserted: 0,
+			metadata_rows_removed: 0,
+			file_rows_removed: 0,
+			files_removed: 0,
+		});
+	}
+
+	let moved_pairs = report
+		.tree
+		.misplaced_pairs
+		.iter()
+		.chain(report.tree.duplicate_pairs.iter());
+	let mut to_remove = report
+		.missing_from_metadata
+		.iter()
+		.copied()
+		.collect::<HashSet<u64>>();
+	let mut to_reinsert = vec![];
+	for pair in moved_pairs {
+		let uuid = pair.get_uuid();
+		if to_remove.insert(uuid) && report.metadata_uuids.contains(&uuid) {
+			to_reinsert.push(pair.clone());
+		}
+	}
+
+	metadata_database::record_database_settings(config)?;
+
+	// Orphans go first, as features added below may create files with
+	// the same names
+	report.tree.remove_orphaned_files(config)?;
+
+	// Puts the moved features back if the repair is interrupted
+	let features_reinserted = to_reinsert.len();
+	insert_log::write(&to_reinsert, config)?;
+	insert_log::mark_tree_started(config)?;
+	let features_removed = features_database::remove_uuids_from_database(&to_remove, config)?;
+	features_database::insert_description_vec_into_database(to_reinsert, config)?;
+	insert_log::clear(config)?;
+
+	let (metadata_rows_removed, file_rows_removed) =
+		metadata_database::remove_uuids_from_metadata(&report.missing_from_tree, config)?;
+
+	let files_removed = report.tree.orphaned_files.len();
+	return Ok(RepairReport {
+		found: report,
+		is_repaired: true,
+		features_removed: features_removed,
+		features_reinserted: features_reinserted,
+		metadata_rows_removed: metadata_rows_removed,
+		file_rows_removed: file_rows_removed,
+		files_removed: files_removed,
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database::Database;
+	use crate::database_config::RequestedConfig;
+	use crate::extract_from_image::PointOfInterest;
+	use crate::feature_tree::bulk_builder::get_root_path;
+	use crate::feature_tree::node_path::NodePath;
+	use crate::features::uuid_description_pair::UUIDDescriptionPair;
+	use crate::frame_info::FrameInfo;
+
+	// A database of one image with features 1 to 10, whose tree has every
+	// kind of problem check_database looks for: features 1 and 1000 on the
+	// wrong side, feature 2 twice, 1000 without metadata, 10 missing, and
+	// files no node points to
+	fn damaged_database(name: &str) -> Database {
+		let database = Database::open(&RequestedConfig::for_test_database(name)).unwrap();
+		let frame = FrameInfo::new(String::from("damaged"), String::from("png"), 0);
+		database
+			.add_features(frame, PointOfInterest::seeded_random(71, 10))
+			.unwrap();
+		let pairs = UUIDDescriptionPair::seeded_random(71, 1, 10);
+		let without_metadata = UUIDDescriptionPair::seeded_random(72, 1000, 1);
+
+		let near = vec![pairs[0].clone(), without_metadata[0].clone()];
+		let mut far = pairs[1..9].to_vec();
+		far.push(pairs[1].clone());
+		crate::feature_tree::verify::write_damaged_tree(near, far, database.get_config());
+		return database;
+	}
+
+	fn misplaced_uuids(report: &DatabaseReport) -> Vec<u64> {
+		let mut uuids = report
+			.tree
+			.misplaced_features
+			.iter()
+			.map(|feature| feature.uuid)
+			.collect::<Vec<u64>>();
+		uuids.sort_unstable();
+		return uuids;
+	}
+
+	#[test]
+	fn verify_reports_every_problem() {
+		let database = damaged_database("verify_reports");
+		let config = database.get_config().clone();
+		let orphan_count = NodePath::get_all_in_database(&config).unwrap().len() - 1;
+
+		let report = database.verify().unwrap();
+		assert!(report.is_consistent() == false);
+		assert!(report.has_unreadable_files() == false);
+		assert!(report.has_pending_changes() == false);
+		assert_eq!(misplaced_uuids(&report), vec![1, 1000]);
+		assert_eq!(report.tree.duplicate_uuids, vec![2]);
+		assert_eq!(report.missing_from_metadata, vec![1000]);
+		assert_eq!(report.missing_from_tree, vec![10]);
+		assert!(orphan_count >= 1);
+		assert_eq!(report.tree.orphaned_files.len(), orphan_count);
+		assert_eq!(report.tree.orphaned_temp_files.len(), 1);
+		assert_eq!(report.orphaned_file_paths.len(), orphan_count + 1);
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn verify_reports_unreadable_files() {
+		let database = damaged_database("verify_unreadable");
+		let config = database.get_config().clone();
+		crate::feature_tree::verify::damage_root_file(&config);
+
+		let report = database.verify().unwrap();
+		assert!(report.has_unreadable_files());
+		assert_eq!(report.tree.unreadable_files.len(), 1);
+		assert_eq!(
+			report.tree.unreadable_files[0].file,
+			get_root_path().to_file_name()
+		);
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn repair_fixes_every_problem() {
+		let database = damaged_database("repair_fixes");
+		let config = database.get_config().clone();
+		let orphan_count = NodePath::get_all_in_database(&config).unwrap().len() - 1;
+
+		let repaired = database.repair().unwrap();
+		assert!(repaired.is_repaired);
+		assert!(repaired.found.is_consistent() == false);
+		// Feature 1 is moved and 2 is left once, 1000 has no metadata to keep
+		// it and 10 no feature to keep its row
+		assert_eq!(repaired.features_reinserted, 2);
+		assert_eq!(repaired.metadata_rows_removed, 1);
+		assert_eq!(repaired.file_rows_removed, 0);
+		assert_eq!(repaired.files_removed, orphan_count);
+
+		let report = database.verify().unwrap();
+		assert!(report.is_consistent(), "{}", report.to_json());
+		let expected = (1..10).collect::<HashSet<u64>>();
+		assert_eq!(report.tree.uuids, expected);
+		assert_eq!(report.metadata_uuids, expected);
+		assert!(report.orphaned_file_paths.is_empty());
+
+		// The moved feature is found where it belongs
+		let moved = UUIDDescriptionPair::seeded_random(71, 1, 1).remove(0);
+		let results = crate::features_database::find_feature_description_in_database(
+			moved.get_description().clone(),
+			crate::feature_tree::search_result::SearchOptions::new(1),
+			&config,
+		)
+		.unwrap()
+		.get_results();
+		assert_eq!(results[0].get_result_uuid(), 1);
+		assert_eq!(results[0].get_distance(), 0);
+
+		// Nothing is left to repair
+		let repaired = database.repair().unwrap();
+		assert!(repaired.found.is_consistent());
+		assert_eq!(repaired.features_reinserted, 0);
+		config.remove_test_database();
+	}
+
+	#[test]
+	fn repair_leaves_databases_with_unreadable_files_alone() {
+		let database = damaged_database("repair_unreadable");
+		let config = database.get_config().clone();
+		crate::feature_tree::verify::damage_root_file(&config);
+		let files = NodePath::get_all_in_database(&config).unwrap().len();
+
+		let repaired = database.repair().unwrap();
+		assert!(repaired.is_repaired == false);
+		assert!(repaired.found.has_unreadable_files());
+		assert_eq!(NodePath::get_all_in_database(&config).unwrap().len(), files);
+		assert_eq!(
+			crate::metadata_database::get_all_uuids(&config)
+				.unwrap()
+				.len(),
+			10
+		);
+		config.remove_test_database();
+	}
+}